@@ -0,0 +1,56 @@
+//! Audit Log
+//!
+//! Entries are never deleted, only archived, so the metadata table doubles as a log of
+//! every change made to a store. These types describe that log, see
+//! [`DataStore::audit_log`](crate::datastore::store::DataStore::audit_log).
+
+use crate::datastore::schema::{DeviceId, MetadataEntry};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+/// The kind of change an entry made
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditOperation {
+    /// Stored new data
+    Create,
+    /// Replaced its parent
+    Update,
+    /// Archived its parent
+    Archive,
+}
+
+/// A single change made to a store
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    /// Name of the store that was changed
+    pub store: String,
+    /// Id of the entry that made the change
+    pub id: Uuid,
+    /// Device that wrote the entry
+    pub device_id: DeviceId,
+    pub operation: AuditOperation,
+    /// The entry that was updated or archived
+    pub parent_id: Option<Uuid>,
+    /// When the change was made, taken from the entry id
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+impl AuditRecord {
+    pub(crate) fn new(store: &str, entry: &MetadataEntry) -> Self {
+        // Archive entries are the only ones without data
+        let operation = match entry.parent_id {
+            None => AuditOperation::Create,
+            Some(_) if entry.data_hash.is_empty() => AuditOperation::Archive,
+            Some(_) => AuditOperation::Update,
+        };
+        Self {
+            store: store.to_string(),
+            id: entry.id,
+            device_id: entry.device_id,
+            operation,
+            parent_id: entry.parent_id,
+            timestamp: entry.created_at(),
+        }
+    }
+}
@@ -0,0 +1,62 @@
+//! Store Backups
+//!
+//! A backup is a stream of JSON lines: a [`BackupRecord::Header`], then every entry of the
+//! store oldest first. The data of an entry comes before the first entry that uses it, so
+//! a backup can be restored in a single pass, see
+//! [`DataStore::import_backup`](crate::datastore::store::DataStore::import_backup).
+//!
+//! A bundle uses the same format but only holds the entries created after a given entry,
+//! for bringing another copy of the store up to date without a network connection.
+//!
+//! Data is streamed into the backup when it's written, but each line is read whole when
+//! it's restored. Restoring a [`BackupRecord::Data`] line holds its hex encoding and the
+//! decoded bytes in memory at once, about three times the size of the data.
+
+use crate::datastore::schema::MetadataEntry;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use uuid::Uuid;
+
+/// Version of the backup format written by this build
+pub const BACKUP_VERSION: u32 = 1;
+
+/// A single line of a backup
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackupRecord {
+    /// Always the first line
+    Header {
+        version: u32,
+        /// Name of the store that was backed up
+        store: String,
+        created_at: DateTime<Utc>,
+        /// For bundles, the last entry that was left out
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        since: Option<Uuid>,
+    },
+    /// A metadata entry, including archived ones
+    Entry(MetadataEntry),
+    /// Data available to the store when it was backed up
+    Data {
+        hash: String,
+        /// Hex encoded contents
+        bytes: String,
+    },
+}
+
+/// Hex encodes everything written to it into the inner writer
+///
+/// Used to stream data into the `bytes` field of a [`BackupRecord::Data`] line.
+pub struct HexWriter<W: Write>(pub W);
+
+impl<W: Write> Write for HexWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write_all(hex::encode(buf).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
@@ -1,12 +1,14 @@
-use crate::datastore::schema::{DataEntry, DeviceId};
-use anyhow::{anyhow, bail, Error, Result};
-use sqlx::{PgPool, Row};
+//! Data Table
+//!
+//! This is the interface and implementation of the Data Table in the database.
+//! We store the actual data and tracking information about where copies exist.
+//! Everything is indexed on the hash
 
-/// Data Table
-///
-/// This is the interface and implementation of the Data Table in the database.
-/// We store the actual data and tracking information about where copies exist.
-/// Everything is indexed on the hash
+use crate::datastore::schema::{DataAccess, DataEntry, DeviceId};
+use anyhow::{anyhow, bail, Error, Result};
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Row, SqlitePool};
+use uuid::Uuid;
 
 /// Interface for interacting with the data table
 #[allow(dead_code, async_fn_in_trait)]
@@ -26,6 +28,12 @@ pub trait DataTable {
     /// Increase the ref_count
     async fn increase_ref_count(&mut self, hash: &str) -> Result<i32>;
 
+    /// Increase the ref_count of many entries in a single transaction
+    ///
+    /// A hash given more than once is increased once for each time. If any hash isn't
+    /// found no ref_count is changed.
+    async fn increase_ref_counts(&mut self, hashes: &[String]) -> Result<()>;
+
     /// Decrease the ref_count
     async fn decrease_ref_count(&mut self, hash: &str) -> Result<i32>;
 
@@ -52,6 +60,30 @@ pub trait DataTable {
 
     /// Remove inline data for this entry
     async fn remove_inline_data(&mut self, hash: &str) -> Result<()>;
+
+    /// Record that this data was just read or written locally
+    ///
+    /// This is local bookkeeping only, so it does not require exclusive access.
+    async fn touch(&self, hash: &str) -> Result<()>;
+
+    /// Random id of the database this table is in, the same for every store in it
+    async fn database_id(&self) -> Result<Uuid>;
+
+    /// Get the least recently accessed data, oldest first
+    async fn get_least_recently_used(&self, limit: i64) -> Result<Vec<DataAccess>>;
+
+    /// Get the data no metadata entry expects that hasn't been accessed since `accessed_before`
+    async fn get_unreferenced(&self, accessed_before: DateTime<Utc>) -> Result<Vec<DataAccess>>;
+
+    /// Delete an entry, only if it's still unreferenced and hasn't been accessed since
+    /// `accessed_before`
+    ///
+    /// Returns false if nothing was deleted, e.g. because the data was referenced again.
+    async fn delete_unreferenced(
+        &mut self,
+        hash: &str,
+        accessed_before: DateTime<Utc>,
+    ) -> Result<bool>;
 }
 
 /// PostgreSQL implementation of the data table
@@ -87,8 +119,9 @@ impl PostgresDataTable {
         let mut last_error = None;
 
         while attempts < MAX_RETRIES {
-            match sqlx::query(
-                r#"
+            let created = async {
+                sqlx::query(
+                    r#"
                 CREATE TABLE IF NOT EXISTS data_entries (
                     hash CHAR(67) PRIMARY KEY,
                     ref_count INT NOT NULL DEFAULT 0,
@@ -96,25 +129,62 @@ impl PostgresDataTable {
                     devices BYTEA[] NOT NULL DEFAULT '{}',
                     local_path TEXT[] NOT NULL DEFAULT '{}',
                     s3_path TEXT[] NOT NULL DEFAULT '{}',
-                    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+                    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                    last_accessed TIMESTAMPTZ NOT NULL DEFAULT NOW()
                 );"#,
-            )
-            .execute(pool)
-            .await
-            {
+                )
+                .execute(pool)
+                .await?;
+                Self::add_missing_columns(pool).await?;
+                Self::create_info_table(pool).await
+            }
+            .await;
+            match created {
                 Ok(_) => return Ok(()),
-                Err(e) => {
-                    last_error = Some(e);
-                    attempts += 1;
-                    if attempts < MAX_RETRIES {
-                        tokio::time::sleep(std::time::Duration::from_millis(RETRY_DELAY_MS)).await;
-                    }
-                }
+                Err(e) => last_error = Some(e),
+            }
+            attempts += 1;
+            if attempts < MAX_RETRIES {
+                tokio::time::sleep(std::time::Duration::from_millis(RETRY_DELAY_MS)).await;
             }
         }
 
         Err(last_error.unwrap().into())
     }
+
+    /// Add the columns that are newer than an existing data table
+    ///
+    /// Existing data counts as accessed now, so it isn't the first to be collected.
+    async fn add_missing_columns(pool: &PgPool) -> std::result::Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            ALTER TABLE data_entries
+            ADD COLUMN IF NOT EXISTS last_accessed TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            "#,
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Create the table holding the database id, see [`DataTable::database_id`]
+    async fn create_info_table(pool: &PgPool) -> std::result::Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS data_info (
+                singleton BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (singleton),
+                database_id UUID NOT NULL
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+        sqlx::query("INSERT INTO data_info (database_id) VALUES ($1) ON CONFLICT DO NOTHING")
+            .bind(Uuid::new_v4())
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
 }
 
 impl DataTable for PostgresDataTable {
@@ -264,12 +334,616 @@ impl DataTable for PostgresDataTable {
         }
     }
 
+    async fn increase_ref_counts(&mut self, hashes: &[String]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for hash in hashes {
+            let result = sqlx::query(
+                r#"
+                UPDATE data_entries
+                SET ref_count = ref_count + 1
+                WHERE hash = $1
+                "#,
+            )
+            .bind(hash)
+            .execute(&mut *tx)
+            .await?;
+
+            // The transaction rolls back on drop
+            if result.rows_affected() != 1 {
+                bail!("Data {} not found", hash);
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn decrease_ref_count(&mut self, hash: &str) -> Result<i32> {
+        let row = sqlx::query(
+            r#"
+            UPDATE data_entries
+            SET ref_count = GREATEST(ref_count - 1, 0)
+            WHERE hash = $1
+            RETURNING ref_count
+            "#,
+        )
+        .bind(hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match row {
+            Some(row) => Ok(row.get("ref_count")),
+            None => Err(anyhow!("Not found")),
+        }
+    }
+
+    async fn add_inline_data(&mut self, hash: &str, data: Vec<u8>) -> Result<()> {
+        let result = sqlx::query(
+            r#"
+            UPDATE data_entries
+            SET inline_data = $2
+            WHERE hash = $1
+            "#,
+        )
+        .bind(hash)
+        .bind(&data)
+        .execute(&self.pool)
+        .await?;
+
+        // Verify one row was affected
+        if result.rows_affected() != 1 {
+            bail!("Not found")
+        }
+
+        Ok(())
+    }
+
+    async fn remove_inline_data(&mut self, hash: &str) -> Result<()> {
+        let result = sqlx::query(
+            r#"
+            UPDATE data_entries
+            SET inline_data = NULL
+            WHERE hash = $1
+            "#,
+        )
+        .bind(hash)
+        .execute(&self.pool)
+        .await?;
+
+        // Verify one row was affected
+        if result.rows_affected() != 1 {
+            bail!("Not found")
+        }
+
+        Ok(())
+    }
+
+    async fn add_s3_path(&mut self, hash: &str, path: String) -> Result<()> {
+        self.append_to_array(hash, "s3_path", path, "TEXT").await
+    }
+
+    async fn add_local_path(&mut self, hash: &str, path: String) -> Result<()> {
+        self.append_to_array(hash, "local_path", path, "TEXT").await
+    }
+
+    async fn add_device(&mut self, hash: &str, device_id: DeviceId) -> Result<()> {
+        self.append_to_array(hash, "devices", device_id, "BYTEA")
+            .await
+    }
+
+    async fn remove_s3_path(&mut self, hash: &str, path: &str) -> Result<()> {
+        self.remove_from_array(hash, "s3_path", path, "TEXT").await
+    }
+
+    async fn remove_local_path(&mut self, hash: &str, path: &str) -> Result<()> {
+        self.remove_from_array(hash, "local_path", path, "TEXT")
+            .await
+    }
+
+    async fn remove_device(&mut self, hash: &str, device_id: DeviceId) -> Result<()> {
+        self.remove_from_array(hash, "devices", device_id, "BYTEA")
+            .await
+    }
+
+    async fn touch(&self, hash: &str) -> Result<()> {
+        let result = sqlx::query(
+            r#"
+            UPDATE data_entries
+            SET last_accessed = NOW()
+            WHERE hash = $1
+            "#,
+        )
+        .bind(hash)
+        .execute(&self.pool)
+        .await?;
+
+        // Verify one row was affected
+        if result.rows_affected() != 1 {
+            bail!("Not found")
+        }
+
+        Ok(())
+    }
+
+    async fn database_id(&self) -> Result<Uuid> {
+        Ok(sqlx::query_scalar("SELECT database_id FROM data_info")
+            .fetch_one(&self.pool)
+            .await?)
+    }
+
+    async fn get_least_recently_used(&self, limit: i64) -> Result<Vec<DataAccess>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT hash, ref_count, last_accessed
+            FROM data_entries
+            ORDER BY last_accessed ASC
+            LIMIT $1
+            "#,
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| DataAccess {
+                hash: row.get("hash"),
+                ref_count: row.get("ref_count"),
+                last_accessed: row.get("last_accessed"),
+            })
+            .collect())
+    }
+
+    async fn get_unreferenced(&self, accessed_before: DateTime<Utc>) -> Result<Vec<DataAccess>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT hash, ref_count, last_accessed
+            FROM data_entries
+            WHERE ref_count <= 0 AND last_accessed < $1
+            ORDER BY last_accessed ASC
+            "#,
+        )
+        .bind(accessed_before)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| DataAccess {
+                hash: row.get("hash"),
+                ref_count: row.get("ref_count"),
+                last_accessed: row.get("last_accessed"),
+            })
+            .collect())
+    }
+
+    async fn delete_unreferenced(
+        &mut self,
+        hash: &str,
+        accessed_before: DateTime<Utc>,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM data_entries
+            WHERE hash = $1 AND ref_count <= 0 AND last_accessed < $2
+            "#,
+        )
+        .bind(hash)
+        .bind(accessed_before)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+}
+
+impl PostgresDataTable {
+    /// Append to an array in the DB
+    async fn append_to_array<T>(
+        &mut self,
+        hash: &str,
+        column: &str,
+        value: T,
+        array_type: &str,
+    ) -> Result<()>
+    where
+        T: sqlx::Type<sqlx::Postgres>,
+        for<'r> &'r T: sqlx::Encode<'r, sqlx::Postgres>,
+        T: Send,
+    {
+        let query = format!(
+            r#"
+            UPDATE data_entries
+            SET {column} = array_append(
+                CASE
+                    WHEN $2 = ANY(COALESCE({column}, ARRAY[]::{array_type}[]))
+                    THEN {column}
+                    ELSE COALESCE({column}, ARRAY[]::{array_type}[])
+                END,
+                $2
+            )
+            WHERE hash = $1
+            AND NOT ($2 = ANY(COALESCE({column}, ARRAY[]::{array_type}[])))
+            "#
+        );
+
+        let result = sqlx::query(&query)
+            .bind(hash)
+            .bind(&value)
+            .execute(&self.pool)
+            .await?;
+
+        // If no rows were affected, check if the entry exists
+        if result.rows_affected() == 0 {
+            let exists = sqlx::query("SELECT 1 FROM data_entries WHERE hash = $1")
+                .bind(hash)
+                .fetch_optional(&self.pool)
+                .await?;
+
+            if exists.is_none() {
+                bail!("Not found")
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove from an array in the DB
+    async fn remove_from_array<T>(
+        &mut self,
+        hash: &str,
+        column: &str,
+        value: T,
+        array_type: &str,
+    ) -> Result<()>
+    where
+        T: sqlx::Type<sqlx::Postgres>,
+        for<'r> &'r T: sqlx::Encode<'r, sqlx::Postgres>,
+        T: Send,
+    {
+        let query = format!(
+            r#"
+            UPDATE data_entries
+            SET {column} = array_remove(COALESCE({column}, ARRAY[]::{array_type}[]), $2)
+            WHERE hash = $1
+            "#
+        );
+
+        let result = sqlx::query(&query)
+            .bind(hash)
+            .bind(&value)
+            .execute(&self.pool)
+            .await?;
+
+        // Verify one row was affected
+        if result.rows_affected() != 1 {
+            bail!("Not found")
+        }
+
+        Ok(())
+    }
+}
+
+/// SQLite implementation of the data table
+///
+/// SQLite has no array types, so the device and path lists are stored as JSON arrays.
+/// Device IDs are hex encoded inside those arrays.
+pub struct SqliteDataTable {
+    pool: SqlitePool,
+}
+
+#[allow(dead_code)]
+impl SqliteDataTable {
+    /// Create a new SqliteDataTable instance
+    pub async fn new(connection_string: &str) -> Result<Self> {
+        let pool = SqlitePool::connect(connection_string).await?;
+
+        // Ensure table exists
+        Self::create_table(&pool).await?;
+
+        Ok(Self { pool })
+    }
+
+    /// Create a new SqliteDataTable from an existing pool connection
+    pub async fn from_pool(pool: SqlitePool) -> Result<Self> {
+        Self::create_table(&pool).await?;
+        Ok(Self { pool })
+    }
+
+    /// Create the data table if it doesn't exist
+    async fn create_table(pool: &SqlitePool) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS data_entries (
+                hash TEXT PRIMARY KEY,
+                ref_count INTEGER NOT NULL DEFAULT 0,
+                inline_data BLOB,
+                devices TEXT NOT NULL DEFAULT '[]',
+                local_path TEXT NOT NULL DEFAULT '[]',
+                s3_path TEXT NOT NULL DEFAULT '[]',
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+                last_accessed TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+            );"#,
+        )
+        .execute(pool)
+        .await?;
+
+        Self::add_missing_columns(pool).await?;
+        Self::create_info_table(pool).await
+    }
+
+    /// Add the columns that are newer than an existing data table
+    ///
+    /// Existing data counts as accessed now, so it isn't the first to be collected.
+    async fn add_missing_columns(pool: &SqlitePool) -> Result<()> {
+        let has_last_accessed: bool = sqlx::query_scalar(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('data_entries') WHERE name = 'last_accessed'",
+        )
+        .fetch_one(pool)
+        .await?;
+        if !has_last_accessed {
+            // SQLite only allows constant defaults when adding a column
+            let mut tx = pool.begin().await?;
+            sqlx::query(
+                r#"
+                ALTER TABLE data_entries
+                ADD COLUMN last_accessed TEXT NOT NULL DEFAULT '1970-01-01 00:00:00.000'
+                "#,
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query(
+                "UPDATE data_entries SET last_accessed = strftime('%Y-%m-%d %H:%M:%f', 'now')",
+            )
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+        }
+        Ok(())
+    }
+
+    /// Create the table holding the database id, see [`DataTable::database_id`]
+    async fn create_info_table(pool: &SqlitePool) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS data_info (
+                singleton INTEGER PRIMARY KEY CHECK (singleton = 1),
+                database_id TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(pool)
+        .await?;
+        sqlx::query(
+            "INSERT INTO data_info (singleton, database_id) VALUES (1, ?1) ON CONFLICT DO NOTHING",
+        )
+        .bind(Uuid::new_v4().to_string())
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Convert a row from the data table into a DataEntry
+    fn entry_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<DataEntry> {
+        let devices: Vec<String> = serde_json::from_str(row.get("devices"))?;
+        let devices = devices
+            .iter()
+            .map(|device| {
+                let bytes = hex::decode(device)?;
+                bytes
+                    .try_into()
+                    .map_err(|_| anyhow!("Stored device id has the wrong length"))
+            })
+            .collect::<Result<Vec<DeviceId>>>()?;
+
+        Ok(DataEntry {
+            hash: row.get("hash"),
+            ref_count: row.get("ref_count"),
+            inline_data: row.get("inline_data"),
+            devices,
+            local_path: serde_json::from_str(row.get("local_path"))?,
+            s3_path: serde_json::from_str(row.get("s3_path"))?,
+        })
+    }
+
+    /// Append to a JSON array column, ignoring duplicates
+    async fn append_to_array(&mut self, hash: &str, column: &str, value: String) -> Result<()> {
+        let query = format!(
+            r#"
+            UPDATE data_entries
+            SET {column} = json_insert({column}, '$[#]', ?2)
+            WHERE hash = ?1
+            AND NOT EXISTS (SELECT 1 FROM json_each({column}) WHERE value = ?2)
+            "#
+        );
+
+        let result = sqlx::query(&query)
+            .bind(hash)
+            .bind(&value)
+            .execute(&self.pool)
+            .await?;
+
+        // If no rows were affected, check if the entry exists
+        if result.rows_affected() == 0 {
+            let exists = sqlx::query("SELECT 1 FROM data_entries WHERE hash = ?1")
+                .bind(hash)
+                .fetch_optional(&self.pool)
+                .await?;
+
+            if exists.is_none() {
+                bail!("Not found")
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove from a JSON array column
+    async fn remove_from_array(&mut self, hash: &str, column: &str, value: &str) -> Result<()> {
+        let query = format!(
+            r#"
+            UPDATE data_entries
+            SET {column} = (SELECT json_group_array(value) FROM json_each({column}) WHERE value != ?2)
+            WHERE hash = ?1
+            "#
+        );
+
+        let result = sqlx::query(&query)
+            .bind(hash)
+            .bind(value)
+            .execute(&self.pool)
+            .await?;
+
+        // Verify one row was affected
+        if result.rows_affected() != 1 {
+            bail!("Not found")
+        }
+
+        Ok(())
+    }
+}
+
+impl DataTable for SqliteDataTable {
+    async fn get_or_insert_entry(&mut self, hash: &str) -> Result<DataEntry> {
+        let row = sqlx::query(
+            r#"
+            -- Tables upgraded from before last_accessed have a constant default for it
+            INSERT INTO data_entries (hash, last_accessed)
+            VALUES (?1, strftime('%Y-%m-%d %H:%M:%f', 'now'))
+            ON CONFLICT (hash) DO UPDATE SET
+                -- Set hash to itself to trigger the RETURNING clause
+                hash = excluded.hash
+            RETURNING
+                hash,
+                ref_count,
+                inline_data,
+                devices,
+                local_path,
+                s3_path
+            "#,
+        )
+        .bind(hash)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Self::entry_from_row(&row)
+    }
+
+    async fn get_entry(&self, hash: &str) -> Result<Option<DataEntry>> {
+        let row = sqlx::query(
+            r#"
+            SELECT
+                hash,
+                ref_count,
+                inline_data,
+                devices,
+                local_path,
+                s3_path
+            FROM data_entries
+            WHERE hash = ?1
+            "#,
+        )
+        .bind(hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.as_ref().map(Self::entry_from_row).transpose()
+    }
+
+    async fn create_entry(&mut self, entry: DataEntry) -> Result<()> {
+        let devices: Vec<String> = entry.devices.iter().map(hex::encode).collect();
+
+        let result = sqlx::query(
+            r#"
+            INSERT INTO data_entries
+                (hash, ref_count, inline_data, devices, local_path, s3_path, last_accessed)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6, strftime('%Y-%m-%d %H:%M:%f', 'now'))
+            "#,
+        )
+        .bind(&entry.hash)
+        .bind(entry.ref_count)
+        .bind(entry.inline_data)
+        .bind(serde_json::to_string(&devices)?)
+        .bind(serde_json::to_string(&entry.local_path)?)
+        .bind(serde_json::to_string(&entry.s3_path)?)
+        .execute(&self.pool)
+        .await;
+
+        // Add context for certain error codes
+        if let Err(e) = &result {
+            if let Some(db_error) = e.as_database_error() {
+                if db_error.is_unique_violation() {
+                    return Err(anyhow!("Entry already exists").context(db_error.to_string()));
+                }
+            }
+        }
+
+        // Handle other potential errors
+        result?;
+
+        Ok(())
+    }
+
+    async fn delete_entry(&mut self, hash: &str) -> Result<()> {
+        let result = sqlx::query("DELETE FROM data_entries WHERE hash = ?1")
+            .bind(hash)
+            .execute(&self.pool)
+            .await;
+
+        match result {
+            Ok(rows_affected) if rows_affected.rows_affected() == 1 => Ok(()),
+            Ok(_) => Err(anyhow!("Record not found")),
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+
+    async fn increase_ref_count(&mut self, hash: &str) -> Result<i32> {
+        let row = sqlx::query(
+            r#"
+            UPDATE data_entries
+            SET ref_count = ref_count + 1
+            WHERE hash = ?1
+            RETURNING ref_count
+            "#,
+        )
+        .bind(hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match row {
+            Some(row) => Ok(row.get("ref_count")),
+            None => Err(anyhow!("Not found")),
+        }
+    }
+
+    async fn increase_ref_counts(&mut self, hashes: &[String]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for hash in hashes {
+            let result = sqlx::query(
+                r#"
+                UPDATE data_entries
+                SET ref_count = ref_count + 1
+                WHERE hash = ?1
+                "#,
+            )
+            .bind(hash)
+            .execute(&mut *tx)
+            .await?;
+
+            // The transaction rolls back on drop
+            if result.rows_affected() != 1 {
+                bail!("Data {} not found", hash);
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
     async fn decrease_ref_count(&mut self, hash: &str) -> Result<i32> {
         let row = sqlx::query(
             r#"
             UPDATE data_entries
-            SET ref_count = GREATEST(ref_count - 1, 0)
-            WHERE hash = $1
+            SET ref_count = MAX(ref_count - 1, 0)
+            WHERE hash = ?1
             RETURNING ref_count
             "#,
         )
@@ -284,17 +958,11 @@ impl DataTable for PostgresDataTable {
     }
 
     async fn add_inline_data(&mut self, hash: &str, data: Vec<u8>) -> Result<()> {
-        let result = sqlx::query(
-            r#"
-            UPDATE data_entries
-            SET inline_data = $2
-            WHERE hash = $1
-            "#,
-        )
-        .bind(hash)
-        .bind(&data)
-        .execute(&self.pool)
-        .await?;
+        let result = sqlx::query("UPDATE data_entries SET inline_data = ?2 WHERE hash = ?1")
+            .bind(hash)
+            .bind(data)
+            .execute(&self.pool)
+            .await?;
 
         // Verify one row was affected
         if result.rows_affected() != 1 {
@@ -305,16 +973,10 @@ impl DataTable for PostgresDataTable {
     }
 
     async fn remove_inline_data(&mut self, hash: &str) -> Result<()> {
-        let result = sqlx::query(
-            r#"
-            UPDATE data_entries
-            SET inline_data = NULL
-            WHERE hash = $1
-            "#,
-        )
-        .bind(hash)
-        .execute(&self.pool)
-        .await?;
+        let result = sqlx::query("UPDATE data_entries SET inline_data = NULL WHERE hash = ?1")
+            .bind(hash)
+            .execute(&self.pool)
+            .await?;
 
         // Verify one row was affected
         if result.rows_affected() != 1 {
@@ -325,117 +987,121 @@ impl DataTable for PostgresDataTable {
     }
 
     async fn add_s3_path(&mut self, hash: &str, path: String) -> Result<()> {
-        self.append_to_array(hash, "s3_path", path, "TEXT").await
+        self.append_to_array(hash, "s3_path", path).await
     }
 
     async fn add_local_path(&mut self, hash: &str, path: String) -> Result<()> {
-        self.append_to_array(hash, "local_path", path, "TEXT").await
+        self.append_to_array(hash, "local_path", path).await
     }
 
     async fn add_device(&mut self, hash: &str, device_id: DeviceId) -> Result<()> {
-        self.append_to_array(hash, "devices", device_id, "BYTEA")
+        self.append_to_array(hash, "devices", hex::encode(device_id))
             .await
     }
 
     async fn remove_s3_path(&mut self, hash: &str, path: &str) -> Result<()> {
-        self.remove_from_array(hash, "s3_path", path, "TEXT").await
+        self.remove_from_array(hash, "s3_path", path).await
     }
 
     async fn remove_local_path(&mut self, hash: &str, path: &str) -> Result<()> {
-        self.remove_from_array(hash, "local_path", path, "TEXT")
-            .await
+        self.remove_from_array(hash, "local_path", path).await
     }
 
     async fn remove_device(&mut self, hash: &str, device_id: DeviceId) -> Result<()> {
-        self.remove_from_array(hash, "devices", device_id, "BYTEA")
+        self.remove_from_array(hash, "devices", &hex::encode(device_id))
             .await
     }
-}
 
-impl PostgresDataTable {
-    /// Append to an array in the DB
-    async fn append_to_array<T>(
-        &mut self,
-        hash: &str,
-        column: &str,
-        value: T,
-        array_type: &str,
-    ) -> Result<()>
-    where
-        T: sqlx::Type<sqlx::Postgres>,
-        for<'r> &'r T: sqlx::Encode<'r, sqlx::Postgres>,
-        T: Send,
-    {
-        let query = format!(
+    async fn touch(&self, hash: &str) -> Result<()> {
+        let result = sqlx::query(
             r#"
             UPDATE data_entries
-            SET {column} = array_append(
-                CASE
-                    WHEN $2 = ANY(COALESCE({column}, ARRAY[]::{array_type}[]))
-                    THEN {column}
-                    ELSE COALESCE({column}, ARRAY[]::{array_type}[])
-                END,
-                $2
-            )
-            WHERE hash = $1
-            AND NOT ($2 = ANY(COALESCE({column}, ARRAY[]::{array_type}[])))
-            "#
-        );
-
-        let result = sqlx::query(&query)
-            .bind(hash)
-            .bind(&value)
-            .execute(&self.pool)
-            .await?;
-
-        // If no rows were affected, check if the entry exists
-        if result.rows_affected() == 0 {
-            let exists = sqlx::query("SELECT 1 FROM data_entries WHERE hash = $1")
-                .bind(hash)
-                .fetch_optional(&self.pool)
-                .await?;
+            SET last_accessed = strftime('%Y-%m-%d %H:%M:%f', 'now')
+            WHERE hash = ?1
+            "#,
+        )
+        .bind(hash)
+        .execute(&self.pool)
+        .await?;
 
-            if exists.is_none() {
-                bail!("Not found")
-            }
+        // Verify one row was affected
+        if result.rows_affected() != 1 {
+            bail!("Not found")
         }
 
         Ok(())
     }
 
-    /// Remove from an array in the DB
-    async fn remove_from_array<T>(
-        &mut self,
-        hash: &str,
-        column: &str,
-        value: T,
-        array_type: &str,
-    ) -> Result<()>
-    where
-        T: sqlx::Type<sqlx::Postgres>,
-        for<'r> &'r T: sqlx::Encode<'r, sqlx::Postgres>,
-        T: Send,
-    {
-        let query = format!(
+    async fn database_id(&self) -> Result<Uuid> {
+        let id: String = sqlx::query_scalar("SELECT database_id FROM data_info")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(Uuid::parse_str(&id)?)
+    }
+
+    async fn get_least_recently_used(&self, limit: i64) -> Result<Vec<DataAccess>> {
+        let rows = sqlx::query(
             r#"
-            UPDATE data_entries
-            SET {column} = array_remove(COALESCE({column}, ARRAY[]::{array_type}[]), $2)
-            WHERE hash = $1
-            "#
-        );
+            SELECT hash, ref_count, last_accessed
+            FROM data_entries
+            ORDER BY last_accessed ASC
+            LIMIT ?1
+            "#,
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
 
-        let result = sqlx::query(&query)
-            .bind(hash)
-            .bind(&value)
-            .execute(&self.pool)
-            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| DataAccess {
+                hash: row.get("hash"),
+                ref_count: row.get("ref_count"),
+                last_accessed: row.get("last_accessed"),
+            })
+            .collect())
+    }
 
-        // Verify one row was affected
-        if result.rows_affected() != 1 {
-            bail!("Not found")
-        }
+    async fn get_unreferenced(&self, accessed_before: DateTime<Utc>) -> Result<Vec<DataAccess>> {
+        // Access times are stored as text, in a format that sorts chronologically
+        let rows = sqlx::query(
+            r#"
+            SELECT hash, ref_count, last_accessed
+            FROM data_entries
+            WHERE ref_count <= 0 AND last_accessed < ?1
+            ORDER BY last_accessed ASC
+            "#,
+        )
+        .bind(accessed_before.format("%Y-%m-%d %H:%M:%S%.3f").to_string())
+        .fetch_all(&self.pool)
+        .await?;
 
-        Ok(())
+        Ok(rows
+            .into_iter()
+            .map(|row| DataAccess {
+                hash: row.get("hash"),
+                ref_count: row.get("ref_count"),
+                last_accessed: row.get("last_accessed"),
+            })
+            .collect())
+    }
+
+    async fn delete_unreferenced(
+        &mut self,
+        hash: &str,
+        accessed_before: DateTime<Utc>,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM data_entries
+            WHERE hash = ?1 AND ref_count <= 0 AND last_accessed < ?2
+            "#,
+        )
+        .bind(hash)
+        .bind(accessed_before.format("%Y-%m-%d %H:%M:%S%.3f").to_string())
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
     }
 }
 
@@ -846,4 +1512,359 @@ mod tests {
 
         Ok(())
     }
+
+    #[sqlx::test]
+    async fn test_increase_ref_counts_batch(pool: PgPool) -> Result<()> {
+        let mut table = PostgresDataTable::from_pool(pool).await?;
+        let first = generate_hash("first".as_bytes())?;
+        let second = generate_hash("second".as_bytes())?;
+        table.get_or_insert_entry(&first).await?;
+        table.get_or_insert_entry(&second).await?;
+
+        // A hash given more than once is increased once for each time
+        table
+            .increase_ref_counts(&[first.clone(), second.clone(), first.clone()])
+            .await?;
+        assert_eq!(table.get_entry(&first).await?.unwrap().ref_count, 2);
+        assert_eq!(table.get_entry(&second).await?.unwrap().ref_count, 1);
+
+        // A missing hash rolls back the whole batch, including repeats
+        let missing = generate_hash("missing".as_bytes())?;
+        assert!(table
+            .increase_ref_counts(&[second.clone(), second.clone(), missing])
+            .await
+            .is_err());
+        assert_eq!(table.get_entry(&second).await?.unwrap().ref_count, 1);
+
+        // An empty batch changes nothing
+        table.increase_ref_counts(&[]).await?;
+        assert_eq!(table.get_entry(&first).await?.unwrap().ref_count, 2);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_least_recently_used(pool: PgPool) -> Result<()> {
+        let mut table = PostgresDataTable::from_pool(pool).await?;
+        let hashes: Vec<String> = (0..3)
+            .map(|i| generate_hash(format!("data {i}").as_bytes()).unwrap())
+            .collect();
+
+        for hash in &hashes {
+            table.create_entry(DataEntry::new(hash)).await?;
+        }
+
+        // Touch in reverse order so the first entry is the most recently used
+        for hash in hashes.iter().rev() {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            table.touch(hash).await?;
+        }
+
+        let lru = table.get_least_recently_used(10).await?;
+        let order: Vec<&str> = lru.iter().map(|access| access.hash.as_str()).collect();
+        assert_eq!(order, vec![&hashes[2], &hashes[1], &hashes[0]]);
+        assert!(lru[0].last_accessed <= lru[1].last_accessed);
+
+        // The limit is respected
+        assert_eq!(table.get_least_recently_used(1).await?.len(), 1);
+
+        // Touching an unknown hash is an error
+        let non_existent = generate_hash("non_existent".as_bytes()).unwrap();
+        assert!(table.touch(&non_existent).await.is_err());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_sqlite_entry_round_trip(pool: SqlitePool) -> Result<()> {
+        let mut table = SqliteDataTable::from_pool(pool).await?;
+        let device_id = generate_test_device_id();
+        let hash = generate_hash("test_data".as_bytes())?;
+
+        let original_entry = DataEntry {
+            hash: hash.clone(),
+            ref_count: 1,
+            inline_data: Some(b"test data".to_vec()),
+            devices: vec![device_id],
+            local_path: vec!["local/path/to/file".to_string()],
+            s3_path: vec!["s3/path/to/file".to_string()],
+        };
+        table.create_entry(original_entry.clone()).await?;
+
+        // Duplicates are rejected
+        assert!(table.create_entry(original_entry.clone()).await.is_err());
+
+        // The JSON encoded columns come back unchanged
+        assert_eq!(table.get_entry(&hash).await?, Some(original_entry.clone()));
+        assert_eq!(table.get_or_insert_entry(&hash).await?, original_entry);
+
+        // Missing entries are inserted with defaults
+        let other_hash = generate_hash("other_data".as_bytes())?;
+        assert_eq!(
+            table.get_or_insert_entry(&other_hash).await?,
+            DataEntry::new(other_hash.clone())
+        );
+
+        table.delete_entry(&other_hash).await?;
+        assert!(table.get_entry(&other_hash).await?.is_none());
+        assert!(table.delete_entry(&other_hash).await.is_err());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_sqlite_array_operations(pool: SqlitePool) -> Result<()> {
+        let mut table = SqliteDataTable::from_pool(pool).await?;
+        let device_id = generate_test_device_id();
+        let hash = generate_hash("test_data".as_bytes())?;
+        table.get_or_insert_entry(&hash).await?;
+
+        // Additions are deduplicated
+        table.add_local_path(&hash, "path1".to_string()).await?;
+        table.add_local_path(&hash, "path2".to_string()).await?;
+        table.add_local_path(&hash, "path1".to_string()).await?;
+        table.add_s3_path(&hash, "s3path".to_string()).await?;
+        table.add_device(&hash, device_id).await?;
+        table.add_device(&hash, device_id).await?;
+
+        let entry = table.get_entry(&hash).await?.unwrap();
+        assert_eq!(entry.local_path, vec!["path1", "path2"]);
+        assert_eq!(entry.s3_path, vec!["s3path"]);
+        assert_eq!(entry.devices, vec![device_id]);
+
+        table.remove_local_path(&hash, "path1").await?;
+        table.remove_s3_path(&hash, "s3path").await?;
+        table.remove_device(&hash, device_id).await?;
+
+        let entry = table.get_entry(&hash).await?.unwrap();
+        assert_eq!(entry.local_path, vec!["path2"]);
+        assert!(entry.s3_path.is_empty());
+        assert!(entry.devices.is_empty());
+
+        // Operations on missing entries fail
+        let missing = generate_hash("missing".as_bytes())?;
+        assert!(table
+            .add_local_path(&missing, "path".to_string())
+            .await
+            .is_err());
+        assert!(table.remove_local_path(&missing, "path").await.is_err());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_sqlite_ref_count_and_access(pool: SqlitePool) -> Result<()> {
+        let mut table = SqliteDataTable::from_pool(pool).await?;
+        let first = generate_hash("first".as_bytes())?;
+        let second = generate_hash("second".as_bytes())?;
+        table.get_or_insert_entry(&first).await?;
+        table.get_or_insert_entry(&second).await?;
+
+        assert_eq!(table.increase_ref_count(&first).await?, 1);
+        assert_eq!(table.increase_ref_count(&first).await?, 2);
+        assert_eq!(table.decrease_ref_count(&first).await?, 1);
+        assert_eq!(table.decrease_ref_count(&second).await?, 0);
+        assert!(table.increase_ref_count("missing").await.is_err());
+
+        table.add_inline_data(&first, b"data".to_vec()).await?;
+        assert_eq!(
+            table.get_entry(&first).await?.unwrap().inline_data,
+            Some(b"data".to_vec())
+        );
+        table.remove_inline_data(&first).await?;
+        assert_eq!(table.get_entry(&first).await?.unwrap().inline_data, None);
+
+        // Touching the first entry makes the second the least recently used
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        table.touch(&first).await?;
+        let lru = table.get_least_recently_used(2).await?;
+        assert_eq!(lru.len(), 2);
+        assert_eq!(lru[0].hash, second);
+        assert_eq!(lru[1].hash, first);
+        assert_eq!(lru[1].ref_count, 1);
+        assert!(table.touch("missing").await.is_err());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_sqlite_increase_ref_counts_batch(pool: SqlitePool) -> Result<()> {
+        let mut table = SqliteDataTable::from_pool(pool).await?;
+        let first = generate_hash("first".as_bytes())?;
+        let second = generate_hash("second".as_bytes())?;
+        table.get_or_insert_entry(&first).await?;
+        table.get_or_insert_entry(&second).await?;
+
+        table
+            .increase_ref_counts(&[first.clone(), second.clone(), first.clone()])
+            .await?;
+        assert_eq!(table.get_entry(&first).await?.unwrap().ref_count, 2);
+        assert_eq!(table.get_entry(&second).await?.unwrap().ref_count, 1);
+
+        let missing = generate_hash("missing".as_bytes())?;
+        assert!(table
+            .increase_ref_counts(&[second.clone(), second.clone(), missing])
+            .await
+            .is_err());
+        assert_eq!(table.get_entry(&second).await?.unwrap().ref_count, 1);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_sqlite_unreferenced(pool: SqlitePool) -> Result<()> {
+        let mut table = SqliteDataTable::from_pool(pool).await?;
+        let referenced = generate_hash("referenced".as_bytes())?;
+        let unreferenced = generate_hash("unreferenced".as_bytes())?;
+        table.get_or_insert_entry(&referenced).await?;
+        table.get_or_insert_entry(&unreferenced).await?;
+        table.increase_ref_count(&referenced).await?;
+
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        let found = table.get_unreferenced(Utc::now()).await?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].hash, unreferenced);
+
+        // Only data accessed before the cutoff is returned
+        let cutoff = Utc::now();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        table.touch(&unreferenced).await?;
+        assert!(table.get_unreferenced(cutoff).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_upgrade_adds_last_accessed(pool: PgPool) -> Result<()> {
+        // The data table as it was before access times were tracked
+        sqlx::query(
+            r#"
+            CREATE TABLE data_entries (
+                hash CHAR(67) PRIMARY KEY,
+                ref_count INT NOT NULL DEFAULT 0,
+                inline_data BYTEA,
+                devices BYTEA[] NOT NULL DEFAULT '{}',
+                local_path TEXT[] NOT NULL DEFAULT '{}',
+                s3_path TEXT[] NOT NULL DEFAULT '{}',
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            )"#,
+        )
+        .execute(&pool)
+        .await?;
+        let hash = generate_hash("old data".as_bytes())?;
+        sqlx::query("INSERT INTO data_entries (hash) VALUES ($1)")
+            .bind(&hash)
+            .execute(&pool)
+            .await?;
+
+        let table = PostgresDataTable::from_pool(pool).await?;
+        table.touch(&hash).await?;
+        assert_eq!(table.get_least_recently_used(10).await?[0].hash, hash);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_sqlite_upgrade_adds_last_accessed(pool: SqlitePool) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE data_entries (
+                hash TEXT PRIMARY KEY,
+                ref_count INTEGER NOT NULL DEFAULT 0,
+                inline_data BLOB,
+                devices TEXT NOT NULL DEFAULT '[]',
+                local_path TEXT NOT NULL DEFAULT '[]',
+                s3_path TEXT NOT NULL DEFAULT '[]',
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+            )"#,
+        )
+        .execute(&pool)
+        .await?;
+        let hash = generate_hash("old data".as_bytes())?;
+        sqlx::query("INSERT INTO data_entries (hash) VALUES (?1)")
+            .bind(&hash)
+            .execute(&pool)
+            .await?;
+
+        let mut table = SqliteDataTable::from_pool(pool.clone()).await?;
+        table.touch(&hash).await?;
+        assert_eq!(table.get_least_recently_used(10).await?[0].hash, hash);
+
+        // Neither existing nor new data is treated as long unused
+        table
+            .get_or_insert_entry(&generate_hash("new data".as_bytes())?)
+            .await?;
+        let hour_ago = Utc::now() - chrono::Duration::hours(1);
+        assert!(table.get_unreferenced(hour_ago).await?.is_empty());
+
+        // Opening it again doesn't change anything
+        SqliteDataTable::from_pool(pool).await?;
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_delete_unreferenced(pool: PgPool) -> Result<()> {
+        let mut table = PostgresDataTable::from_pool(pool).await?;
+        let hash = generate_hash("data".as_bytes())?;
+        table.get_or_insert_entry(&hash).await?;
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        let cutoff = Utc::now();
+
+        // Referenced data is kept, even after it was listed as unreferenced
+        assert_eq!(table.get_unreferenced(cutoff).await?.len(), 1);
+        table.increase_ref_count(&hash).await?;
+        assert!(!table.delete_unreferenced(&hash, cutoff).await?);
+        assert!(table.get_entry(&hash).await?.is_some());
+
+        // As is data accessed after the cutoff
+        table.decrease_ref_count(&hash).await?;
+        table.touch(&hash).await?;
+        assert!(!table.delete_unreferenced(&hash, cutoff).await?);
+
+        assert!(table.delete_unreferenced(&hash, Utc::now()).await?);
+        assert!(table.get_entry(&hash).await?.is_none());
+        assert!(!table.delete_unreferenced(&hash, Utc::now()).await?);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_sqlite_delete_unreferenced(pool: SqlitePool) -> Result<()> {
+        let mut table = SqliteDataTable::from_pool(pool).await?;
+        let hash = generate_hash("data".as_bytes())?;
+        table.get_or_insert_entry(&hash).await?;
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        let cutoff = Utc::now();
+
+        table.increase_ref_count(&hash).await?;
+        assert!(!table.delete_unreferenced(&hash, cutoff).await?);
+        assert!(table.get_entry(&hash).await?.is_some());
+
+        table.decrease_ref_count(&hash).await?;
+        assert!(table.delete_unreferenced(&hash, cutoff).await?);
+        assert!(table.get_entry(&hash).await?.is_none());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_database_id(pool: PgPool) -> Result<()> {
+        let id = PostgresDataTable::from_pool(pool.clone())
+            .await?
+            .database_id()
+            .await?;
+        let reopened = PostgresDataTable::from_pool(pool).await?;
+        assert_eq!(reopened.database_id().await?, id);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_sqlite_database_id(pool: SqlitePool) -> Result<()> {
+        let id = SqliteDataTable::from_pool(pool.clone())
+            .await?
+            .database_id()
+            .await?;
+        let reopened = SqliteDataTable::from_pool(pool).await?;
+        assert_eq!(reopened.database_id().await?, id);
+
+        // Another database has another id
+        let other = SqliteDataTable::new("sqlite::memory:").await?;
+        assert_ne!(other.database_id().await?, id);
+        Ok(())
+    }
 }
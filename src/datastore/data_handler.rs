@@ -1,10 +1,16 @@
 use super::data::DataTable;
-use super::schema::{DataEntry, DeviceId};
-use crate::utils;
+use super::schema::{DataAccess, DataEntry, DeviceId};
+use crate::utils::{generate_hash, generate_hash_from_path};
 use anyhow::{bail, Result};
+use chrono::{Duration, Utc};
 use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+use uuid::Uuid;
+
+/// Default number of bytes held in memory at once while ingesting data
+const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
 
 /// Manged Handler for the DataTable
 ///
@@ -13,6 +19,34 @@ use std::path::PathBuf;
 pub struct DataTableHandler<T: DataTable> {
     data_table: T,
     local_path: PathBuf,
+    /// Where data was stored before each database had its own directory, only read from
+    legacy_path: Option<PathBuf>,
+    /// Size of the buffer used to stream data in, bounding memory use during ingest
+    buffer_size: usize,
+}
+
+/// What was, or would be, removed by garbage collection
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// Data table entries that no metadata entry expects
+    pub entries: usize,
+    /// Local files removed
+    pub files: usize,
+    /// Total size of the local files removed
+    pub bytes: u64,
+}
+
+/// Result of checking the copy of a piece of data stored on this device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataCheck {
+    /// The data matches its hash
+    Valid,
+    /// The data doesn't match its hash
+    Corrupt,
+    /// The data table records a local copy, but the file is gone
+    Missing,
+    /// No copy is stored on this device, so there's nothing to check
+    NotStored,
 }
 
 /// Represents different types of data storage locations.
@@ -32,9 +66,32 @@ impl<T: DataTable> DataTableHandler<T> {
         Self {
             data_table,
             local_path,
+            legacy_path: None,
+            buffer_size: DEFAULT_BUFFER_SIZE,
         }
     }
 
+    /// Also read data from `legacy_path`, where it was stored before each database had its
+    /// own directory
+    ///
+    /// Files there may be shared with other databases, so they're never removed.
+    pub fn with_legacy_path(mut self, legacy_path: PathBuf) -> Self {
+        self.legacy_path = Some(legacy_path);
+        self
+    }
+
+    /// Id of the database, see [`DataTable::database_id`]
+    pub async fn database_id(&self) -> Result<Uuid> {
+        self.data_table.database_id().await
+    }
+
+    /// Set the size of the buffer used when streaming data into the store
+    ///
+    /// Data read from local files is never held in memory beyond this many bytes.
+    pub fn set_buffer_size(&mut self, buffer_size: usize) {
+        self.buffer_size = buffer_size.max(1);
+    }
+
     /// Set a piece of data as 'wanted' to be present locally.
     ///
     /// This increases the refcount for this piece of data or adds it into the
@@ -45,6 +102,22 @@ impl<T: DataTable> DataTableHandler<T> {
         // TODO: Poke a bg job to go try to find data
     }
 
+    /// Set many pieces of data as 'wanted' in a single transaction
+    ///
+    /// See [`Self::set_local_needed`]. A hash given more than once is counted each time.
+    pub async fn set_many_local_needed(&mut self, hashes: &[String]) -> Result<()> {
+        self.data_table.increase_ref_counts(hashes).await
+    }
+
+    /// Record a piece of data that may not be stored on this device
+    ///
+    /// Adds an entry without any locations to the table if there isn't one yet, so entries
+    /// copied from elsewhere can reference data that wasn't copied with them.
+    pub async fn ensure_entry(&mut self, hash: &str) -> Result<()> {
+        self.data_table.get_or_insert_entry(hash).await?;
+        Ok(())
+    }
+
     /// Set a piece of data as 'unwanted' to be present locally.
     /// This decreases the refcount for this piece of data or removes it from the
     /// table if necessary.
@@ -88,15 +161,73 @@ impl<T: DataTable> DataTableHandler<T> {
             locations.push(DataLocation::S3(s3));
         }
 
+        self.touch(hash).await;
+
         Ok(locations)
     }
 
+    /// Get a location on this device the data for `hash` can be copied from
+    ///
+    /// Returns None if the data isn't available locally.
+    pub async fn get_readable_location(&self, hash: &str) -> Result<Option<DataLocation>> {
+        let Some(entry) = self.data_table.get_entry(hash).await? else {
+            return Ok(None);
+        };
+        if let Some(inline) = entry.inline_data {
+            return Ok(Some(DataLocation::Inline(inline)));
+        }
+        Ok(self
+            .get_local_path(hash)
+            .await
+            .ok()
+            .map(DataLocation::LocalPath))
+    }
+
+    /// Rehash the copy of the data for `hash` stored on this device
+    pub async fn verify_data(&self, hash: &str) -> Result<DataCheck> {
+        let Some(entry) = self.data_table.get_entry(hash).await? else {
+            return Ok(DataCheck::NotStored);
+        };
+        let actual = if let Some(inline) = entry.inline_data {
+            generate_hash(&inline)?
+        } else if entry.local_path.is_empty() {
+            return Ok(DataCheck::NotStored);
+        } else {
+            match self.get_local_path(hash).await {
+                Ok(path) => generate_hash_from_path(path)?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Ok(DataCheck::Missing)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        Ok(if actual == hash {
+            DataCheck::Valid
+        } else {
+            DataCheck::Corrupt
+        })
+    }
+
+    /// Get the least recently accessed data, oldest first
+    ///
+    /// These are the best candidates for removing from local storage.
+    pub async fn get_least_recently_used(&self, limit: i64) -> Result<Vec<DataAccess>> {
+        self.data_table.get_least_recently_used(limit).await
+    }
+
     /// Get the local path of the hash or return an error
     pub async fn get_local_path(&self, hash: &str) -> std::io::Result<PathBuf> {
         //let entry = self.data_table.get_entry(hash).await?;
         let local_path = self.hash_to_path(hash)?;
         if local_path.exists() {
-            Ok(local_path)
+            return Ok(local_path);
+        }
+        let legacy_path = match &self.legacy_path {
+            Some(base) => Some(hash_to_path_in(base, hash)?),
+            None => None,
+        };
+        if let Some(legacy_path) = legacy_path.filter(|path| path.exists()) {
+            Ok(legacy_path)
         } else {
             Err(std::io::Error::new(
                 std::io::ErrorKind::NotFound,
@@ -114,7 +245,7 @@ impl<T: DataTable> DataTableHandler<T> {
         self.data_table
             .remove_local_path(hash, path.as_os_str().to_str().unwrap_or_default())
             .await
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
 
         Ok(())
     }
@@ -128,53 +259,165 @@ impl<T: DataTable> DataTableHandler<T> {
         self.data_table
             .delete_entry(hash)
             .await
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
         Ok(())
     }
 
+    /// Remove data that nothing references any more
+    ///
+    /// Deletes the data table entries with a ref_count of zero that haven't been accessed
+    /// for `min_age`, along with their local files. Files the data table doesn't know about
+    /// are left alone, as are files in the legacy path, which other databases may share.
+    ///
+    /// The age limit protects data that is being ingested, which is recorded in the data
+    /// table before anything references it. Data that is referenced again while this runs is
+    /// kept, a file is only removed once its entry was deleted while still unreferenced. A run
+    /// interrupted in between leaves the file behind, to be reused if the data is stored again.
+    ///
+    /// # Arguments
+    /// * `min_age` - How long unreferenced data is kept after it was last accessed
+    /// * `dry_run` - Only report what would be removed
+    pub async fn collect_garbage(&mut self, min_age: Duration, dry_run: bool) -> Result<GcStats> {
+        let mut stats = GcStats::default();
+        let accessed_before = Utc::now() - min_age;
+
+        for access in self.data_table.get_unreferenced(accessed_before).await? {
+            if !dry_run
+                && !self
+                    .data_table
+                    .delete_unreferenced(&access.hash, accessed_before)
+                    .await?
+            {
+                continue;
+            }
+            stats.entries += 1;
+
+            let path = self.hash_to_path(&access.hash)?;
+            if let Ok(metadata) = std::fs::metadata(&path) {
+                if !dry_run {
+                    std::fs::remove_file(&path)?;
+                }
+                stats.files += 1;
+                stats.bytes += metadata.len();
+            }
+        }
+
+        Ok(stats)
+    }
+
     /// Copy a file into the data store
     ///
     /// This will:
-    /// 1. Calculate the Blake3 hash of the file
+    /// 1. Stream the data into a temporary file while calculating its Blake3 hash
     /// 2. Create an entry in the data table
     /// 3. Move the file to the correct local path
+    ///
+    /// Local files and URLs are streamed, so large data is never fully loaded into memory.
     pub async fn copy_file(&mut self, data: DataLocation) -> std::io::Result<DataEntry> {
-        let raw_data = match data {
-            DataLocation::Inline(raw_data) => raw_data,
-            DataLocation::LocalPath(path) => {
-                let mut file = File::open(path)?;
-                let mut buffer = Vec::new();
-                file.read_to_end(&mut buffer)?;
-                buffer
+        let pending = match data {
+            DataLocation::Inline(raw_data) => {
+                let mut pending = PendingFile::create(&self.local_path)?;
+                pending.write(&raw_data)?;
+                pending
             }
+            DataLocation::LocalPath(path) => self.stream_to_pending(File::open(path)?)?,
             DataLocation::Url(url) => {
-                // Download the url into a Vec<u8>
-                // TODO: don't unwrap
-                let response = reqwest::get(url).await.unwrap();
-                let bytes = response.bytes().await.unwrap();
-                bytes.to_vec()
+                let mut response = reqwest::get(url)
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(std::io::Error::other)?;
+                let mut pending = PendingFile::create(&self.local_path)?;
+                while let Some(chunk) = response.chunk().await.map_err(std::io::Error::other)? {
+                    pending.write(&chunk)?;
+                }
+                pending
             }
             _ => todo!(),
         };
-        let hash = utils::generate_hash(&raw_data)?;
+        self.store_pending(pending).await
+    }
+
+    /// Store everything read from `reader` into the data store
+    ///
+    /// The data is streamed through a buffer of the configured size, so it can be
+    /// arbitrarily large.
+    pub async fn put_reader<R: Read>(&mut self, reader: R) -> std::io::Result<DataEntry> {
+        let pending = self.stream_to_pending(reader)?;
+        self.store_pending(pending).await
+    }
+
+    /// Write the data for `hash` into `writer`, returning the number of bytes written
+    ///
+    /// Only inline data and data stored under the local path can be read.
+    pub async fn get_writer<W: Write>(&self, hash: &str, writer: &mut W) -> Result<u64> {
+        let entry = match self.data_table.get_entry(hash).await? {
+            Some(e) => e,
+            None => bail!("Not found"),
+        };
+
+        let written = if let Some(inline) = entry.inline_data {
+            writer.write_all(&inline)?;
+            inline.len() as u64
+        } else {
+            let path = self.get_local_path(hash).await?;
+            let mut file = File::open(path)?;
+            let mut buffer = vec![0; self.buffer_size];
+            let mut written = 0;
+            loop {
+                let bytes_read = file.read(&mut buffer)?;
+                if bytes_read == 0 {
+                    break;
+                }
+                writer.write_all(&buffer[..bytes_read])?;
+                written += bytes_read as u64;
+            }
+            written
+        };
+
+        self.touch(hash).await;
+
+        Ok(written)
+    }
+
+    /// Record a read of the data for `hash`
+    ///
+    /// Access times only guide garbage collection, so failing to update one doesn't fail
+    /// the read.
+    async fn touch(&self, hash: &str) {
+        if let Err(e) = self.data_table.touch(hash).await {
+            warn!(%hash, "Failed to update access time: {e}");
+        }
+    }
+
+    /// Read `reader` into a new pending file, one buffer at a time
+    fn stream_to_pending<R: Read>(&self, mut reader: R) -> std::io::Result<PendingFile> {
+        let mut pending = PendingFile::create(&self.local_path)?;
+        let mut buffer = vec![0; self.buffer_size];
+        loop {
+            let bytes_read = reader.read(&mut buffer)?;
+            if bytes_read == 0 {
+                break;
+            }
+            pending.write(&buffer[..bytes_read])?;
+        }
+        Ok(pending)
+    }
+
+    /// Move a fully written pending file into place and record it in the data table
+    async fn store_pending(&mut self, pending: PendingFile) -> std::io::Result<DataEntry> {
+        let hash = pending.hash();
 
         match self.data_table.get_or_insert_entry(&hash.clone()).await {
             Ok(entry) => entry,
             Err(e) => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("Failed to get or insert entry: {e}"),
-                ))
+                return Err(std::io::Error::other(format!(
+                    "Failed to get or insert entry: {e}"
+                )))
             }
         };
         let dest_path = match self.hash_to_path(hash.as_str()) {
             Ok(path) => path,
-            Err(_) => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Failed to convert hash to path",
-                ))
-            }
+            Err(_) => return Err(std::io::Error::other("Failed to convert hash to path")),
         };
 
         // Create parent directories if they don't exist
@@ -182,8 +425,10 @@ impl<T: DataTable> DataTableHandler<T> {
             std::fs::create_dir_all(parent)?;
         }
 
-        // Copy the file
-        std::fs::write(&dest_path, raw_data)?;
+        // Move the file into place, identical data may already be there
+        if !dest_path.exists() {
+            pending.persist(&dest_path)?;
+        }
 
         // Update the table
         match self
@@ -193,21 +438,25 @@ impl<T: DataTable> DataTableHandler<T> {
         {
             Ok(_) => {}
             Err(e) => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("Failed to add local path: {e}"),
-                ))
+                return Err(std::io::Error::other(format!(
+                    "Failed to add local path: {e}"
+                )))
             }
         };
 
+        // Storing data that already existed still counts as an access
+        self.data_table
+            .touch(&hash)
+            .await
+            .map_err(|e| std::io::Error::other(format!("Failed to update access time: {e}")))?;
+
         Ok(
             match self.data_table.get_or_insert_entry(&hash.clone()).await {
                 Ok(entry) => entry,
                 Err(e) => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("Failed to get or insert entry: {e}"),
-                    ))
+                    return Err(std::io::Error::other(format!(
+                        "Failed to get or insert entry: {e}"
+                    )))
                 }
             },
         )
@@ -271,35 +520,93 @@ impl<T: DataTable> DataTableHandler<T> {
     /// * Any remaining single character becomes the final component
     /// * Example: "b3_abcdefg" becomes "base_path/b3/ab/cd/ef/g"
     fn hash_to_path(&self, hash: &str) -> std::io::Result<PathBuf> {
-        let mut path = self.local_path.clone();
-
-        // Return error if hash is empty or doesn't start with b3_
-        if hash.is_empty() || !hash.starts_with("b3_") {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                format!("Incorrect hash formatting: {}", hash),
-            ));
-        };
+        hash_to_path_in(&self.local_path, hash)
+    }
+}
 
-        path.push("b3");
+/// Path of the file for `hash` under `base`, see [`DataTableHandler::hash_to_path`]
+fn hash_to_path_in(base: &Path, hash: &str) -> std::io::Result<PathBuf> {
+    let mut path = base.to_path_buf();
 
-        // Get hash part after prefix
-        let hash_part = &hash[3..];
-        let chars: Vec<_> = hash_part.chars().collect();
+    // Return error if hash is empty or doesn't start with b3_
+    if hash.is_empty() || !hash.starts_with("b3_") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Incorrect hash formatting: {}", hash),
+        ));
+    };
 
-        // Process complete pairs into directories (all but the last pair)
-        let pairs_to_process = chars.len() / 2;
-        for i in 0..pairs_to_process {
-            path.push(chars[i * 2..i * 2 + 2].iter().collect::<String>());
-        }
+    path.push("b3");
 
-        // Add remaining characters as the final component if there are any
-        let remaining_start = pairs_to_process * 2;
-        if remaining_start < chars.len() {
-            path.push(chars[remaining_start..].iter().collect::<String>());
-        }
+    // Get hash part after prefix
+    let hash_part = &hash[3..];
+    let chars: Vec<_> = hash_part.chars().collect();
+
+    // Process complete pairs into directories (all but the last pair)
+    let pairs_to_process = chars.len() / 2;
+    for i in 0..pairs_to_process {
+        path.push(chars[i * 2..i * 2 + 2].iter().collect::<String>());
+    }
+
+    // Add remaining characters as the final component if there are any
+    let remaining_start = pairs_to_process * 2;
+    if remaining_start < chars.len() {
+        path.push(chars[remaining_start..].iter().collect::<String>());
+    }
+
+    Ok(path)
+}
+
+/// Data being streamed into a temporary file inside the local path
+///
+/// The file is deleted on drop unless it was persisted.
+struct PendingFile {
+    path: PathBuf,
+    file: File,
+    hasher: blake3::Hasher,
+    persisted: bool,
+}
 
-        Ok(path)
+impl PendingFile {
+    /// Create a new temporary file under `local_path`
+    fn create(local_path: &Path) -> std::io::Result<Self> {
+        let dir = local_path.join("tmp");
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(Uuid::now_v7().to_string());
+        let file = File::create(&path)?;
+        Ok(Self {
+            path,
+            file,
+            hasher: blake3::Hasher::new(),
+            persisted: false,
+        })
+    }
+
+    /// Append bytes to the file and the hash
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.hasher.update(bytes);
+        self.file.write_all(bytes)
+    }
+
+    /// Hash of everything written so far
+    fn hash(&self) -> String {
+        format!("b3_{}", self.hasher.finalize().to_hex())
+    }
+
+    /// Move the finished file to its final location
+    fn persist(mut self, dest_path: &Path) -> std::io::Result<()> {
+        self.file.flush()?;
+        std::fs::rename(&self.path, dest_path)?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for PendingFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(&self.path);
+        }
     }
 }
 
@@ -307,6 +614,7 @@ impl<T: DataTable> DataTableHandler<T> {
 mod tests {
     use super::*;
     use crate::datastore::data::PostgresDataTable;
+    use chrono::DateTime;
     use sqlx::PgPool;
     use std::fs::{self, File};
     use std::io::Write;
@@ -340,6 +648,10 @@ mod tests {
             todo!()
         }
 
+        async fn increase_ref_counts(&mut self, _: &[String]) -> Result<()> {
+            todo!()
+        }
+
         async fn decrease_ref_count(&mut self, _: &str) -> Result<i32> {
             todo!()
         }
@@ -375,6 +687,26 @@ mod tests {
         async fn remove_inline_data(&mut self, _: &str) -> Result<()> {
             todo!()
         }
+
+        async fn touch(&self, _: &str) -> Result<()> {
+            todo!()
+        }
+
+        async fn get_least_recently_used(&self, _: i64) -> Result<Vec<DataAccess>> {
+            todo!()
+        }
+
+        async fn get_unreferenced(&self, _: DateTime<Utc>) -> Result<Vec<DataAccess>> {
+            todo!()
+        }
+
+        async fn delete_unreferenced(&mut self, _: &str, _: DateTime<Utc>) -> Result<bool> {
+            todo!()
+        }
+
+        async fn database_id(&self) -> Result<Uuid> {
+            todo!()
+        }
     }
 
     fn setup_handler() -> DataTableHandler<MockDataTable> {
@@ -571,4 +903,139 @@ mod tests {
             .await;
         assert!(result.is_err());
     }
+
+    #[sqlx::test]
+    async fn test_copy_file_streaming(pool: PgPool) -> TestResult<()> {
+        let mut handler = setup_handler_postgres(pool).await;
+        // Force the file to be read in many small pieces
+        handler.set_buffer_size(7);
+
+        let temp_dir = tempdir()?;
+        let source_path = temp_dir.path().join("large.txt");
+        let content = "streamed content ".repeat(100);
+        fs::write(&source_path, &content)?;
+
+        let result = handler
+            .copy_file(DataLocation::LocalPath(source_path))
+            .await?;
+
+        // The hash covers the whole file, not just one buffer
+        assert_eq!(
+            result.hash,
+            crate::utils::generate_hash(content.as_bytes())?
+        );
+        let stored = fs::read_to_string(handler.hash_to_path(&result.hash)?)?;
+        assert_eq!(stored, content);
+
+        // Inline data is stored the same way
+        let inline = handler
+            .copy_file(DataLocation::Inline(content.clone().into_bytes()))
+            .await?;
+        assert_eq!(inline, result);
+
+        // No temporary files are left behind
+        let leftovers = fs::read_dir(handler.local_path.join("tmp"))?.count();
+        assert_eq!(leftovers, 0);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_collect_garbage(pool: PgPool) -> TestResult<()> {
+        let mut handler = setup_handler_postgres(pool).await;
+
+        let kept = handler.put_reader(&b"still referenced"[..]).await?.hash;
+        handler
+            .set_local_needed(&kept)
+            .await
+            .expect("Failed to reference data");
+        let unreferenced = handler.put_reader(&b"unreferenced"[..]).await?.hash;
+
+        // A file with no data table entry, which may belong to another database
+        let unknown = handler.hash_to_path("b3_0123456789")?;
+        fs::create_dir_all(unknown.parent().unwrap())?;
+        fs::write(&unknown, b"unknown")?;
+
+        let stats = handler
+            .collect_garbage(Duration::zero(), true)
+            .await
+            .expect("Failed to collect garbage");
+        assert_eq!(
+            stats,
+            GcStats {
+                entries: 1,
+                files: 1,
+                bytes: 12
+            }
+        );
+        assert!(handler.hash_to_path(&unreferenced)?.exists());
+
+        // Recently accessed data is protected
+        let stats = handler
+            .collect_garbage(Duration::hours(1), true)
+            .await
+            .expect("Failed to collect garbage");
+        assert_eq!(stats, GcStats::default());
+
+        handler
+            .collect_garbage(Duration::zero(), false)
+            .await
+            .expect("Failed to collect garbage");
+        assert!(unknown.exists());
+        assert!(!handler.hash_to_path(&unreferenced)?.exists());
+        assert!(handler
+            .data_table
+            .get_entry(&unreferenced)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(handler.hash_to_path(&kept)?.exists());
+        assert!(handler.data_table.get_entry(&kept).await.unwrap().is_some());
+
+        // Nothing is left to collect
+        let stats = handler
+            .collect_garbage(Duration::zero(), false)
+            .await
+            .expect("Failed to collect garbage");
+        assert_eq!(stats, GcStats::default());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_legacy_path(pool: PgPool) -> TestResult<()> {
+        let handler = setup_handler_postgres(pool).await;
+        let base_path = handler.local_path.clone();
+        let mut handler = DataTableHandler::new(handler.data_table, base_path.join("database"))
+            .with_legacy_path(base_path.clone());
+
+        // Data stored before each database had its own directory
+        let hash = handler.put_reader(&b"old data"[..]).await?.hash;
+        let legacy = hash_to_path_in(&base_path, &hash)?;
+        fs::create_dir_all(legacy.parent().unwrap())?;
+        fs::rename(handler.hash_to_path(&hash)?, &legacy)?;
+
+        let mut data = Vec::new();
+        handler
+            .get_writer(&hash, &mut data)
+            .await
+            .expect("Failed to read data");
+        assert_eq!(data, b"old data");
+
+        // Collecting it leaves the file, another database may still use it
+        let stats = handler
+            .collect_garbage(Duration::zero(), false)
+            .await
+            .expect("Failed to collect garbage");
+        assert_eq!(
+            stats,
+            GcStats {
+                entries: 1,
+                files: 0,
+                bytes: 0
+            }
+        );
+        assert!(legacy.exists());
+        Ok(())
+    }
 }
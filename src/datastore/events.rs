@@ -0,0 +1,91 @@
+//! Change Notifications
+//!
+//! A DataStore broadcasts a [`ChangeEvent`] for every entry it commits, see
+//! [`DataStore::subscribe`](crate::datastore::store::DataStore::subscribe). To follow a
+//! single entry use [`EntryWatch`] instead.
+
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokio::sync::broadcast::{self, error::RecvError};
+use uuid::Uuid;
+
+/// Number of events buffered for each subscriber before the oldest are dropped
+///
+/// A subscriber that falls behind receives `RecvError::Lagged` and should resync from
+/// the metadata table, e.g. with `iter_entries_since`.
+pub const CHANGE_CHANNEL_CAPACITY: usize = 1024;
+
+/// What happened to produce a ChangeEvent
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// A new entry was created, possibly replacing its parent
+    Created,
+    /// The parent entry was archived
+    Archived,
+}
+
+/// A new entry was committed to a store
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+    /// Name of the store the entry was committed to
+    pub store: String,
+    /// Id of the new entry
+    pub id: Uuid,
+    /// The entry this one replaces or archives, if any
+    pub parent_id: Option<Uuid>,
+    /// Metadata of the new entry
+    pub metadata: Value,
+    pub kind: ChangeKind,
+}
+
+/// Waits for changes to a single entry
+///
+/// Created with [`DataStore::watch`](crate::datastore::store::DataStore::watch).
+pub struct EntryWatch {
+    changes: broadcast::Receiver<ChangeEvent>,
+    /// Latest entry seen in the watched history
+    current: Uuid,
+}
+
+#[allow(dead_code)]
+impl EntryWatch {
+    pub(crate) fn new(changes: broadcast::Receiver<ChangeEvent>, id: Uuid) -> Self {
+        Self {
+            changes,
+            current: id,
+        }
+    }
+
+    /// Id of the latest entry seen in the watched history
+    pub fn current(&self) -> Uuid {
+        self.current
+    }
+
+    /// Wait for the next entry that replaces or archives the watched entry
+    ///
+    /// Fails if the store is dropped, or if this watch fell too far behind and missed
+    /// events, in which case the history should be re-read from the store.
+    pub async fn changed(&mut self) -> Result<ChangeEvent> {
+        loop {
+            let event = match self.changes.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    return Err(anyhow!("Watch missed {} changes", missed))
+                }
+                Err(RecvError::Closed) => return Err(anyhow!("DataStore was dropped")),
+            };
+            if event.parent_id == Some(self.current) {
+                self.current = event.id;
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Wait for the next change and deserialize the new metadata
+    pub async fn changed_value<T: DeserializeOwned>(&mut self) -> Result<T> {
+        let event = self.changed().await?;
+        Ok(serde_json::from_value(event.metadata)?)
+    }
+}
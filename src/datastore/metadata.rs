@@ -1,13 +1,35 @@
+//! Eidetica Database
+//!
+//! This is the interface and implementation of the Metadata Table in the database.
+//! We run on postgresql or sqlite, and store the blob data as described in the design doc.
+
+use crate::datastore::path::MetadataPath;
+use crate::datastore::query::{
+    first_id_at, validate_locale, Direction, MetadataQuery, Predicate, QueryParam,
+};
 use crate::datastore::schema::MetadataEntry;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use serde_json::Value;
-use sqlx::{Error, PgPool, Row};
+use sqlx::{Error, PgPool, Row, SqlitePool};
 use uuid::Uuid;
 
-/// Eidetica Database
+/// The parent of a new entry was replaced or archived after it was read
 ///
-/// This is the interface and implementation of the Metadata Table in the database.
-/// We run on postgresql, and store the blob data as described in the design doc.
+/// Returned by [`MetadataTable::create_entry_if_current`]. Callers can find it with
+/// `error.downcast_ref::<EntryReplaced>()`, re-read the latest entry, and retry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryReplaced {
+    /// The entry that is no longer current
+    pub id: Uuid,
+}
+
+impl std::fmt::Display for EntryReplaced {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Entry {} has already been replaced", self.id)
+    }
+}
+
+impl std::error::Error for EntryReplaced {}
 
 /// Interface for interacting with the metadata table
 #[allow(dead_code, async_fn_in_trait)]
@@ -21,6 +43,34 @@ pub trait MetadataTable {
     /// Create a new metadata entry
     async fn create_entry(&mut self, entry: MetadataEntry) -> Result<()>;
 
+    /// Create many metadata entries in a single transaction
+    ///
+    /// Either every entry is created or none are. Entries may name earlier entries in the
+    /// same batch as their parent.
+    async fn create_entries(&mut self, entries: Vec<MetadataEntry>) -> Result<()>;
+
+    /// Create a new entry only if its parent is still active
+    ///
+    /// The parent is archived in the same transaction, so of several concurrent updates to
+    /// one entry only the first succeeds. The others fail with [`EntryReplaced`] and nothing
+    /// is written. Entries without a parent are always created.
+    async fn create_entry_if_current(&mut self, entry: MetadataEntry) -> Result<()>;
+
+    /// Create many entries in a single transaction, only if all their parents are still active
+    ///
+    /// Like [`Self::create_entry_if_current`] for each entry. If any parent was already
+    /// replaced or archived, including by an earlier entry in the batch, this fails with
+    /// [`EntryReplaced`] and no entry is created.
+    async fn create_entries_if_current(&mut self, entries: Vec<MetadataEntry>) -> Result<()>;
+
+    /// Create a new entry only if its parent doesn't have any children yet
+    ///
+    /// Like [`Self::create_entry_if_current`], for parents that are archived without being
+    /// replaced, like archive entries. The check and the insert happen in one transaction, so
+    /// of several concurrent children only the first succeeds. The others fail with
+    /// [`EntryReplaced`] and nothing is written. Entries without a parent are always created.
+    async fn create_entry_if_leaf(&mut self, entry: MetadataEntry) -> Result<()>;
+
     /// Retrieve an entry by its ID
     async fn get_entry(&self, id: Uuid) -> Result<Option<MetadataEntry>>;
 
@@ -44,15 +94,49 @@ pub trait MetadataTable {
     /// Get all the archived entries
     async fn get_archived_entries(&self) -> Result<Vec<MetadataEntry>>;
 
+    /// Get every entry that shares its parent with another entry, ordered by parent then id
+    ///
+    /// These are concurrent updates of the same entry, e.g. from merging two copies.
+    async fn get_forked_entries(&self) -> Result<Vec<MetadataEntry>>;
+
+    /// Get up to `limit` entries with an id after `after`, oldest first
+    ///
+    /// Ids are UUIDv7 and sort by creation time, so passing the last id of one page
+    /// continues from there. Use this to page through large tables.
+    async fn get_entries_after(
+        &self,
+        after: Option<Uuid>,
+        limit: i64,
+        include_archived: bool,
+    ) -> Result<Vec<MetadataEntry>>;
+
     /// Get entries by 1 or more metadata conditions
+    ///
+    /// Condition keys are [`MetadataPath`]s, so nested values can be matched with
+    /// `file.name`, `items[3]`, or `tags[*]`. A condition holds if any selected value matches.
     async fn get_entries_by_metadata_conditions(
         &self,
         conditions: &Value,
         include_archived: bool,
     ) -> Result<Vec<MetadataEntry>>;
+
+    /// Get the entries matching a query, with its ordering and pagination applied
+    async fn query(&self, query: &MetadataQuery) -> Result<Vec<MetadataEntry>>;
 }
 // ... existing code ...
 
+/// SQL condition matching the entries of `table` that were active when `pin` was created
+///
+/// An entry was active then if none of its children are that old, and it's either still
+/// active or was archived by a newer child. Entries archived without a child, like archive
+/// markers, never count as active.
+fn active_at(table: &str, pin: &str) -> String {
+    format!(
+        "(archived = FALSE OR EXISTS (SELECT 1 FROM {table} AS child WHERE child.parent_id = {table}.id AND child.id > {pin})) \
+         AND NOT EXISTS (SELECT 1 FROM {table} AS child WHERE child.parent_id = {table}.id AND child.id <= {pin})"
+    )
+}
+
 impl From<sqlx::postgres::PgRow> for MetadataEntry {
     fn from(row: sqlx::postgres::PgRow) -> Self {
         Self {
@@ -62,7 +146,8 @@ impl From<sqlx::postgres::PgRow> for MetadataEntry {
             local: row.get("local"),
             parent_id: row.get("parent_id"),
             metadata: row.get("metadata"),
-            data_hash: row.get("data_hash"),
+            // CHAR pads shorter values, like the empty hash of archive entries
+            data_hash: row.get::<String, _>("data_hash").trim_end().to_string(),
         }
     }
 }
@@ -96,28 +181,20 @@ impl PostgresMetadataTable {
         table.create_table().await?;
         Ok(table)
     }
-}
-
-impl MetadataTable for PostgresMetadataTable {
-    fn table_name(&self) -> &str {
-        &self.table_name
-    }
 
-    async fn create_entry(&mut self, entry: MetadataEntry) -> Result<()> {
-        // Convert the metadata to a sqlx::types::Json
-        let metadata_json = serde_json::to_value(&entry.metadata)?;
-
-        // Start a transaction since we might need to update two rows
-        let mut tx = self.pool.begin().await?;
-
-        // Insert the new entry using the table name from the struct
+    /// Insert a single entry as part of a transaction
+    async fn insert_entry(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        entry: MetadataEntry,
+    ) -> Result<()> {
         let query = format!(
             r#"
             INSERT INTO {}
-            (id, device_id, archived, local, parent_id, metadata, data_hash)
-        VALUES
-            ($1, $2, $3, $4, $5, $6, $7)
-        "#,
+                (id, device_id, archived, local, parent_id, metadata, data_hash)
+            VALUES
+                ($1, $2, $3, $4, $5, $6, $7)
+            "#,
             self.table_name
         );
 
@@ -127,26 +204,80 @@ impl MetadataTable for PostgresMetadataTable {
             .bind(entry.archived)
             .bind(entry.local)
             .bind(entry.parent_id)
-            .bind(metadata_json)
+            .bind(serde_json::to_value(&entry.metadata)?)
             .bind(entry.data_hash)
-            .execute(&mut *tx)
+            .execute(&mut **tx)
             .await?;
 
         // Verify one row was inserted
         if result.rows_affected() != 1 {
             return Err(Error::RowNotFound.into());
         }
+        Ok(())
+    }
 
-        // If there's a parent_id, archive it
-        if let Some(parent_id) = entry.parent_id {
-            let update_query = format!(
-                "UPDATE {} SET archived = TRUE WHERE id = $1",
-                self.table_name
-            );
-            sqlx::query(&update_query)
-                .bind(parent_id)
-                .execute(&mut *tx)
-                .await?;
+    /// Archive the parent of a new entry as part of a transaction
+    ///
+    /// Returns false if the parent was already archived.
+    async fn archive_parent(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        parent_id: Uuid,
+    ) -> Result<bool> {
+        let query = format!(
+            "UPDATE {} SET archived = TRUE WHERE id = $1 AND archived = FALSE",
+            self.table_name
+        );
+        let result = sqlx::query(&query)
+            .bind(parent_id)
+            .execute(&mut **tx)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Lock an entry for the rest of a transaction and check whether it has children
+    async fn lock_has_children(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        id: Uuid,
+    ) -> Result<bool> {
+        let query = format!(
+            "SELECT id FROM {} WHERE id = $1 FOR UPDATE",
+            self.table_name
+        );
+        sqlx::query(&query).bind(id).execute(&mut **tx).await?;
+
+        let query = format!(
+            "SELECT EXISTS (SELECT 1 FROM {} WHERE parent_id = $1)",
+            self.table_name
+        );
+        Ok(sqlx::query(&query)
+            .bind(id)
+            .fetch_one(&mut **tx)
+            .await?
+            .get(0))
+    }
+}
+
+impl MetadataTable for PostgresMetadataTable {
+    fn table_name(&self) -> &str {
+        &self.table_name
+    }
+
+    async fn create_entry(&mut self, entry: MetadataEntry) -> Result<()> {
+        self.create_entries(vec![entry]).await
+    }
+
+    async fn create_entries(&mut self, entries: Vec<MetadataEntry>) -> Result<()> {
+        // Start a transaction since we might need to update two rows per entry
+        let mut tx = self.pool.begin().await?;
+
+        for entry in entries {
+            // If there's a parent_id, archive it
+            if let Some(parent_id) = entry.parent_id {
+                self.archive_parent(&mut tx, parent_id).await?;
+            }
+            self.insert_entry(&mut tx, entry).await?;
         }
 
         // Commit the transaction
@@ -155,6 +286,45 @@ impl MetadataTable for PostgresMetadataTable {
         Ok(())
     }
 
+    async fn create_entry_if_current(&mut self, entry: MetadataEntry) -> Result<()> {
+        self.create_entries_if_current(vec![entry]).await
+    }
+
+    async fn create_entries_if_current(&mut self, entries: Vec<MetadataEntry>) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        for entry in entries {
+            // Only one writer can archive the parent, the transaction rolls back on drop
+            if let Some(parent_id) = entry.parent_id {
+                if !self.archive_parent(&mut tx, parent_id).await? {
+                    return Err(EntryReplaced { id: parent_id }.into());
+                }
+            }
+            self.insert_entry(&mut tx, entry).await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn create_entry_if_leaf(&mut self, entry: MetadataEntry) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        // Concurrent writers wait on the lock, then see the first writer's child
+        if let Some(parent_id) = entry.parent_id {
+            if self.lock_has_children(&mut tx, parent_id).await? {
+                return Err(EntryReplaced { id: parent_id }.into());
+            }
+            self.archive_parent(&mut tx, parent_id).await?;
+        }
+        self.insert_entry(&mut tx, entry).await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
     /// Create the metadata table if it doesn't exist
     async fn create_table(&mut self) -> Result<()> {
         // This command may fail if we're trying to run this in parallel (as in testing).
@@ -349,6 +519,61 @@ impl MetadataTable for PostgresMetadataTable {
         Ok(entries)
     }
 
+    async fn get_forked_entries(&self) -> Result<Vec<MetadataEntry>> {
+        let query = format!(
+            r#"
+        SELECT
+            id, device_id, archived, local, parent_id, metadata, data_hash
+        FROM {0}
+        WHERE parent_id IN (
+            SELECT parent_id FROM {0}
+            WHERE parent_id IS NOT NULL
+            GROUP BY parent_id
+            HAVING COUNT(*) > 1
+        )
+        ORDER BY parent_id, id
+        "#,
+            self.table_name
+        );
+
+        let rows = sqlx::query(&query).fetch_all(&self.pool).await?;
+
+        let entries = rows.into_iter().map(MetadataEntry::from).collect();
+
+        Ok(entries)
+    }
+
+    async fn get_entries_after(
+        &self,
+        after: Option<Uuid>,
+        limit: i64,
+        include_archived: bool,
+    ) -> Result<Vec<MetadataEntry>> {
+        let query = format!(
+            r#"
+            SELECT
+                id, device_id, archived, local, parent_id, metadata, data_hash
+            FROM {}
+            WHERE ($1::uuid IS NULL OR id > $1::uuid)
+            AND ($2 OR archived = FALSE)
+            ORDER BY id ASC
+            LIMIT $3
+            "#,
+            self.table_name
+        );
+
+        let rows = sqlx::query(&query)
+            .bind(after)
+            .bind(include_archived)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let entries = rows.into_iter().map(MetadataEntry::from).collect();
+
+        Ok(entries)
+    }
+
     /// Query entries by multiple metadata key-value pairs
     async fn get_entries_by_metadata_conditions(
         &self,
@@ -367,35 +592,31 @@ impl MetadataTable for PostgresMetadataTable {
 
         if let Value::Object(map) = conditions {
             for (key, value) in map {
-                match value {
+                // Every condition matches if any value selected by the path matches
+                let path: MetadataPath = key.parse()?;
+                let path_param = bind_values.len() + 1;
+                let value_param = bind_values.len() + 2;
+                bind_values.push(path.to_jsonpath());
+                let comparison = match value {
                     Value::Number(_) => {
                         // Cast the parameter to JSONB for proper comparison
-                        condition_parts.push(format!(
-                            "metadata->'{}' = ${}::jsonb",
-                            key,
-                            bind_values.len() + 1
-                        ));
                         bind_values.push(value.to_string());
+                        format!("value = ${}::jsonb", value_param)
                     }
                     Value::Object(obj) if obj.contains_key("$regex") => {
                         // Handle regex pattern matching
-                        condition_parts.push(format!(
-                            "metadata->>'{}' ~ ${}",
-                            key,
-                            bind_values.len() + 1
-                        ));
                         bind_values.push(obj["$regex"].as_str().unwrap_or_default().to_string());
+                        format!("value #>> '{{}}' ~ ${}", value_param)
                     }
                     _ => {
-                        // String comparisons remain the same
-                        condition_parts.push(format!(
-                            "metadata->>'{}' = ${}",
-                            key,
-                            bind_values.len() + 1
-                        ));
                         bind_values.push(value.as_str().unwrap_or_default().to_string());
+                        format!("value #>> '{{}}' = ${}", value_param)
                     }
-                }
+                };
+                condition_parts.push(format!(
+                    "EXISTS (SELECT 1 FROM jsonb_path_query(metadata, ${}::jsonpath) AS matched(value) WHERE {})",
+                    path_param, comparison
+                ));
             }
         }
 
@@ -426,39 +647,1018 @@ impl MetadataTable for PostgresMetadataTable {
 
         Ok(entries)
     }
+
+    /// Run a query built with MetadataQuery
+    ///
+    /// Missing sort values are treated as JSON null, which sorts before every other value.
+    async fn query(&self, query: &MetadataQuery) -> Result<Vec<MetadataEntry>> {
+        let mut clauses = Vec::new();
+        let mut params = Vec::new();
+
+        if let Some(pin) = query.pinned_at {
+            params.push(QueryParam::Id(pin));
+            clauses.push(format!("id <= ${}", params.len()));
+            if !query.include_archived {
+                clauses.push(active_at(&self.table_name, &format!("${}", params.len())));
+            }
+        } else if !query.include_archived {
+            clauses.push("archived = FALSE".to_string());
+        }
+        if let Some(device_id) = query.device_id {
+            params.push(QueryParam::Bytes(device_id.to_vec()));
+            clauses.push(format!("device_id = ${}", params.len()));
+        }
+        if let Some(time) = query.created_since {
+            params.push(QueryParam::Id(first_id_at(time)));
+            clauses.push(format!("id >= ${}", params.len()));
+        }
+        if let Some(time) = query.created_before {
+            params.push(QueryParam::Id(first_id_at(time)));
+            clauses.push(format!("id < ${}", params.len()));
+        }
+
+        for (path, predicate) in &query.filters {
+            params.push(QueryParam::Text(path.to_jsonpath()));
+            let path_param = params.len();
+            let comparison = match predicate.comparison() {
+                Some((operator, value)) => {
+                    params.push(QueryParam::Text(value.to_string()));
+                    format!("value {} ${}::jsonb", operator, params.len())
+                }
+                None => {
+                    let Predicate::Regex(pattern) = predicate else {
+                        unreachable!()
+                    };
+                    params.push(QueryParam::Text(pattern.clone()));
+                    format!("value #>> '{{}}' ~ ${}", params.len())
+                }
+            };
+            clauses.push(format!(
+                "EXISTS (SELECT 1 FROM jsonb_path_query(metadata, ${}::jsonpath) AS matched(value) WHERE {})",
+                path_param, comparison
+            ));
+        }
+
+        let sort = match &query.order_by {
+            Some(path) => {
+                params.push(QueryParam::TextArray(path.to_text_array()?));
+                Some(format!(
+                    "COALESCE(metadata #> ${}::text[], 'null'::jsonb)",
+                    params.len()
+                ))
+            }
+            None => None,
+        };
+        if let Some(locale) = &query.collation {
+            validate_locale(locale)?;
+        }
+
+        let operator = query.direction.after_operator();
+        if let Some(cursor) = &query.after {
+            params.push(QueryParam::Id(cursor.id));
+            let id = params.len();
+            match &sort {
+                Some(sort) => {
+                    params.push(QueryParam::Text(cursor.sort_value.to_string()));
+                    let value = format!("${}::jsonb", params.len());
+                    clauses.push(format!(
+                        "({}, id) {} ({}, ${})",
+                        postgres_sort_keys(sort, query.collation.as_deref()).join(", "),
+                        operator,
+                        postgres_sort_keys(&value, query.collation.as_deref()).join(", "),
+                        id
+                    ));
+                }
+                None => clauses.push(format!("id {} ${}", operator, id)),
+            }
+        }
+
+        let direction = query.direction.keyword();
+        let order = match &sort {
+            Some(sort) => {
+                let mut order: Vec<_> = postgres_sort_keys(sort, query.collation.as_deref())
+                    .into_iter()
+                    .map(|key| format!("{key} {direction}"))
+                    .collect();
+                order.push(format!("id {direction}"));
+                order.join(", ")
+            }
+            None => format!("id {direction}"),
+        };
+
+        // A NULL limit is no limit
+        params.push(QueryParam::Int(query.limit.unwrap_or(i64::MAX)));
+        let limit = params.len();
+        params.push(QueryParam::Int(query.offset.unwrap_or(0)));
+        let offset = params.len();
+
+        let sql = format!(
+            r#"
+            SELECT
+                id, device_id, archived, local, parent_id, metadata, data_hash
+            FROM {}
+            WHERE {}
+            ORDER BY {}
+            LIMIT ${} OFFSET ${}
+            "#,
+            self.table_name,
+            if clauses.is_empty() {
+                "TRUE".to_string()
+            } else {
+                clauses.join(" AND ")
+            },
+            order,
+            limit,
+            offset
+        );
+
+        let rows = bind_postgres_params(sqlx::query(&sql), params)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let entries = rows.into_iter().map(MetadataEntry::from).collect();
+
+        Ok(entries)
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::datastore::schema::DeviceId;
-    use crate::utils::generate_hash;
-    use crate::utils::generate_key;
+impl From<sqlx::sqlite::SqliteRow> for MetadataEntry {
+    fn from(row: sqlx::sqlite::SqliteRow) -> Self {
+        let device_id: Vec<u8> = row.get("device_id");
+        let metadata: sqlx::types::Json<Value> = row.get("metadata");
+        Self {
+            id: row.get("id"),
+            device_id: device_id
+                .try_into()
+                .expect("Stored device id has the wrong length"),
+            archived: row.get("archived"),
+            local: row.get("local"),
+            parent_id: row.get("parent_id"),
+            metadata: metadata.0,
+            data_hash: row.get("data_hash"),
+        }
+    }
+}
+
+/// SQLite implementation of the metadata table
+///
+/// The metadata is stored as JSON text and queried with SQLite's JSON1 functions.
+pub struct SqliteMetadataTable {
+    pub table_name: String,
+    pub pool: SqlitePool,
+}
+
+#[allow(dead_code)]
+impl SqliteMetadataTable {
+    /// Create a new SqliteMetadataTable instance
+    pub async fn new(connection_string: &str, table_name: &str) -> Result<Self> {
+        let pool = SqlitePool::connect(connection_string).await?;
+
+        let mut table = Self {
+            table_name: table_name.to_string(),
+            pool,
+        };
+        table.create_table().await?;
+        Ok(table)
+    }
+
+    /// Create a new SqliteMetadataTable from an existing pool connection
+    pub async fn from_pool(pool: SqlitePool, table_name: &str) -> Result<Self> {
+        let mut table = Self {
+            table_name: table_name.to_string(),
+            pool,
+        };
+        table.create_table().await?;
+        Ok(table)
+    }
+
+    /// Insert a single entry as part of a transaction
+    async fn insert_entry(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        entry: MetadataEntry,
+    ) -> Result<()> {
+        let query = format!(
+            r#"
+            INSERT INTO {}
+                (id, device_id, archived, local, parent_id, metadata, data_hash)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            "#,
+            self.table_name
+        );
+
+        let result = sqlx::query(&query)
+            .bind(entry.id)
+            .bind(entry.device_id.to_vec())
+            .bind(entry.archived)
+            .bind(entry.local)
+            .bind(entry.parent_id)
+            .bind(sqlx::types::Json(&entry.metadata))
+            .bind(entry.data_hash)
+            .execute(&mut **tx)
+            .await?;
+
+        // Verify one row was inserted
+        if result.rows_affected() != 1 {
+            return Err(Error::RowNotFound.into());
+        }
+        Ok(())
+    }
+
+    /// Archive the parent of a new entry as part of a transaction
+    ///
+    /// Returns false if the parent was already archived.
+    async fn archive_parent(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        parent_id: Uuid,
+    ) -> Result<bool> {
+        let query = format!(
+            "UPDATE {} SET archived = TRUE WHERE id = ?1 AND archived = FALSE",
+            self.table_name
+        );
+        let result = sqlx::query(&query)
+            .bind(parent_id)
+            .execute(&mut **tx)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Lock the database for the rest of a transaction and check whether an entry has children
+    async fn lock_has_children(
+        &self,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        id: Uuid,
+    ) -> Result<bool> {
+        // SQLite has no row locks, writing takes the database write lock
+        let query = format!(
+            "UPDATE {} SET archived = archived WHERE id = ?1",
+            self.table_name
+        );
+        sqlx::query(&query).bind(id).execute(&mut **tx).await?;
+
+        let query = format!(
+            "SELECT EXISTS (SELECT 1 FROM {} WHERE parent_id = ?1)",
+            self.table_name
+        );
+        Ok(sqlx::query(&query)
+            .bind(id)
+            .fetch_one(&mut **tx)
+            .await?
+            .get(0))
+    }
+}
+
+impl MetadataTable for SqliteMetadataTable {
+    fn table_name(&self) -> &str {
+        &self.table_name
+    }
+
+    async fn create_entry(&mut self, entry: MetadataEntry) -> Result<()> {
+        self.create_entries(vec![entry]).await
+    }
+
+    async fn create_entries(&mut self, entries: Vec<MetadataEntry>) -> Result<()> {
+        // Start a transaction since we might need to update two rows per entry
+        let mut tx = self.pool.begin().await?;
+
+        for entry in entries {
+            // If there's a parent_id, archive it
+            if let Some(parent_id) = entry.parent_id {
+                self.archive_parent(&mut tx, parent_id).await?;
+            }
+            self.insert_entry(&mut tx, entry).await?;
+        }
+
+        // Commit the transaction
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn create_entry_if_current(&mut self, entry: MetadataEntry) -> Result<()> {
+        self.create_entries_if_current(vec![entry]).await
+    }
+
+    async fn create_entries_if_current(&mut self, entries: Vec<MetadataEntry>) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        for entry in entries {
+            // Only one writer can archive the parent, the transaction rolls back on drop
+            if let Some(parent_id) = entry.parent_id {
+                if !self.archive_parent(&mut tx, parent_id).await? {
+                    return Err(EntryReplaced { id: parent_id }.into());
+                }
+            }
+            self.insert_entry(&mut tx, entry).await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn create_entry_if_leaf(&mut self, entry: MetadataEntry) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        // Concurrent writers wait on the lock, then see the first writer's child
+        if let Some(parent_id) = entry.parent_id {
+            if self.lock_has_children(&mut tx, parent_id).await? {
+                return Err(EntryReplaced { id: parent_id }.into());
+            }
+            self.archive_parent(&mut tx, parent_id).await?;
+        }
+        self.insert_entry(&mut tx, entry).await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Create the metadata table if it doesn't exist
+    async fn create_table(&mut self) -> Result<()> {
+        let query = format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS {} (
+                id BLOB PRIMARY KEY,
+                device_id BLOB NOT NULL,
+                archived BOOLEAN NOT NULL DEFAULT FALSE,
+                local BOOLEAN NOT NULL DEFAULT FALSE,
+                parent_id BLOB,
+                metadata TEXT NOT NULL,
+                data_hash TEXT,
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+
+                FOREIGN KEY (parent_id) REFERENCES {}(id)
+            );"#,
+            self.table_name, self.table_name
+        );
+
+        sqlx::query(&query).execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn get_entry(&self, id: Uuid) -> Result<Option<MetadataEntry>> {
+        let query = format!(
+            r#"
+            SELECT
+                id,
+                device_id,
+                archived,
+                local,
+                parent_id,
+                metadata,
+                data_hash
+            FROM {}
+            WHERE id = ?1
+            "#,
+            self.table_name
+        );
+
+        let row = sqlx::query(&query)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(MetadataEntry::from))
+    }
+
+    async fn archive_entry(&mut self, id: Uuid) -> Result<()> {
+        let query = format!(
+            "UPDATE {} SET archived = TRUE WHERE id = ?1",
+            self.table_name
+        );
+
+        let result = sqlx::query(&query).bind(id).execute(&self.pool).await?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::RowNotFound.into());
+        }
+
+        Ok(())
+    }
+
+    async fn set_local(&mut self, id: Uuid, local: bool) -> Result<()> {
+        let query = format!("UPDATE {} SET local = ?1 WHERE id = ?2", self.table_name);
+
+        let result = sqlx::query(&query)
+            .bind(local)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::RowNotFound.into());
+        }
+
+        Ok(())
+    }
+
+    async fn get_entry_history(&self, id: Uuid) -> Result<Vec<MetadataEntry>> {
+        // Using a WITH RECURSIVE query to follow the parent_id chain
+        let query = format!(
+            r#"
+            WITH RECURSIVE history AS (
+                -- Base case: start with the entry we want
+                SELECT
+                    id, device_id, archived, local, parent_id, metadata, data_hash
+                FROM {}
+                WHERE id = ?1
+
+                UNION ALL
+
+                -- Recursive case: join with parent entries
+                SELECT
+                    e.id, e.device_id, e.archived, e.local, e.parent_id, e.metadata, e.data_hash
+                FROM {} e
+                INNER JOIN history h ON h.parent_id = e.id
+            )
+            SELECT * FROM history
+            ORDER BY id DESC
+            "#,
+            self.table_name, self.table_name
+        );
+
+        let rows = sqlx::query(&query).bind(id).fetch_all(&self.pool).await?;
+
+        if rows.is_empty() {
+            return Err(Error::RowNotFound.into());
+        }
+
+        let entries = rows.into_iter().map(MetadataEntry::from).collect();
+
+        Ok(entries)
+    }
+
+    async fn get_child_entries(&self, id: Uuid) -> Result<Vec<MetadataEntry>> {
+        let query = format!(
+            r#"
+            SELECT
+                id, device_id, archived, local, parent_id, metadata, data_hash
+            FROM {}
+            WHERE parent_id = ?1
+            ORDER BY id DESC
+            "#,
+            self.table_name
+        );
+
+        let rows = sqlx::query(&query).bind(id).fetch_all(&self.pool).await?;
+
+        let entries = rows.into_iter().map(MetadataEntry::from).collect();
+
+        Ok(entries)
+    }
+
+    async fn get_active_entries(&self) -> Result<Vec<MetadataEntry>> {
+        let query = format!(
+            r#"
+            SELECT
+                id, device_id, archived, local, parent_id, metadata, data_hash
+            FROM {}
+            WHERE archived = FALSE
+            ORDER BY id DESC
+            "#,
+            self.table_name
+        );
+
+        let rows = sqlx::query(&query).fetch_all(&self.pool).await?;
+
+        let entries = rows.into_iter().map(MetadataEntry::from).collect();
+
+        Ok(entries)
+    }
+
+    async fn get_archived_entries(&self) -> Result<Vec<MetadataEntry>> {
+        let query = format!(
+            r#"
+            SELECT
+                id, device_id, archived, local, parent_id, metadata, data_hash
+            FROM {}
+            WHERE archived = TRUE
+            ORDER BY id DESC
+            "#,
+            self.table_name
+        );
+
+        let rows = sqlx::query(&query).fetch_all(&self.pool).await?;
+
+        let entries = rows.into_iter().map(MetadataEntry::from).collect();
+
+        Ok(entries)
+    }
+
+    async fn get_forked_entries(&self) -> Result<Vec<MetadataEntry>> {
+        let query = format!(
+            r#"
+            SELECT
+                id, device_id, archived, local, parent_id, metadata, data_hash
+            FROM {0}
+            WHERE parent_id IN (
+                SELECT parent_id FROM {0}
+                WHERE parent_id IS NOT NULL
+                GROUP BY parent_id
+                HAVING COUNT(*) > 1
+            )
+            ORDER BY parent_id, id
+            "#,
+            self.table_name
+        );
+
+        let rows = sqlx::query(&query).fetch_all(&self.pool).await?;
+
+        let entries = rows.into_iter().map(MetadataEntry::from).collect();
+
+        Ok(entries)
+    }
+
+    async fn get_entries_after(
+        &self,
+        after: Option<Uuid>,
+        limit: i64,
+        include_archived: bool,
+    ) -> Result<Vec<MetadataEntry>> {
+        let query = format!(
+            r#"
+            SELECT
+                id, device_id, archived, local, parent_id, metadata, data_hash
+            FROM {}
+            WHERE (?1 IS NULL OR id > ?1)
+            AND (?2 OR archived = FALSE)
+            ORDER BY id ASC
+            LIMIT ?3
+            "#,
+            self.table_name
+        );
+
+        let rows = sqlx::query(&query)
+            .bind(after)
+            .bind(include_archived)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let entries = rows.into_iter().map(MetadataEntry::from).collect();
+
+        Ok(entries)
+    }
+
+    /// Query entries by multiple metadata key-value pairs
+    ///
+    /// Regex conditions need a pool whose connections were opened with `with_regexp()`.
+    async fn get_entries_by_metadata_conditions(
+        &self,
+        conditions: &Value,
+        include_archived: bool,
+    ) -> Result<Vec<MetadataEntry>> {
+        let archived_clause = if !include_archived {
+            "AND archived = FALSE"
+        } else {
+            ""
+        };
+
+        // Build conditions for each key-value pair in the JSON object
+        let mut condition_parts = Vec::new();
+        let mut params = Vec::new();
+
+        if let Value::Object(map) = conditions {
+            for (key, value) in map {
+                let path: MetadataPath = key.parse()?;
+                condition_parts.push(sqlite_path_condition(&path, &mut params, |params| {
+                    match value {
+                        Value::Number(_) => {
+                            // Parse the parameter as JSON so numbers compare numerically
+                            params.push(QueryParam::Text(value.to_string()));
+                            format!("= json_extract(?{}, '$')", params.len())
+                        }
+                        Value::Object(obj) if obj.contains_key("$regex") => {
+                            let pattern = obj["$regex"].as_str().unwrap_or_default();
+                            params.push(QueryParam::Text(pattern.to_string()));
+                            format!("REGEXP ?{}", params.len())
+                        }
+                        _ => {
+                            let value = value.as_str().unwrap_or_default();
+                            params.push(QueryParam::Text(value.to_string()));
+                            format!("= ?{}", params.len())
+                        }
+                    }
+                })?);
+            }
+        }
+
+        let query = format!(
+            r#"
+            SELECT
+                id, device_id, archived, local, parent_id, metadata, data_hash
+            FROM {}
+            WHERE {}
+            {}
+            ORDER BY id DESC
+            "#,
+            self.table_name,
+            condition_parts.join(" AND "),
+            archived_clause
+        );
+
+        let rows = bind_sqlite_params(sqlx::query(&query), params)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let entries = rows.into_iter().map(MetadataEntry::from).collect();
+
+        Ok(entries)
+    }
+
+    /// Run a query built with MetadataQuery
+    ///
+    /// Missing and null sort values both sort first, as SQL NULL.
+    async fn query(&self, query: &MetadataQuery) -> Result<Vec<MetadataEntry>> {
+        let mut clauses = Vec::new();
+        let mut params = Vec::new();
+
+        if let Some(pin) = query.pinned_at {
+            params.push(QueryParam::Id(pin));
+            clauses.push(format!("id <= ?{}", params.len()));
+            if !query.include_archived {
+                clauses.push(active_at(&self.table_name, &format!("?{}", params.len())));
+            }
+        } else if !query.include_archived {
+            clauses.push("archived = FALSE".to_string());
+        }
+        if let Some(device_id) = query.device_id {
+            params.push(QueryParam::Bytes(device_id.to_vec()));
+            clauses.push(format!("device_id = ?{}", params.len()));
+        }
+        if let Some(time) = query.created_since {
+            params.push(QueryParam::Id(first_id_at(time)));
+            clauses.push(format!("id >= ?{}", params.len()));
+        }
+        if let Some(time) = query.created_before {
+            params.push(QueryParam::Id(first_id_at(time)));
+            clauses.push(format!("id < ?{}", params.len()));
+        }
+
+        for (path, predicate) in &query.filters {
+            clauses.push(sqlite_path_condition(
+                path,
+                &mut params,
+                |params| match predicate.comparison() {
+                    Some((operator, value)) => {
+                        params.push(QueryParam::Text(value.to_string()));
+                        format!("{} json_extract(?{}, '$')", operator, params.len())
+                    }
+                    None => {
+                        let Predicate::Regex(pattern) = predicate else {
+                            unreachable!()
+                        };
+                        params.push(QueryParam::Text(pattern.clone()));
+                        format!("REGEXP ?{}", params.len())
+                    }
+                },
+            )?);
+        }
+
+        // Collations would need to be registered on every connection, and a different
+        // implementation than Postgres could order pages differently, so refuse instead
+        if query.collation.is_some() && query.order_by.is_some() {
+            bail!("The SQLite backend doesn't support collations");
+        }
+
+        // The sort key can be bound once and referenced repeatedly by number
+        let sort = match &query.order_by {
+            Some(path) => {
+                let mut paths = path.to_sqlite_paths()?;
+                if paths.len() != 1 {
+                    bail!("Can't order by the wildcard path '{}'", path);
+                }
+                params.push(QueryParam::Text(paths.remove(0)));
+                Some(format!("json_extract(metadata, ?{})", params.len()))
+            }
+            None => None,
+        };
+
+        let operator = query.direction.after_operator();
+        if let Some(cursor) = &query.after {
+            params.push(QueryParam::Id(cursor.id));
+            let id = params.len();
+            match &sort {
+                Some(sort) => {
+                    params.push(QueryParam::Text(cursor.sort_value.to_string()));
+                    let value = format!("json_extract(?{}, '$')", params.len());
+                    // NULL sorts first, so it's only after the cursor in one direction
+                    let null_after = match query.direction {
+                        Direction::Ascending => format!("({value} IS NULL AND {sort} IS NOT NULL)"),
+                        Direction::Descending => {
+                            format!("({sort} IS NULL AND {value} IS NOT NULL)")
+                        }
+                    };
+                    clauses.push(format!(
+                        "({sort} {operator} {value} OR ({sort} IS {value} AND id {operator} ?{id}) OR {null_after})"
+                    ));
+                }
+                None => clauses.push(format!("id {} ?{}", operator, id)),
+            }
+        }
+
+        let direction = query.direction.keyword();
+        let order = match &sort {
+            Some(sort) => format!("{sort} {direction}, id {direction}"),
+            None => format!("id {direction}"),
+        };
+
+        // SQLite only accepts OFFSET after a LIMIT, -1 is no limit
+        params.push(QueryParam::Int(query.limit.unwrap_or(-1)));
+        let limit = params.len();
+        params.push(QueryParam::Int(query.offset.unwrap_or(0)));
+        let offset = params.len();
+
+        let sql = format!(
+            r#"
+            SELECT
+                id, device_id, archived, local, parent_id, metadata, data_hash
+            FROM {}
+            WHERE {}
+            ORDER BY {}
+            LIMIT ?{} OFFSET ?{}
+            "#,
+            self.table_name,
+            if clauses.is_empty() {
+                "TRUE".to_string()
+            } else {
+                clauses.join(" AND ")
+            },
+            order,
+            limit,
+            offset
+        );
+
+        let rows = bind_sqlite_params(sqlx::query(&sql), params)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let entries = rows.into_iter().map(MetadataEntry::from).collect();
+
+        Ok(entries)
+    }
+}
+
+/// Bind generated parameters to a SQLite query, in order
+fn bind_sqlite_params<'q>(
+    mut query: sqlx::query::Query<'q, sqlx::Sqlite, sqlx::sqlite::SqliteArguments<'q>>,
+    params: Vec<QueryParam>,
+) -> sqlx::query::Query<'q, sqlx::Sqlite, sqlx::sqlite::SqliteArguments<'q>> {
+    for param in params {
+        query = match param {
+            QueryParam::Text(value) => query.bind(value),
+            // SQLite has no arrays, so they're passed as JSON
+            QueryParam::TextArray(values) => query.bind(Value::from(values).to_string()),
+            QueryParam::Id(id) => query.bind(id),
+            QueryParam::Int(value) => query.bind(value),
+            QueryParam::Bytes(value) => query.bind(value),
+        };
+    }
+    query
+}
+
+/// Bind generated parameters to a PostgreSQL query, in order
+fn bind_postgres_params(
+    mut query: sqlx::query::Query<'_, sqlx::Postgres, sqlx::postgres::PgArguments>,
+    params: Vec<QueryParam>,
+) -> sqlx::query::Query<'_, sqlx::Postgres, sqlx::postgres::PgArguments> {
+    for param in params {
+        query = match param {
+            QueryParam::Text(value) => query.bind(value),
+            QueryParam::TextArray(values) => query.bind(values),
+            QueryParam::Id(id) => query.bind(id),
+            QueryParam::Int(value) => query.bind(value),
+            QueryParam::Bytes(value) => query.bind(value),
+        };
+    }
+    query
+}
+
+/// Expressions ordering a PostgreSQL jsonb sort value, optionally collating strings
+///
+/// With a collation, every string is first replaced by `""` so types keep their jsonb
+/// order, then strings are compared as text in the ICU collation for the locale.
+fn postgres_sort_keys(value: &str, collation: Option<&str>) -> Vec<String> {
+    match collation {
+        None => vec![value.to_string()],
+        Some(locale) => vec![
+            format!("CASE WHEN jsonb_typeof({value}) = 'string' THEN '\"\"'::jsonb ELSE {value} END"),
+            format!(
+                "(CASE WHEN jsonb_typeof({value}) = 'string' THEN {value} #>> '{{}}' ELSE '' END) COLLATE \"{locale}-x-icu\""
+            ),
+        ],
+    }
+}
+
+/// Build a SQLite condition comparing the values selected by a metadata path
+///
+/// SQLite paths have no wildcards, so every wildcard becomes a `json_each` over the
+/// current match, and the rest of the path is appended to each row's full path.
+/// `compare` pushes its own parameters and returns the comparison applied to each value.
+fn sqlite_path_condition(
+    path: &MetadataPath,
+    params: &mut Vec<QueryParam>,
+    compare: impl FnOnce(&mut Vec<QueryParam>) -> String,
+) -> Result<String> {
+    let mut paths = path.to_sqlite_paths()?;
+    let last = paths.pop().unwrap();
+
+    if paths.is_empty() {
+        params.push(QueryParam::Text(last));
+        let value = format!("json_extract(metadata, ?{})", params.len());
+        return Ok(format!("{} {}", value, compare(params)));
+    }
+
+    let mut sources = Vec::new();
+    let mut filters = Vec::new();
+    for (i, path) in paths.into_iter().enumerate() {
+        params.push(QueryParam::Text(path));
+        let base = match i {
+            0 => format!("?{}", params.len()),
+            _ => format!("w{}.fullkey || ?{}", i - 1, params.len()),
+        };
+        sources.push(format!("json_each(metadata, {}) AS w{}", base, i));
+        // A scalar iterates as itself with no key, it has no members to match
+        filters.push(format!("w{}.key IS NOT NULL", i));
+    }
+    params.push(QueryParam::Text(last));
+    let value = format!(
+        "json_extract(metadata, w{}.fullkey || ?{})",
+        sources.len() - 1,
+        params.len()
+    );
+
+    Ok(format!(
+        "EXISTS (SELECT 1 FROM {} WHERE {} AND {} {})",
+        sources.join(", "),
+        filters.join(" AND "),
+        value,
+        compare(params)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datastore::query::id_created_at;
+    use crate::datastore::schema::DeviceId;
+    use crate::utils::generate_hash;
+    use crate::utils::generate_key;
+
+    fn generate_test_device_id() -> DeviceId {
+        generate_key().verifying_key().to_bytes()
+    }
+
+    #[sqlx::test]
+    async fn test_create_entry(pool: PgPool) {
+        let mut table = PostgresMetadataTable::from_pool(pool, "test_data")
+            .await
+            .unwrap();
+
+        let entry = MetadataEntry {
+            id: Uuid::now_v7(),
+            device_id: generate_test_device_id(),
+            archived: false,
+            local: false,
+            parent_id: None,
+            metadata: serde_json::json!({
+                "type": "test",
+                "name": "test_entry"
+            }),
+            data_hash: generate_hash("entry".as_bytes()).unwrap(),
+        };
+
+        assert!(table.create_entry(entry).await.is_ok());
+    }
+
+    #[sqlx::test]
+    async fn test_create_entries(pool: PgPool) {
+        let mut table = PostgresMetadataTable::from_pool(pool, "test_data")
+            .await
+            .unwrap();
+        let device_id = generate_test_device_id();
+        let entry = |parent_id: Option<Uuid>| MetadataEntry {
+            id: Uuid::now_v7(),
+            device_id,
+            archived: false,
+            local: false,
+            parent_id,
+            metadata: serde_json::json!({}),
+            data_hash: generate_hash("entry".as_bytes()).unwrap(),
+        };
+
+        // A batch can build on itself
+        let first = entry(None);
+        let second = entry(Some(first.id));
+        let (first_id, second_id) = (first.id, second.id);
+        table.create_entries(vec![first, second]).await.unwrap();
+        assert!(table.get_entry(first_id).await.unwrap().unwrap().archived);
+        assert!(!table.get_entry(second_id).await.unwrap().unwrap().archived);
+
+        // A failure part way through creates nothing
+        let new = entry(None);
+        let new_id = new.id;
+        let mut duplicate = entry(None);
+        duplicate.id = second_id;
+        assert!(table.create_entries(vec![new, duplicate]).await.is_err());
+        assert!(table.get_entry(new_id).await.unwrap().is_none());
+    }
+
+    #[sqlx::test]
+    async fn test_create_entry_if_current(pool: PgPool) {
+        let mut table = PostgresMetadataTable::from_pool(pool, "test_data")
+            .await
+            .unwrap();
+        let device_id = generate_test_device_id();
+        let entry = |parent_id: Option<Uuid>| MetadataEntry {
+            id: Uuid::now_v7(),
+            device_id,
+            archived: false,
+            local: false,
+            parent_id,
+            metadata: serde_json::json!({}),
+            data_hash: generate_hash("entry".as_bytes()).unwrap(),
+        };
+
+        let parent = entry(None);
+        let parent_id = parent.id;
+        table.create_entry_if_current(parent).await.unwrap();
+        let first = entry(Some(parent_id));
+        let first_id = first.id;
+        table.create_entry_if_current(first).await.unwrap();
+
+        // A second update to the same parent loses and writes nothing
+        let second = entry(Some(parent_id));
+        let second_id = second.id;
+        let err = table.create_entry_if_current(second).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EntryReplaced>(),
+            Some(&EntryReplaced { id: parent_id })
+        );
+        assert!(table.get_entry(second_id).await.unwrap().is_none());
+        assert!(!table.get_entry(first_id).await.unwrap().unwrap().archived);
+
+        // A batch fails as a whole when any of its parents was replaced
+        let update = entry(Some(first_id));
+        let update_id = update.id;
+        let stale = entry(Some(parent_id));
+        let err = table
+            .create_entries_if_current(vec![update, stale])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EntryReplaced>(),
+            Some(&EntryReplaced { id: parent_id })
+        );
+        assert!(table.get_entry(update_id).await.unwrap().is_none());
+        assert!(!table.get_entry(first_id).await.unwrap().unwrap().archived);
 
-    fn generate_test_device_id() -> DeviceId {
-        generate_key().verifying_key().to_bytes()
+        // As does updating the same entry twice in one batch
+        let twice = vec![entry(Some(first_id)), entry(Some(first_id))];
+        assert!(table.create_entries_if_current(twice).await.is_err());
+        assert!(!table.get_entry(first_id).await.unwrap().unwrap().archived);
     }
 
     #[sqlx::test]
-    async fn test_create_entry(pool: PgPool) {
+    async fn test_create_entry_if_leaf(pool: PgPool) {
         let mut table = PostgresMetadataTable::from_pool(pool, "test_data")
             .await
             .unwrap();
-
-        let entry = MetadataEntry {
+        let device_id = generate_test_device_id();
+        let entry = |parent_id: Option<Uuid>, archived: bool| MetadataEntry {
             id: Uuid::now_v7(),
-            device_id: generate_test_device_id(),
-            archived: false,
+            device_id,
+            archived,
             local: false,
-            parent_id: None,
-            metadata: serde_json::json!({
-                "type": "test",
-                "name": "test_entry"
-            }),
-            data_hash: generate_hash("entry".as_bytes()).unwrap(),
+            parent_id,
+            metadata: serde_json::json!({}),
+            data_hash: String::new(),
         };
 
-        assert!(table.create_entry(entry).await.is_ok());
+        // An archive entry is created archived, but can still be replaced once
+        let parent = entry(None, false);
+        let parent_id = parent.id;
+        table.create_entry(parent).await.unwrap();
+        let archive = entry(Some(parent_id), true);
+        let archive_id = archive.id;
+        table.create_entry(archive).await.unwrap();
+        let first = entry(Some(archive_id), false);
+        let first_id = first.id;
+        table.create_entry_if_leaf(first).await.unwrap();
+
+        let second = entry(Some(archive_id), false);
+        let second_id = second.id;
+        let err = table.create_entry_if_leaf(second).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EntryReplaced>(),
+            Some(&EntryReplaced { id: archive_id })
+        );
+        assert!(table.get_entry(second_id).await.unwrap().is_none());
+        assert!(!table.get_entry(first_id).await.unwrap().unwrap().archived);
     }
 
     #[sqlx::test]
@@ -1018,4 +2218,574 @@ mod tests {
         assert!(table1.create_table().await.is_ok());
         assert!(table2.create_table().await.is_ok());
     }
+
+    #[sqlx::test]
+    async fn test_sqlite_entry_history(pool: SqlitePool) {
+        let mut table = SqliteMetadataTable::from_pool(pool, "test_data")
+            .await
+            .unwrap();
+        let device_id = generate_test_device_id();
+
+        let entry1 = MetadataEntry {
+            id: Uuid::now_v7(),
+            device_id,
+            archived: false,
+            local: false,
+            parent_id: None,
+            metadata: serde_json::json!({"version": 1}),
+            data_hash: generate_hash("entry1".as_bytes()).unwrap(),
+        };
+
+        let entry2 = MetadataEntry {
+            id: Uuid::now_v7(),
+            device_id,
+            archived: false,
+            local: true,
+            parent_id: Some(entry1.id),
+            metadata: serde_json::json!({"version": 2}),
+            data_hash: generate_hash("entry2".as_bytes()).unwrap(),
+        };
+
+        table.create_entry(entry1.clone()).await.unwrap();
+        table.create_entry(entry2.clone()).await.unwrap();
+
+        // The new entry round trips and archives its parent
+        let retrieved = table.get_entry(entry2.id).await.unwrap().unwrap();
+        assert_eq!(retrieved.device_id, entry2.device_id);
+        assert_eq!(retrieved.parent_id, entry2.parent_id);
+        assert_eq!(retrieved.metadata, entry2.metadata);
+        assert!(retrieved.local);
+        assert!(table.get_entry(entry1.id).await.unwrap().unwrap().archived);
+
+        let history = table.get_entry_history(entry2.id).await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].id, entry2.id);
+        assert_eq!(history[1].id, entry1.id);
+
+        let children = table.get_child_entries(entry1.id).await.unwrap();
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].id, entry2.id);
+
+        assert_eq!(table.get_active_entries().await.unwrap().len(), 1);
+        assert_eq!(table.get_archived_entries().await.unwrap().len(), 1);
+        assert!(table.get_entry_history(Uuid::new_v4()).await.is_err());
+        assert!(table.archive_entry(Uuid::new_v4()).await.is_err());
+    }
+
+    #[sqlx::test]
+    async fn test_sqlite_metadata_conditions(
+        pool_options: sqlx::sqlite::SqlitePoolOptions,
+        connect_options: sqlx::sqlite::SqliteConnectOptions,
+    ) {
+        // REGEXP needs to be loaded on every connection
+        let pool = pool_options
+            .connect_with(connect_options.with_regexp())
+            .await
+            .unwrap();
+        let mut table = SqliteMetadataTable::from_pool(pool, "test_data")
+            .await
+            .unwrap();
+        let device_id = generate_test_device_id();
+
+        let entry1 = MetadataEntry {
+            id: Uuid::now_v7(),
+            device_id,
+            archived: false,
+            local: false,
+            parent_id: None,
+            metadata: serde_json::json!({"name": "report.pdf", "size": 10}),
+            data_hash: generate_hash("entry1".as_bytes()).unwrap(),
+        };
+
+        let entry2 = MetadataEntry {
+            id: Uuid::now_v7(),
+            device_id,
+            archived: true,
+            local: false,
+            parent_id: None,
+            metadata: serde_json::json!({"name": "notes.txt", "size": 10}),
+            data_hash: generate_hash("entry2".as_bytes()).unwrap(),
+        };
+
+        table.create_entry(entry1.clone()).await.unwrap();
+        table.create_entry(entry2.clone()).await.unwrap();
+
+        let results = table
+            .get_entries_by_metadata_conditions(&serde_json::json!({"size": 10}), true)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 2);
+
+        let results = table
+            .get_entries_by_metadata_conditions(&serde_json::json!({"size": 10}), false)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, entry1.id);
+
+        let results = table
+            .get_entries_by_metadata_conditions(&serde_json::json!({"name": "notes.txt"}), true)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, entry2.id);
+
+        let conditions = serde_json::json!({"name": {"$regex": "\\.pdf$"}, "size": 10});
+        let results = table
+            .get_entries_by_metadata_conditions(&conditions, true)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, entry1.id);
+    }
+
+    /// Ids of the active entries matching the conditions
+    async fn query_ids<M: MetadataTable>(table: &M, conditions: Value) -> Vec<Uuid> {
+        table
+            .get_entries_by_metadata_conditions(&conditions, false)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.id)
+            .collect()
+    }
+
+    /// Query nested metadata through path conditions on any backend
+    async fn check_metadata_path_conditions<M: MetadataTable>(table: &mut M) {
+        let device_id = generate_test_device_id();
+
+        let entry1 = MetadataEntry {
+            id: Uuid::now_v7(),
+            device_id,
+            archived: false,
+            local: false,
+            parent_id: None,
+            metadata: serde_json::json!({
+                "file": {"name": "report.pdf", "size": 10},
+                "tags": ["work", "urgent"],
+                "a.b": "dotted"
+            }),
+            data_hash: generate_hash("entry1".as_bytes()).unwrap(),
+        };
+
+        let entry2 = MetadataEntry {
+            id: Uuid::now_v7(),
+            device_id,
+            archived: false,
+            local: false,
+            parent_id: None,
+            metadata: serde_json::json!({
+                "file": {"name": "notes.txt", "size": 20},
+                "tags": ["home"],
+                "a": {"b": "nested"}
+            }),
+            data_hash: generate_hash("entry2".as_bytes()).unwrap(),
+        };
+
+        table.create_entry(entry1.clone()).await.unwrap();
+        table.create_entry(entry2.clone()).await.unwrap();
+
+        assert_eq!(
+            query_ids(table, serde_json::json!({"file.name": "notes.txt"})).await,
+            [entry2.id]
+        );
+        assert_eq!(
+            query_ids(table, serde_json::json!({"file.size": 10})).await,
+            [entry1.id]
+        );
+        assert_eq!(
+            query_ids(table, serde_json::json!({"tags[1]": "urgent"})).await,
+            [entry1.id]
+        );
+        assert_eq!(
+            query_ids(table, serde_json::json!({"tags[*]": "home"})).await,
+            [entry2.id]
+        );
+        assert_eq!(
+            query_ids(table, serde_json::json!({"file.*": {"$regex": "\\.pdf$"}})).await,
+            [entry1.id]
+        );
+
+        // Escaped dots address a single key, unescaped dots a nested one
+        assert_eq!(
+            query_ids(table, serde_json::json!({"a\\.b": "dotted"})).await,
+            [entry1.id]
+        );
+        assert_eq!(
+            query_ids(table, serde_json::json!({"a.b": "nested"})).await,
+            [entry2.id]
+        );
+        assert!(query_ids(table, serde_json::json!({"a.b": "dotted"}))
+            .await
+            .is_empty());
+
+        // Keys are bound as parameters, never spliced into the query
+        assert!(query_ids(table, serde_json::json!({"x' OR '1'='1": "y"}))
+            .await
+            .is_empty());
+
+        assert!(table
+            .get_entries_by_metadata_conditions(&serde_json::json!({"a..b": "x"}), false)
+            .await
+            .is_err());
+    }
+
+    #[sqlx::test]
+    async fn test_get_entries_by_metadata_path_conditions(pool: PgPool) {
+        let mut table = PostgresMetadataTable::from_pool(pool, "test_data")
+            .await
+            .unwrap();
+        check_metadata_path_conditions(&mut table).await;
+    }
+
+    #[sqlx::test]
+    async fn test_sqlite_metadata_path_conditions(
+        pool_options: sqlx::sqlite::SqlitePoolOptions,
+        connect_options: sqlx::sqlite::SqliteConnectOptions,
+    ) {
+        let pool = pool_options
+            .connect_with(connect_options.with_regexp())
+            .await
+            .unwrap();
+        let mut table = SqliteMetadataTable::from_pool(pool, "test_data")
+            .await
+            .unwrap();
+        check_metadata_path_conditions(&mut table).await;
+    }
+
+    #[sqlx::test]
+    async fn test_sqlite_get_entries_after(pool: SqlitePool) {
+        let mut table = SqliteMetadataTable::from_pool(pool, "test_data")
+            .await
+            .unwrap();
+        let device_id = generate_test_device_id();
+
+        let mut ids = Vec::new();
+        for i in 0..3 {
+            let entry = MetadataEntry {
+                id: Uuid::now_v7(),
+                device_id,
+                archived: i == 1,
+                local: false,
+                parent_id: None,
+                metadata: serde_json::json!({ "index": i }),
+                data_hash: generate_hash(format!("entry{}", i).as_bytes()).unwrap(),
+            };
+            ids.push(entry.id);
+            table.create_entry(entry).await.unwrap();
+        }
+
+        let page = table.get_entries_after(None, 2, true).await.unwrap();
+        assert_eq!(page.iter().map(|e| e.id).collect::<Vec<_>>(), ids[..2]);
+
+        let page = table
+            .get_entries_after(Some(ids[1]), 2, true)
+            .await
+            .unwrap();
+        assert_eq!(page.iter().map(|e| e.id).collect::<Vec<_>>(), ids[2..]);
+
+        let page = table.get_entries_after(None, 5, false).await.unwrap();
+        assert_eq!(
+            page.iter().map(|e| e.id).collect::<Vec<_>>(),
+            [ids[0], ids[2]]
+        );
+    }
+
+    /// Ids of the entries matching a query
+    async fn run_query<M: MetadataTable>(table: &M, query: MetadataQuery) -> Vec<Uuid> {
+        table
+            .query(&query)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.id)
+            .collect()
+    }
+
+    /// Run filtered, ordered, and paginated queries on any backend
+    async fn check_query<M: MetadataTable>(table: &mut M) {
+        let device_id = generate_test_device_id();
+
+        // Priorities with a tie and a missing value
+        let priorities = [Some(2), Some(5), None, Some(2), Some(9)];
+        let mut ids = Vec::new();
+        for (i, priority) in priorities.iter().enumerate() {
+            let mut metadata = serde_json::json!({
+                "type": if i == 4 { "note" } else { "todo" },
+                "title": format!("task {}", i),
+            });
+            if let Some(priority) = priority {
+                metadata["priority"] = serde_json::json!(priority);
+            }
+            let entry = MetadataEntry {
+                id: Uuid::now_v7(),
+                device_id,
+                archived: false,
+                local: false,
+                parent_id: None,
+                metadata,
+                data_hash: generate_hash(format!("entry{}", i).as_bytes()).unwrap(),
+            };
+            ids.push(entry.id);
+            table.create_entry(entry).await.unwrap();
+        }
+        table.archive_entry(ids[1]).await.unwrap();
+
+        let path = |s: &str| s.parse::<MetadataPath>().unwrap();
+
+        // Defaults to active entries in creation order
+        assert_eq!(
+            run_query(table, MetadataQuery::new()).await,
+            [ids[0], ids[2], ids[3], ids[4]]
+        );
+        assert_eq!(
+            run_query(
+                table,
+                MetadataQuery::new()
+                    .include_archived(true)
+                    .limit(2)
+                    .offset(1)
+            )
+            .await,
+            [ids[1], ids[2]]
+        );
+
+        // Typed comparisons
+        let todos =
+            MetadataQuery::new().filter(path("type"), Predicate::Eq(serde_json::json!("todo")));
+        assert_eq!(
+            run_query(table, todos.clone()).await,
+            [ids[0], ids[2], ids[3]]
+        );
+        assert_eq!(
+            run_query(
+                table,
+                todos
+                    .clone()
+                    .filter(path("priority"), Predicate::Ge(serde_json::json!(2)))
+            )
+            .await,
+            [ids[0], ids[3]]
+        );
+        assert!(run_query(
+            table,
+            MetadataQuery::new().filter(path("priority"), Predicate::Eq(serde_json::json!("2")))
+        )
+        .await
+        .is_empty());
+        assert_eq!(
+            run_query(
+                table,
+                MetadataQuery::new().filter(path("title"), Predicate::Regex("[34]$".to_string()))
+            )
+            .await,
+            [ids[3], ids[4]]
+        );
+        assert_eq!(
+            run_query(
+                table,
+                MetadataQuery::new().filter(path("priority"), Predicate::Ne(serde_json::json!(2)))
+            )
+            .await,
+            [ids[4]]
+        );
+
+        // Missing values sort first, ties are broken by id
+        let by_priority = MetadataQuery::new().order_by(path("priority"), Direction::Ascending);
+        assert_eq!(
+            run_query(table, by_priority.clone()).await,
+            [ids[2], ids[0], ids[3], ids[4]]
+        );
+        let by_priority_desc =
+            MetadataQuery::new().order_by(path("priority"), Direction::Descending);
+        assert_eq!(
+            run_query(table, by_priority_desc.clone()).await,
+            [ids[4], ids[3], ids[0], ids[2]]
+        );
+
+        // Cursors continue exactly where the last page stopped, in both directions
+        for query in [
+            by_priority,
+            by_priority_desc,
+            MetadataQuery::new().order_by_id(Direction::Descending),
+        ] {
+            let all = run_query(table, query.clone()).await;
+            let mut paged = Vec::new();
+            let mut next = query.clone().limit(1);
+            loop {
+                let page = table.query(&next).await.unwrap();
+                let Some(last) = page.last() else { break };
+                paged.push(last.id);
+                next = next.clone().after(query.cursor(last));
+            }
+            assert_eq!(paged, all);
+        }
+
+        assert!(table
+            .query(&MetadataQuery::new().order_by(path("items[*]"), Direction::Ascending))
+            .await
+            .is_err());
+
+        // Filter on the writer and creation time
+        let active = [ids[0], ids[2], ids[3], ids[4]];
+        assert_eq!(
+            run_query(table, MetadataQuery::new().device(device_id)).await,
+            active
+        );
+        assert!(run_query(
+            table,
+            MetadataQuery::new().device(generate_test_device_id())
+        )
+        .await
+        .is_empty());
+        let created_at = id_created_at(ids[0]).unwrap();
+        let later = created_at + chrono::Duration::hours(1);
+        assert_eq!(
+            run_query(table, MetadataQuery::new().created_since(created_at)).await,
+            active
+        );
+        assert!(run_query(table, MetadataQuery::new().created_since(later))
+            .await
+            .is_empty());
+        assert!(
+            run_query(table, MetadataQuery::new().created_before(created_at))
+                .await
+                .is_empty()
+        );
+        assert_eq!(
+            run_query(table, MetadataQuery::new().created_before(later)).await,
+            active
+        );
+
+        // Pinned queries ignore later updates
+        let update = MetadataEntry {
+            id: Uuid::now_v7(),
+            device_id,
+            archived: false,
+            local: false,
+            parent_id: Some(ids[0]),
+            metadata: serde_json::json!({"type": "todo"}),
+            data_hash: generate_hash("update".as_bytes()).unwrap(),
+        };
+        let update_id = update.id;
+        table.create_entry(update).await.unwrap();
+        assert_eq!(
+            run_query(table, MetadataQuery::new()).await,
+            [ids[2], ids[3], ids[4], update_id]
+        );
+        assert_eq!(
+            run_query(table, MetadataQuery::new().pinned_at(ids[4])).await,
+            active
+        );
+        assert_eq!(
+            run_query(table, MetadataQuery::new().pinned_at(update_id)).await,
+            [ids[2], ids[3], ids[4], update_id]
+        );
+        assert_eq!(
+            run_query(
+                table,
+                MetadataQuery::new()
+                    .pinned_at(ids[2])
+                    .include_archived(true)
+            )
+            .await,
+            ids[..3]
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_query(pool: PgPool) {
+        let mut table = PostgresMetadataTable::from_pool(pool, "test_data")
+            .await
+            .unwrap();
+        check_query(&mut table).await;
+    }
+
+    #[sqlx::test]
+    async fn test_sqlite_query(
+        pool_options: sqlx::sqlite::SqlitePoolOptions,
+        connect_options: sqlx::sqlite::SqliteConnectOptions,
+    ) {
+        let pool = pool_options
+            .connect_with(connect_options.with_regexp())
+            .await
+            .unwrap();
+        let mut table = SqliteMetadataTable::from_pool(pool, "test_data")
+            .await
+            .unwrap();
+        check_query(&mut table).await;
+
+        // Collated ordering isn't supported
+        let query = MetadataQuery::new()
+            .order_by("title".parse().unwrap(), Direction::Ascending)
+            .collation("en-US");
+        assert!(table.query(&query).await.is_err());
+    }
+
+    #[sqlx::test]
+    async fn test_query_collation(pool: PgPool) {
+        let mut table = PostgresMetadataTable::from_pool(pool, "test_data")
+            .await
+            .unwrap();
+        let device_id = generate_test_device_id();
+
+        let names = [
+            serde_json::json!("b"),
+            serde_json::json!("Ä"),
+            serde_json::json!(1),
+            serde_json::json!("a"),
+            serde_json::json!("A"),
+        ];
+        let mut ids = Vec::new();
+        for (i, name) in names.into_iter().enumerate() {
+            let entry = MetadataEntry {
+                id: Uuid::now_v7(),
+                device_id,
+                archived: false,
+                local: false,
+                parent_id: None,
+                metadata: serde_json::json!({ "name": name }),
+                data_hash: generate_hash(format!("entry{}", i).as_bytes()).unwrap(),
+            };
+            ids.push(entry.id);
+            table.create_entry(entry).await.unwrap();
+        }
+
+        // Byte order puts the uppercase and accented letters apart
+        let by_name = MetadataQuery::new().order_by("name".parse().unwrap(), Direction::Ascending);
+        assert_eq!(
+            run_query(&table, by_name.clone()).await,
+            [ids[4], ids[3], ids[0], ids[1], ids[2]]
+        );
+
+        // Strings are collated, but still sort before numbers as in JSON
+        for query in [
+            by_name.clone().collation("en-US"),
+            MetadataQuery::new()
+                .order_by("name".parse().unwrap(), Direction::Descending)
+                .collation("en-US"),
+        ] {
+            let all = run_query(&table, query.clone()).await;
+            let mut expected = vec![ids[3], ids[4], ids[1], ids[0], ids[2]];
+            if query.direction == Direction::Descending {
+                expected.reverse();
+            }
+            assert_eq!(all, expected);
+
+            let mut paged = Vec::new();
+            let mut next = query.clone().limit(2);
+            loop {
+                let page = table.query(&next).await.unwrap();
+                let Some(last) = page.last() else { break };
+                paged.extend(page.iter().map(|entry| entry.id));
+                next = next.clone().after(query.cursor(last));
+            }
+            assert_eq!(paged, all);
+        }
+
+        assert!(table
+            .query(&by_name.clone().collation("en\"; DROP TABLE x; --"))
+            .await
+            .is_err());
+    }
 }
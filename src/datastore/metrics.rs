@@ -0,0 +1,51 @@
+//! Store Metrics
+//!
+//! Counters of the work a DataStore has done since it was opened. They're kept in memory
+//! only, read them with [`DataStore::metrics`](crate::datastore::store::DataStore::metrics).
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A snapshot of the counters of a DataStore
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreMetrics {
+    /// Entries created, including updates of existing entries
+    pub entries_committed: u64,
+    /// Entries archived
+    pub entries_archived: u64,
+    /// Entries copied in from other stores
+    pub entries_merged: u64,
+    /// Queries run against the metadata table
+    pub queries: u64,
+    /// Bytes of data read out of the store
+    pub bytes_read: u64,
+}
+
+/// The live counters behind [`StoreMetrics`]
+///
+/// Atomic so they can be updated through a shared reference.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) entries_committed: AtomicU64,
+    pub(crate) entries_archived: AtomicU64,
+    pub(crate) entries_merged: AtomicU64,
+    pub(crate) queries: AtomicU64,
+    pub(crate) bytes_read: AtomicU64,
+}
+
+impl Counters {
+    /// Add `amount` to a counter
+    pub(crate) fn add(counter: &AtomicU64, amount: u64) {
+        counter.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    /// Read the current value of every counter
+    pub(crate) fn snapshot(&self) -> StoreMetrics {
+        StoreMetrics {
+            entries_committed: self.entries_committed.load(Ordering::Relaxed),
+            entries_archived: self.entries_archived.load(Ordering::Relaxed),
+            entries_merged: self.entries_merged.load(Ordering::Relaxed),
+            queries: self.queries.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -1,7 +1,15 @@
+pub mod audit;
+pub mod backup;
 pub mod data;
 pub mod data_handler;
+pub mod events;
 pub mod metadata;
+pub mod metrics;
+pub mod path;
+pub mod query;
+pub mod readonly;
 pub mod schema;
 pub mod settings;
+pub mod snapshot;
 pub mod store;
 pub mod stream_table;
@@ -0,0 +1,441 @@
+//! Metadata Paths
+//!
+//! A parsed path into a JSON metadata document, used to address nested values.
+//!
+//! Syntax:
+//! - Keys are separated by `.`, e.g. `file.name`
+//! - `[3]` addresses an array index, e.g. `items[3].name`
+//! - `*` or `[*]` matches every member of an object or element of an array
+//! - `\` escapes the next character, so `a\.b` is the single key `a.b`
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::Value;
+use std::fmt;
+use std::str::FromStr;
+
+/// A single step in a MetadataPath
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSegment {
+    /// An object key
+    Key(String),
+    /// An array index
+    Index(usize),
+    /// Every member of an object or element of an array
+    Wildcard,
+}
+
+/// A parsed path into a JSON document
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataPath {
+    segments: Vec<PathSegment>,
+}
+
+#[allow(dead_code)]
+impl MetadataPath {
+    /// Create a path from already parsed segments
+    pub fn new(segments: Vec<PathSegment>) -> Self {
+        Self { segments }
+    }
+
+    /// The segments of this path
+    pub fn segments(&self) -> &[PathSegment] {
+        &self.segments
+    }
+
+    /// Whether this path contains any wildcard segments
+    pub fn has_wildcard(&self) -> bool {
+        self.segments.contains(&PathSegment::Wildcard)
+    }
+
+    /// Get every value in `document` matched by this path
+    ///
+    /// Paths without wildcards match at most one value.
+    pub fn get<'a>(&self, document: &'a Value) -> Vec<&'a Value> {
+        let mut current = vec![document];
+        for segment in &self.segments {
+            current = current
+                .into_iter()
+                .flat_map(|value| match (segment, value) {
+                    (PathSegment::Key(key), Value::Object(map)) => {
+                        map.get(key).into_iter().collect()
+                    }
+                    (PathSegment::Index(index), Value::Array(items)) => {
+                        items.get(*index).into_iter().collect()
+                    }
+                    (PathSegment::Wildcard, Value::Object(map)) => map.values().collect(),
+                    (PathSegment::Wildcard, Value::Array(items)) => items.iter().collect(),
+                    _ => Vec::new(),
+                })
+                .collect();
+        }
+        current
+    }
+
+    /// Set the value at this path in `document`
+    ///
+    /// Missing object keys are created along the way, array indices must already exist.
+    /// Wildcard paths can't be set.
+    pub fn set(&self, document: &mut Value, value: Value) -> Result<()> {
+        let mut current = document;
+        for segment in &self.segments {
+            current = match segment {
+                PathSegment::Key(key) => {
+                    if current.is_null() {
+                        *current = Value::Object(Default::default());
+                    }
+                    current
+                        .as_object_mut()
+                        .ok_or_else(|| anyhow!("'{}' is not an object", self))?
+                        .entry(key.clone())
+                        .or_insert(Value::Null)
+                }
+                PathSegment::Index(index) => current
+                    .as_array_mut()
+                    .ok_or_else(|| anyhow!("'{}' is not an array", self))?
+                    .get_mut(*index)
+                    .ok_or_else(|| anyhow!("Index {} is out of bounds in '{}'", index, self))?,
+                PathSegment::Wildcard => bail!("Can't set a wildcard path '{}'", self),
+            };
+        }
+        *current = value;
+        Ok(())
+    }
+
+    /// Remove the value at this path from `document`, returning it if it existed
+    ///
+    /// Wildcard paths can't be removed.
+    pub fn remove(&self, document: &mut Value) -> Result<Option<Value>> {
+        if self.has_wildcard() {
+            bail!("Can't remove a wildcard path '{}'", self);
+        }
+        let Some((last, parents)) = self.segments.split_last() else {
+            bail!("Can't remove the root of a document");
+        };
+
+        let mut current = document;
+        for segment in parents {
+            let next = match (segment, current) {
+                (PathSegment::Key(key), Value::Object(map)) => map.get_mut(key),
+                (PathSegment::Index(index), Value::Array(items)) => items.get_mut(*index),
+                _ => None,
+            };
+            match next {
+                Some(next) => current = next,
+                None => return Ok(None),
+            }
+        }
+
+        Ok(match (last, current) {
+            (PathSegment::Key(key), Value::Object(map)) => map.remove(key),
+            (PathSegment::Index(index), Value::Array(items)) if *index < items.len() => {
+                Some(items.remove(*index))
+            }
+            _ => None,
+        })
+    }
+
+    /// Convert to a PostgreSQL jsonpath expression
+    pub fn to_jsonpath(&self) -> String {
+        let mut path = "$".to_string();
+        for segment in &self.segments {
+            match segment {
+                PathSegment::Key(key) => {
+                    // jsonpath string literals use JSON escaping
+                    path.push('.');
+                    path.push_str(&Value::String(key.clone()).to_string());
+                }
+                PathSegment::Index(index) => path.push_str(&format!("[{}]", index)),
+                // Exactly one level down, for both objects and arrays
+                PathSegment::Wildcard => path.push_str(".**{1}"),
+            }
+        }
+        path
+    }
+
+    /// Convert to a PostgreSQL text array path, as used by the `#>` operator
+    ///
+    /// Wildcard paths can't be converted.
+    pub fn to_text_array(&self) -> Result<Vec<String>> {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                PathSegment::Key(key) => Ok(key.clone()),
+                PathSegment::Index(index) => Ok(index.to_string()),
+                PathSegment::Wildcard => bail!("Wildcards aren't allowed in '{}'", self),
+            })
+            .collect()
+    }
+
+    /// Convert to SQLite JSON paths, split at every wildcard
+    ///
+    /// SQLite paths have no wildcards, so callers iterate with `json_each` at each split.
+    /// Every path after the first is relative and has no leading `$`.
+    pub fn to_sqlite_paths(&self) -> Result<Vec<String>> {
+        let mut paths = vec!["$".to_string()];
+        for segment in &self.segments {
+            match segment {
+                PathSegment::Key(key) => {
+                    // SQLite has no way to escape a quote inside a quoted key
+                    if key.contains('"') {
+                        bail!("SQLite can't query keys containing '\"' in '{}'", self);
+                    }
+                    paths.last_mut().unwrap().push_str(&format!(".\"{}\"", key));
+                }
+                PathSegment::Index(index) => {
+                    paths.last_mut().unwrap().push_str(&format!("[{}]", index));
+                }
+                PathSegment::Wildcard => paths.push(String::new()),
+            }
+        }
+        Ok(paths)
+    }
+}
+
+impl FromStr for MetadataPath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut chars = s.chars();
+        // The key currently being read, and whether any of it was escaped
+        let mut key = String::new();
+        let mut escaped = false;
+        // Whether a key is expected next, false right after a `]`
+        let mut reading_key = true;
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    if !reading_key {
+                        bail!("Expected '.' or '[' after ']' in path '{}'", s);
+                    }
+                    let c = chars
+                        .next()
+                        .ok_or_else(|| anyhow!("Path '{}' ends with an escape", s))?;
+                    key.push(c);
+                    escaped = true;
+                }
+                '.' => {
+                    if reading_key {
+                        segments.push(key_segment(s, std::mem::take(&mut key), escaped)?);
+                        escaped = false;
+                    }
+                    reading_key = true;
+                }
+                '[' => {
+                    if reading_key {
+                        segments.push(key_segment(s, std::mem::take(&mut key), escaped)?);
+                        escaped = false;
+                        reading_key = false;
+                    }
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find(']')
+                        .ok_or_else(|| anyhow!("Unclosed '[' in path '{}'", s))?;
+                    let index = &rest[..end];
+                    segments.push(if index == "*" {
+                        PathSegment::Wildcard
+                    } else {
+                        PathSegment::Index(
+                            index.parse().map_err(|_| {
+                                anyhow!("Invalid index '{}' in path '{}'", index, s)
+                            })?,
+                        )
+                    });
+                    chars = rest[end + 1..].chars();
+                }
+                _ => {
+                    if !reading_key {
+                        bail!("Expected '.' or '[' after ']' in path '{}'", s);
+                    }
+                    key.push(c);
+                }
+            }
+        }
+
+        if reading_key {
+            segments.push(key_segment(s, key, escaped)?);
+        }
+
+        Ok(Self { segments })
+    }
+}
+
+/// Build the segment for a key that was just read
+///
+/// An unescaped `*` is a wildcard, so `\*` stays the literal key `*`.
+fn key_segment(path: &str, key: String, escaped: bool) -> Result<PathSegment> {
+    if key.is_empty() && !escaped {
+        bail!("Empty key in path '{}'", path);
+    }
+    if key == "*" && !escaped {
+        return Ok(PathSegment::Wildcard);
+    }
+    Ok(PathSegment::Key(key))
+}
+
+impl fmt::Display for MetadataPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                PathSegment::Key(key) => {
+                    if i > 0 {
+                        write!(f, ".")?;
+                    }
+                    for c in key.chars() {
+                        if matches!(c, '.' | '[' | ']' | '\\' | '*') {
+                            write!(f, "\\")?;
+                        }
+                        write!(f, "{}", c)?;
+                    }
+                }
+                PathSegment::Index(index) => write!(f, "[{}]", index)?,
+                PathSegment::Wildcard => write!(f, "[*]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn path(s: &str) -> MetadataPath {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            path("file.name").segments(),
+            [
+                PathSegment::Key("file".to_string()),
+                PathSegment::Key("name".to_string())
+            ]
+        );
+        assert_eq!(
+            path("items[3].tags[*]").segments(),
+            [
+                PathSegment::Key("items".to_string()),
+                PathSegment::Index(3),
+                PathSegment::Key("tags".to_string()),
+                PathSegment::Wildcard
+            ]
+        );
+        assert_eq!(
+            path("items.*.name").segments(),
+            [
+                PathSegment::Key("items".to_string()),
+                PathSegment::Wildcard,
+                PathSegment::Key("name".to_string())
+            ]
+        );
+        assert_eq!(path("grid[0][1]").segments().len(), 3);
+
+        // Escapes make the next character part of the key
+        assert_eq!(
+            path(r"a\.b.\*.c\[0\]\\").segments(),
+            [
+                PathSegment::Key("a.b".to_string()),
+                PathSegment::Key("*".to_string()),
+                PathSegment::Key(r"c[0]\".to_string())
+            ]
+        );
+
+        for invalid in [
+            "", ".a", "a.", "a..b", "[0]", "a[", "a[x]", "a[-1]", "a[0]b", r"a\",
+        ] {
+            assert!(
+                invalid.parse::<MetadataPath>().is_err(),
+                "'{}' should not parse",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        for s in ["file.name", "items[3].tags[*]", r"a\.b.\*.c\[0\]\\"] {
+            let parsed = path(s);
+            assert_eq!(path(&parsed.to_string()), parsed);
+        }
+        assert_eq!(path("items.*.name").to_string(), "items[*].name");
+    }
+
+    #[test]
+    fn test_get() {
+        let document = json!({
+            "a.b": 1,
+            "items": [{"name": "x"}, {"name": "y"}, {"other": true}],
+            "nested": {"deep": {"value": 5}}
+        });
+
+        assert_eq!(path(r"a\.b").get(&document), [&json!(1)]);
+        assert_eq!(path("nested.deep.value").get(&document), [&json!(5)]);
+        assert_eq!(path("items[1].name").get(&document), [&json!("y")]);
+        assert_eq!(
+            path("items[*].name").get(&document),
+            [&json!("x"), &json!("y")]
+        );
+        assert_eq!(path("nested.*.value").get(&document), [&json!(5)]);
+        assert!(path("items[5]").get(&document).is_empty());
+        assert!(path("a.b").get(&document).is_empty());
+    }
+
+    #[test]
+    fn test_set_and_remove() {
+        let mut document = json!({"items": [1, 2, 3]});
+
+        path("file.name")
+            .set(&mut document, json!("a.txt"))
+            .unwrap();
+        path(r"odd\.key").set(&mut document, json!(true)).unwrap();
+        path("items[1]").set(&mut document, json!(20)).unwrap();
+        assert_eq!(
+            document,
+            json!({"items": [1, 20, 3], "file": {"name": "a.txt"}, "odd.key": true})
+        );
+
+        assert!(path("items[5]").set(&mut document, json!(0)).is_err());
+        assert!(path("items.name").set(&mut document, json!(0)).is_err());
+        assert!(path("items[*]").set(&mut document, json!(0)).is_err());
+
+        assert_eq!(
+            path("items[0]").remove(&mut document).unwrap(),
+            Some(json!(1))
+        );
+        assert_eq!(
+            path("file.name").remove(&mut document).unwrap(),
+            Some(json!("a.txt"))
+        );
+        assert_eq!(path("file.missing").remove(&mut document).unwrap(), None);
+        assert_eq!(path("missing.name").remove(&mut document).unwrap(), None);
+        assert!(path("items[*]").remove(&mut document).is_err());
+        assert_eq!(
+            document,
+            json!({"items": [20, 3], "file": {}, "odd.key": true})
+        );
+    }
+
+    #[test]
+    fn test_backend_paths() {
+        let parsed = path(r#"items[2].*.say\"hi\".a\.b"#);
+        assert_eq!(
+            parsed.to_jsonpath(),
+            r#"$."items"[2].**{1}."say\"hi\""."a.b""#
+        );
+        // SQLite can't escape quotes in keys
+        assert!(parsed.to_sqlite_paths().is_err());
+
+        assert_eq!(
+            path(r"items[2].*.a\.b").to_sqlite_paths().unwrap(),
+            [r#"$."items"[2]"#, r#"."a.b""#]
+        );
+        assert_eq!(
+            path("tags[*]").to_sqlite_paths().unwrap(),
+            [r#"$."tags""#, ""]
+        );
+    }
+}
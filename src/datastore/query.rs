@@ -0,0 +1,258 @@
+//! Metadata Queries
+//!
+//! A builder for filtered, ordered, and paginated queries over a metadata table.
+//!
+//! ```ignore
+//! let query = MetadataQuery::new()
+//!     .filter("type".parse()?, Predicate::Eq(json!("todo")))
+//!     .order_by("priority".parse()?, Direction::Descending)
+//!     .limit(20);
+//! let page = store.query(&query).await?;
+//! let next = query.clone().after(query.cursor(page.last().unwrap()));
+//! ```
+
+use crate::datastore::path::MetadataPath;
+use crate::datastore::schema::{DeviceId, MetadataEntry};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use uuid::Uuid;
+
+/// A comparison against the values selected by a MetadataPath
+///
+/// Values are compared as JSON, so `Eq(json!(5))` doesn't match the string `"5"`.
+/// Ordering between values of different JSON types is backend specific.
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Predicate {
+    Eq(Value),
+    Ne(Value),
+    Lt(Value),
+    Le(Value),
+    Gt(Value),
+    Ge(Value),
+    /// Regular expression match against the text of the value
+    Regex(String),
+}
+
+impl Predicate {
+    /// The SQL comparison operator and JSON operand for this predicate
+    ///
+    /// Returns None for predicates that aren't a plain comparison.
+    pub(crate) fn comparison(&self) -> Option<(&'static str, &Value)> {
+        match self {
+            Predicate::Eq(value) => Some(("=", value)),
+            Predicate::Ne(value) => Some(("<>", value)),
+            Predicate::Lt(value) => Some(("<", value)),
+            Predicate::Le(value) => Some(("<=", value)),
+            Predicate::Gt(value) => Some((">", value)),
+            Predicate::Ge(value) => Some((">=", value)),
+            Predicate::Regex(_) => None,
+        }
+    }
+}
+
+/// Sort direction of a query
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Direction {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+impl Direction {
+    /// SQL keyword for ORDER BY
+    pub(crate) fn keyword(&self) -> &'static str {
+        match self {
+            Direction::Ascending => "ASC",
+            Direction::Descending => "DESC",
+        }
+    }
+
+    /// SQL operator selecting the rows that sort after a cursor
+    pub(crate) fn after_operator(&self) -> &'static str {
+        match self {
+            Direction::Ascending => ">",
+            Direction::Descending => "<",
+        }
+    }
+}
+
+/// Position in the results of a query, used to fetch the next page
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryCursor {
+    /// Value of the sort key in the last entry seen, null if the query has no sort key
+    pub sort_value: Value,
+    /// Id of the last entry seen
+    pub id: Uuid,
+}
+
+/// A parameter bound to a generated query
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum QueryParam {
+    Text(String),
+    TextArray(Vec<String>),
+    Id(Uuid),
+    Int(i64),
+    Bytes(Vec<u8>),
+}
+
+/// A query over the entries in a metadata table
+///
+/// Entries are always ordered by id after any sort key, so results are stable and
+/// cursors are unique. Only active entries are returned unless archived entries are included.
+#[derive(Debug, Clone, Default)]
+pub struct MetadataQuery {
+    pub(crate) filters: Vec<(MetadataPath, Predicate)>,
+    pub(crate) order_by: Option<MetadataPath>,
+    pub(crate) direction: Direction,
+    pub(crate) limit: Option<i64>,
+    pub(crate) offset: Option<i64>,
+    pub(crate) after: Option<QueryCursor>,
+    pub(crate) include_archived: bool,
+    pub(crate) collation: Option<String>,
+    pub(crate) device_id: Option<DeviceId>,
+    pub(crate) created_since: Option<DateTime<Utc>>,
+    pub(crate) created_before: Option<DateTime<Utc>>,
+    pub(crate) pinned_at: Option<Uuid>,
+}
+
+#[allow(dead_code)]
+impl MetadataQuery {
+    /// Create a query matching every active entry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only match entries where a value selected by `path` satisfies `predicate`
+    pub fn filter(mut self, path: MetadataPath, predicate: Predicate) -> Self {
+        self.filters.push((path, predicate));
+        self
+    }
+
+    /// Sort by the value at `path`, entries without it sort first
+    ///
+    /// The path can't contain wildcards.
+    pub fn order_by(mut self, path: MetadataPath, direction: Direction) -> Self {
+        self.order_by = Some(path);
+        self.direction = direction;
+        self
+    }
+
+    /// Sort by id only, which is creation order
+    pub fn order_by_id(mut self, direction: Direction) -> Self {
+        self.order_by = None;
+        self.direction = direction;
+        self
+    }
+
+    /// Return at most `limit` entries
+    pub fn limit(mut self, limit: i64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Skip the first `offset` entries
+    pub fn offset(mut self, offset: i64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Only return entries that sort after the cursor
+    ///
+    /// Unlike an offset this stays correct when entries are added between pages.
+    pub fn after(mut self, cursor: QueryCursor) -> Self {
+        self.after = Some(cursor);
+        self
+    }
+
+    /// Whether to include archived entries
+    pub fn include_archived(mut self, include_archived: bool) -> Self {
+        self.include_archived = include_archived;
+        self
+    }
+
+    /// Only match entries written by `device_id`
+    pub fn device(mut self, device_id: DeviceId) -> Self {
+        self.device_id = Some(device_id);
+        self
+    }
+
+    /// Only match entries created at or after `time`, to the millisecond
+    pub fn created_since(mut self, time: DateTime<Utc>) -> Self {
+        self.created_since = Some(time);
+        self
+    }
+
+    /// Only match entries created before `time`, to the millisecond
+    pub fn created_before(mut self, time: DateTime<Utc>) -> Self {
+        self.created_before = Some(time);
+        self
+    }
+
+    /// Match the store as it was when `id` was its newest entry
+    ///
+    /// Entries created after `id` are ignored, including the updates that archived older
+    /// entries, so every query and page pinned to the same id sees the same state even while
+    /// new entries are committed. Get a pin with
+    /// [`DataStore::latest_id`](crate::datastore::store::DataStore::latest_id).
+    ///
+    /// Entries that arrive later with older ids, e.g. merged from another copy of the store,
+    /// still show up.
+    pub fn pinned_at(mut self, id: Uuid) -> Self {
+        self.pinned_at = Some(id);
+        self
+    }
+
+    /// Compare strings using the rules of a locale when sorting, e.g. "en-US" or "sv"
+    ///
+    /// Other values still sort in JSON order. Queries run through a DataStore default to
+    /// the store's collation, see [`DataStore::set_collation`](crate::datastore::store::DataStore::set_collation).
+    pub fn collation(mut self, locale: &str) -> Self {
+        self.collation = Some(locale.to_string());
+        self
+    }
+
+    /// Build the cursor that continues this query after `entry`
+    pub fn cursor(&self, entry: &MetadataEntry) -> QueryCursor {
+        let sort_value = self
+            .order_by
+            .as_ref()
+            .and_then(|path| path.get(&entry.metadata).first().cloned().cloned())
+            .unwrap_or(Value::Null);
+        QueryCursor {
+            sort_value,
+            id: entry.id,
+        }
+    }
+}
+
+/// Check that a locale is safe to use in a collation name
+///
+/// Collation names can't be bound as parameters, so they're restricted to simple identifiers.
+pub(crate) fn validate_locale(locale: &str) -> Result<()> {
+    if locale.is_empty()
+        || !locale
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("Invalid collation locale '{}'", locale);
+    }
+    Ok(())
+}
+
+/// The lowest UUIDv7 that could be generated at `time`
+///
+/// Ids embed their creation time in milliseconds and sort by it, so comparing against this
+/// selects entries by creation time.
+pub(crate) fn first_id_at(time: DateTime<Utc>) -> Uuid {
+    let millis = time.timestamp_millis().max(0) as u128;
+    Uuid::from_u128((millis << 80) | (0x7 << 76) | (0b10 << 62))
+}
+
+/// Creation time embedded in a UUIDv7 entry id
+pub(crate) fn id_created_at(id: Uuid) -> Option<DateTime<Utc>> {
+    let (secs, nanos) = id.get_timestamp()?.to_unix();
+    DateTime::from_timestamp(secs as i64, nanos)
+}
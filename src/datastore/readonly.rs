@@ -0,0 +1,118 @@
+//! Read-only Access
+//!
+//! [`ReadOnlyDataStore`] borrows a DataStore and only exposes its read methods, so code
+//! that should never write, like listing or reporting, can be handed one without any risk
+//! of it creating, archiving, or reconfiguring entries.
+
+use crate::datastore::audit::AuditRecord;
+use crate::datastore::data::DataTable;
+use crate::datastore::events::{ChangeEvent, EntryWatch};
+use crate::datastore::metadata::MetadataTable;
+use crate::datastore::query::MetadataQuery;
+use crate::datastore::schema::{DeviceId, MetadataEntry};
+use crate::datastore::settings::Setting;
+use crate::datastore::store::{DataStore, EntryIter};
+use anyhow::Result;
+use serde_json::Value;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// A view of a DataStore that can't write to it
+///
+/// Created with [`DataStore::read_only`]. Every method forwards to the DataStore method
+/// of the same name.
+pub struct ReadOnlyDataStore<'a, D: DataTable, M: MetadataTable> {
+    store: &'a DataStore<D, M>,
+}
+
+// Derived impls would require D and M to be Clone as well
+impl<D: DataTable, M: MetadataTable> Clone for ReadOnlyDataStore<'_, D, M> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<D: DataTable, M: MetadataTable> Copy for ReadOnlyDataStore<'_, D, M> {}
+
+#[allow(dead_code)]
+impl<'a, D: DataTable, M: MetadataTable> ReadOnlyDataStore<'a, D, M> {
+    pub(crate) fn new(store: &'a DataStore<D, M>) -> Self {
+        Self { store }
+    }
+
+    /// See [`DataStore::read_data`]
+    pub async fn read_data<W: std::io::Write>(&self, id: Uuid, writer: &mut W) -> Result<u64> {
+        self.store.read_data(id, writer).await
+    }
+
+    /// See [`DataStore::get_active_entries`]
+    pub async fn get_active_entries(&self) -> Result<Vec<MetadataEntry>> {
+        self.store.get_active_entries().await
+    }
+
+    /// See [`DataStore::get_archived_entries`]
+    pub async fn get_archived_entries(&self) -> Result<Vec<MetadataEntry>> {
+        self.store.get_archived_entries().await
+    }
+
+    /// See [`DataStore::get_history`]
+    pub async fn get_history(&self, id: Uuid) -> Result<Vec<MetadataEntry>> {
+        self.store.get_history(id).await
+    }
+
+    /// See [`DataStore::iter_entries`]
+    pub fn iter_entries(&self, page_size: i64, include_archived: bool) -> EntryIter<'a, D, M> {
+        self.store.iter_entries(page_size, include_archived)
+    }
+
+    /// See [`DataStore::iter_entries_since`]
+    pub fn iter_entries_since(
+        &self,
+        id: Uuid,
+        page_size: i64,
+        include_archived: bool,
+    ) -> EntryIter<'a, D, M> {
+        self.store
+            .iter_entries_since(id, page_size, include_archived)
+    }
+
+    /// See [`DataStore::query`]
+    pub async fn query(&self, query: &MetadataQuery) -> Result<Vec<MetadataEntry>> {
+        self.store.query(query).await
+    }
+
+    /// See [`DataStore::get_entries_by_metadata_conditions`]
+    pub async fn get_entries_by_metadata_conditions(
+        &self,
+        conditions: Value,
+    ) -> Result<Vec<MetadataEntry>> {
+        self.store
+            .get_entries_by_metadata_conditions(conditions)
+            .await
+    }
+
+    /// See [`DataStore::audit_log`]
+    pub async fn audit_log(&self, query: MetadataQuery) -> Result<Vec<AuditRecord>> {
+        self.store.audit_log(query).await
+    }
+
+    /// See [`DataStore::subscribe`]
+    pub fn subscribe(&self) -> broadcast::Receiver<ChangeEvent> {
+        self.store.subscribe()
+    }
+
+    /// See [`DataStore::watch`]
+    pub fn watch(&self, id: Uuid) -> EntryWatch {
+        self.store.watch(id)
+    }
+
+    /// See [`DataStore::get_setting`]
+    pub async fn get_setting(&self, name: &str) -> Result<Option<Setting>> {
+        self.store.get_setting(name).await
+    }
+
+    /// See [`DataStore::device_id`]
+    pub fn device_id(&self) -> DeviceId {
+        self.store.device_id()
+    }
+}
@@ -1,4 +1,7 @@
+use crate::datastore::query::id_created_at;
+use chrono::{DateTime, Utc};
 use ed25519_dalek::{PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use sqlx::Row;
 use uuid::Uuid;
@@ -7,7 +10,7 @@ pub type DeviceId = [u8; PUBLIC_KEY_LENGTH];
 pub type PrivateKey = [u8; SECRET_KEY_LENGTH];
 
 /// A single entry in the metadata table
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct MetadataEntry {
     /// UUIDv7 that serves as unique identifier across all devices
     pub id: Uuid,
@@ -60,6 +63,21 @@ pub struct DataEntry {
     pub s3_path: Vec<String>,
 }
 
+/// Local access bookkeeping for a piece of data in the data table
+///
+/// This is never synced, it only describes how this device uses its copy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataAccess {
+    /// Hash of the data
+    pub hash: String,
+
+    /// How many metadata entries expect this data
+    pub ref_count: i32,
+
+    /// Last time the data was read or written on this device
+    pub last_accessed: DateTime<Utc>,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum StreamType {
     Stream,
@@ -97,6 +115,41 @@ impl From<sqlx::postgres::PgRow> for StreamEntry {
     }
 }
 
+impl From<sqlx::sqlite::SqliteRow> for StreamEntry {
+    fn from(row: sqlx::sqlite::SqliteRow) -> Self {
+        let id: Vec<u8> = row.get("id");
+        let secret_key: Option<Vec<u8>> = row.get("secret_key");
+        Self {
+            index: row.get("index"),
+            id: id
+                .try_into()
+                .expect("Stored device id has the wrong length"),
+            secret_key: secret_key.map(|key| {
+                key.try_into()
+                    .expect("Stored secret key has the wrong length")
+            }),
+            stream_type: match row.get::<String, _>("stream_type").as_str() {
+                "Stream" => StreamType::Stream,
+                "User" => StreamType::User,
+                "Instance" => StreamType::Instance,
+                "Store" => StreamType::Store,
+                _ => StreamType::Stream,
+            },
+        }
+    }
+}
+
+impl MetadataEntry {
+    /// When the entry was created, to the millisecond
+    ///
+    /// This is read from the id, which is a UUIDv7, so it's the clock of the device that
+    /// wrote the entry. Applications that need more about a change, like a message or tags,
+    /// can store them in the metadata.
+    pub fn created_at(&self) -> Option<DateTime<Utc>> {
+        id_created_at(self.id)
+    }
+}
+
 impl DataEntry {
     /// Create a new DataEntry with the given hash
     ///
@@ -1,14 +1,14 @@
 use crate::datastore::schema::DeviceId;
 use anyhow::{bail, Context, Result};
 use log;
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use sqlx::PgPool;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::{PgPool, SqlitePool};
 use uuid::Uuid;
 use validator::{Validate, ValidationError};
 
 use crate::datastore::{
-    metadata::{MetadataTable, PostgresMetadataTable},
+    metadata::{MetadataTable, PostgresMetadataTable, SqliteMetadataTable},
     schema::MetadataEntry,
 }; // Import both trait and implementation
 
@@ -25,6 +25,31 @@ pub struct Setting {
     pub description: Option<String>,
 }
 
+/// Descriptive settings of a store, saved together as a single setting
+///
+/// Read and change them with
+/// [`DataStore::store_settings`](crate::datastore::store::DataStore::store_settings) and
+/// [`DataStore::update_store_settings`](crate::datastore::store::DataStore::update_store_settings).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, Validate)]
+pub struct StoreSettings {
+    /// Name to show people instead of the store's name
+    #[validate(length(min = 1, max = 255))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+
+    /// What the store is for
+    #[validate(length(max = 4096))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    /// Application specific settings
+    ///
+    /// Fields added by newer versions end up here too, so they're kept when an older
+    /// version updates the settings.
+    #[serde(flatten)]
+    pub custom: Map<String, Value>,
+}
+
 pub struct SettingsTable<T: MetadataTable> {
     table: T,
     device_id: DeviceId,
@@ -130,6 +155,15 @@ impl SettingsTable<PostgresMetadataTable> {
     }
 }
 
+#[allow(dead_code)]
+impl SettingsTable<SqliteMetadataTable> {
+    /// Create a new SettingsTable from a SQLite pool
+    pub async fn from_sqlite(pool: SqlitePool, device_id: DeviceId) -> Result<Self> {
+        let table = SqliteMetadataTable::from_pool(pool, "settings").await?;
+        Self::new(table, device_id).await
+    }
+}
+
 #[allow(dead_code)]
 impl<T: MetadataTable> SettingsTable<T> {
     /// Retrieves a setting by its key
@@ -183,6 +217,21 @@ impl<T: MetadataTable> SettingsTable<T> {
         }
     }
 
+    /// Retrieve the current value deserialized as `V`, None if it's not set
+    pub async fn get_typed<V: DeserializeOwned>(&self, key: &str) -> Result<Option<V>> {
+        match self.get_setting(key).await?.map(|setting| setting.value) {
+            None | Some(Value::Null) => Ok(None),
+            Some(value) => serde_json::from_value(value)
+                .map(Some)
+                .with_context(|| format!("Invalid value for setting {}", key)),
+        }
+    }
+
+    /// Set a key to a serialized value, creating it if necessary
+    pub async fn set_typed<V: Serialize>(&mut self, key: &str, value: &V) -> Result<()> {
+        self.set_value(key, serde_json::to_value(value)?).await
+    }
+
     /// Retrieve the current value. If it's not currently set, we return Null.
     pub async fn get_value(&mut self, key: &str) -> Result<serde_json::Value> {
         let setting = self.get_setting(key).await?;
@@ -278,7 +327,7 @@ impl<T: MetadataTable> SettingsTable<T> {
     ///
     /// # Returns
     /// * `Ok(Vec<(Setting, bool)>)` - List of all versions of the setting in chronological order,
-    ///                                where the bool indicates whether this version is the current active setting
+    ///   where the bool indicates whether this version is the current active setting
     /// * `Err(Error)` - Failed to retrieve the setting history
     pub async fn get_setting_history(&self, key: &str) -> Result<Vec<(Setting, bool)>> {
         let conditions = serde_json::json!({
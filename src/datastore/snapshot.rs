@@ -0,0 +1,81 @@
+//! Signed Snapshots
+//!
+//! A snapshot is the active state of a DataStore signed by an ed25519 key, so it can be
+//! handed to a third party and verified offline without any access to the database.
+
+use crate::datastore::schema::{DeviceId, MetadataEntry};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+/// The active state of a DataStore at a point in time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Name of the store the snapshot was taken from
+    pub store: String,
+
+    /// Device that took the snapshot
+    pub device_id: DeviceId,
+
+    /// When the snapshot was taken
+    pub created_at: DateTime<Utc>,
+
+    /// Every active entry, which are the tips of each history chain
+    pub entries: Vec<MetadataEntry>,
+}
+
+/// A Snapshot with a signature over its JSON encoding
+///
+/// The snapshot is kept as the exact JSON that was signed, since re-serializing a parsed
+/// copy isn't guaranteed to produce the same bytes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignedSnapshot {
+    /// The signed JSON encoding of the [`Snapshot`]
+    pub payload: String,
+
+    /// Public key of the signer
+    pub signer: DeviceId,
+
+    /// Hex encoded ed25519 signature
+    pub signature: String,
+}
+
+impl SignedSnapshot {
+    /// Sign a snapshot
+    pub fn sign(snapshot: &Snapshot, key: &SigningKey) -> Result<Self> {
+        let payload = serde_json::to_string(snapshot)?;
+        let signature = key.sign(payload.as_bytes());
+        Ok(Self {
+            payload,
+            signer: key.verifying_key().to_bytes(),
+            signature: hex::encode(signature.to_bytes()),
+        })
+    }
+
+    /// Parse the snapshot without verifying it, see [`verify_snapshot`]
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        Ok(serde_json::from_str(&self.payload)?)
+    }
+}
+
+/// Verify that a snapshot was signed by its signer and hasn't been modified since
+///
+/// This only proves who signed it, callers should also check that `signer` is a key they trust.
+///
+/// # Returns
+/// The verified snapshot
+#[allow(dead_code)]
+pub fn verify_snapshot(snapshot: &SignedSnapshot) -> Result<Snapshot> {
+    let key = VerifyingKey::from_bytes(&snapshot.signer)?;
+    let signature: [u8; 64] = hex::decode(&snapshot.signature)?
+        .try_into()
+        .map_err(|_| anyhow!("Signature has the wrong length"))?;
+
+    key.verify(
+        snapshot.payload.as_bytes(),
+        &Signature::from_bytes(&signature),
+    )
+    .map_err(|_| anyhow!("Snapshot signature is invalid"))?;
+    snapshot.snapshot()
+}
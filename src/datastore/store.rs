@@ -1,19 +1,143 @@
 use super::*;
-use anyhow::{anyhow, Context, Result};
-use data::{DataTable, PostgresDataTable};
-use data_handler::{DataLocation, DataTableHandler};
-use metadata::{MetadataTable, PostgresMetadataTable};
+use anyhow::{anyhow, bail, Context, Result};
+use audit::AuditRecord;
+use backup::{BackupRecord, HexWriter, BACKUP_VERSION};
+use chrono::{DateTime, Utc};
+use data::{DataTable, PostgresDataTable, SqliteDataTable};
+use data_handler::{DataCheck, DataLocation, DataTableHandler, GcStats};
+use ed25519_dalek::SigningKey;
+use events::{ChangeEvent, ChangeKind, EntryWatch, CHANGE_CHANNEL_CAPACITY};
+use metadata::{EntryReplaced, MetadataTable, PostgresMetadataTable, SqliteMetadataTable};
+use metrics::{Counters, StoreMetrics};
+use path::MetadataPath;
+use query::{id_created_at, validate_locale, Direction, MetadataQuery};
+use readonly::ReadOnlyDataStore;
 use schema::DeviceId;
-use schema::MetadataEntry;
+use schema::{DataAccess, MetadataEntry};
 use serde_json::Value;
-use settings::{Setting, SettingsTable};
-use sqlx::PgPool;
+use settings::{Setting, SettingsTable, StoreSettings};
+use snapshot::{SignedSnapshot, Snapshot};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::{PgPool, Row, SqlitePool};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::sync::broadcast;
+use tracing::{debug, instrument, warn};
 use uuid::Uuid;
+use validator::Validate;
 
 /// Constant key for the local path setting
 const SETTING_LOCAL_PATH: &str = "local_path";
 
+/// Key of the setting holding the default limit on the size of an entry's metadata
+const SETTING_MAX_METADATA_SIZE: &str = "max_metadata_size";
+
+/// Number of entries read, and written in one transaction, at a time while merging stores
+const MERGE_PAGE_SIZE: i64 = 100;
+
+/// What was copied by [`DataStore::merge_from`] or [`DataStore::import_backup`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergeStats {
+    /// Metadata entries copied
+    pub entries: usize,
+    /// Pieces of data copied
+    pub data: usize,
+}
+
+/// The metadata of a new entry is larger than the store allows
+///
+/// Returned when committing, before anything is written, so callers can find it with
+/// `error.downcast_ref::<EntryTooLarge>()`. See [`DataStore::set_max_metadata_size`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryTooLarge {
+    /// Id the entry would have been created with
+    pub id: Uuid,
+    /// Size of the metadata serialized as JSON, in bytes
+    pub size: usize,
+    /// The limit that was exceeded, in bytes
+    pub limit: usize,
+}
+
+impl std::fmt::Display for EntryTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Metadata of entry {} is {} bytes, larger than the limit of {} bytes",
+            self.id, self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for EntryTooLarge {}
+
+/// Concurrent updates of the same entry
+///
+/// Found with [`DataStore::find_conflicts`]. Each update stays active until one of them is
+/// replaced or archived.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    /// The entry that was updated more than once
+    pub parent_id: Uuid,
+    /// The updates, oldest first
+    pub entries: Vec<MetadataEntry>,
+}
+
+#[allow(dead_code)]
+impl Conflict {
+    /// Devices that wrote more than one of the conflicting updates
+    ///
+    /// A DataStore won't update an entry that was already replaced, so a device only forks
+    /// its own history when its key writes to two copies of the store that are later merged.
+    /// That's expected when a [`DataStore::fork`] is merged back. Otherwise the key is in use
+    /// on two machines at once, and the updates are worth reviewing.
+    pub fn equivocators(&self) -> Vec<DeviceId> {
+        let mut counts: BTreeMap<DeviceId, usize> = BTreeMap::new();
+        for entry in &self.entries {
+            *counts.entry(entry.device_id).or_default() += 1;
+        }
+        counts
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(device_id, _)| device_id)
+            .collect()
+    }
+}
+
+/// A problem found by [`DataStore::verify_integrity`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityViolation {
+    /// The data stored on this device for the entry doesn't match its hash
+    CorruptData { id: Uuid, hash: String },
+    /// The data for the entry should be stored on this device, but its file is gone
+    MissingData { id: Uuid, hash: String },
+}
+
+/// Summary of a store found in a database
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoreInfo {
+    /// Name of the store, as passed when opening it
+    pub name: String,
+    /// Number of entries, including archived ones
+    pub entries: i64,
+    /// Number of entries that aren't archived
+    pub active_entries: i64,
+    /// When the first entry was written, None if the store is empty
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl StoreInfo {
+    fn new(name: String, entries: i64, active_entries: i64, first_id: Option<Uuid>) -> Self {
+        Self {
+            name,
+            entries,
+            active_entries,
+            // Entry ids are UUIDv7, so they carry their creation time
+            created_at: first_id.and_then(id_created_at),
+        }
+    }
+}
+
 /// Data Store
 ///
 /// This is a logical set of data, with its own device id, metadata table,
@@ -28,6 +152,14 @@ pub struct DataStore<D: DataTable, M: MetadataTable> {
     metadata_table: M,
     /// Table for storing settings for this data store
     settings_table: SettingsTable<M>,
+    /// Notifies subscribers of newly committed entries
+    changes: broadcast::Sender<ChangeEvent>,
+    /// Locale used to order strings in queries, loaded from settings
+    collation: Option<String>,
+    /// Largest metadata allowed in new entries in bytes, loaded from settings
+    max_metadata_size: Option<usize>,
+    /// Counts of the work done by this store since it was opened
+    counters: Counters,
 }
 
 #[allow(dead_code)]
@@ -40,7 +172,8 @@ impl DataStore<PostgresDataTable, PostgresMetadataTable> {
     /// * `pool` - PostgreSQL connection pool
     /// * `name` - Name of this data store (used as table prefix)
     /// * `device_id` - Unique identifier for this device
-    /// * `local_path` - The local path to store data
+    /// * `local_path` - The local path to store data, each database uses its own directory in
+    ///   it so several databases can share one
     pub async fn init(
         pool: PgPool,
         name: &str,
@@ -49,17 +182,7 @@ impl DataStore<PostgresDataTable, PostgresMetadataTable> {
     ) -> Result<Self> {
         // Initialize the settings table
         let mut settings_table = SettingsTable::from_postgres(pool.clone(), device_id).await?;
-
-        // Create the local_path setting
-        let setting = Setting {
-            key: SETTING_LOCAL_PATH.to_string(),
-            value: Value::String(local_path.to_string_lossy().into_owned()),
-            description: Some("Local path for storing data".to_string()),
-        };
-        settings_table
-            .set_setting(setting)
-            .await
-            .context("Failed to set local_path in settings")?;
+        set_local_path(&mut settings_table, local_path).await?;
 
         // Proceed to create the DataStore using from_pool
         Self::from_pool(pool, name, device_id).await
@@ -76,123 +199,998 @@ impl DataStore<PostgresDataTable, PostgresMetadataTable> {
     pub async fn from_pool(pool: PgPool, name: &str, device_id: DeviceId) -> Result<Self> {
         // Initialize the settings table
         let settings_table = SettingsTable::from_postgres(pool.clone(), device_id).await?;
-
-        // Retrieve the local_path from settings
-        let local_path_setting = settings_table
-            .get_setting(SETTING_LOCAL_PATH)
-            .await
-            .context("Failed to retrieve local_path from settings")?
-            .ok_or_else(|| anyhow!("DataStore not initialized: 'local_path' not set"))?;
-
-        // Convert the setting value to PathBuf
-        let local_path = match local_path_setting.value {
-            Value::String(s) => PathBuf::from(s),
-            _ => return Err(anyhow!("local_path setting is not a string")),
-        };
+        let local_path = get_local_path(&settings_table).await?;
 
         // Create the other tables
         let metadata_table = PostgresMetadataTable::from_pool(pool.clone(), name).await?;
+        let collation = get_collation(&settings_table, name).await?;
+        let max_metadata_size = get_max_metadata_size(&settings_table, name).await?;
         let data_table = PostgresDataTable::from_pool(pool.clone()).await?;
-        let data_table = DataTableHandler::new(data_table, local_path);
+        let data_table = open_data_handler(data_table, local_path).await?;
+
+        Ok(Self {
+            device_id,
+            data_table,
+            metadata_table,
+            settings_table,
+            changes: broadcast::channel(CHANGE_CHANNEL_CAPACITY).0,
+            collation,
+            max_metadata_size,
+            counters: Counters::default(),
+        })
+    }
+
+    /// Copy this store into a new store called `name` in the database of `pool`
+    ///
+    /// See [`Self::fork_into`].
+    pub async fn fork(&self, pool: PgPool, name: &str) -> Result<Self> {
+        let mut fork = Self::from_pool(pool, name, self.device_id).await?;
+        self.fork_into(&mut fork).await?;
+        Ok(fork)
+    }
+
+    /// List the stores in a PostgreSQL database
+    ///
+    /// Any table shaped like a metadata table counts as a store, except the settings table.
+    pub async fn list_stores(pool: &PgPool) -> Result<Vec<StoreInfo>> {
+        let names: Vec<String> = sqlx::query_scalar(
+            r#"
+            SELECT table_name::TEXT
+            FROM information_schema.columns
+            WHERE table_schema = current_schema()
+                AND column_name IN ('id', 'parent_id', 'metadata', 'data_hash')
+            GROUP BY table_name
+            HAVING COUNT(*) = 4 AND table_name <> 'settings'
+            ORDER BY table_name
+            "#,
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let mut stores = Vec::with_capacity(names.len());
+        for name in names {
+            let row = sqlx::query(&format!(
+                r#"
+                SELECT
+                    COUNT(*) AS entries,
+                    COUNT(*) FILTER (WHERE NOT archived) AS active_entries,
+                    (SELECT id FROM {name} ORDER BY id LIMIT 1) AS first_id
+                FROM {name}
+                "#
+            ))
+            .fetch_one(pool)
+            .await?;
+            stores.push(StoreInfo::new(
+                name,
+                row.get("entries"),
+                row.get("active_entries"),
+                row.get("first_id"),
+            ));
+        }
+        Ok(stores)
+    }
+}
+
+#[allow(dead_code)]
+impl DataStore<SqliteDataTable, SqliteMetadataTable> {
+    /// Initialize a SQLite backed DataStore by setting the local_path in settings.
+    ///
+    /// This function should be called once to set up the initial settings.
+    ///
+    /// # Arguments
+    /// * `pool` - SQLite connection pool, see [`connect_sqlite`]
+    /// * `name` - Name of this data store (used as table prefix)
+    /// * `device_id` - Unique identifier for this device
+    /// * `local_path` - The local path to store data, each database uses its own directory in
+    ///   it so several databases can share one
+    pub async fn init_sqlite(
+        pool: SqlitePool,
+        name: &str,
+        device_id: DeviceId,
+        local_path: PathBuf,
+    ) -> Result<Self> {
+        let mut settings_table = SettingsTable::from_sqlite(pool.clone(), device_id).await?;
+        set_local_path(&mut settings_table, local_path).await?;
+
+        Self::from_sqlite(pool, name, device_id).await
+    }
+
+    /// Create a new DataStore from a SQLite connection pool
+    ///
+    /// This function requires that the "local_path" is already set in settings.
+    ///
+    /// # Arguments
+    /// * `pool` - SQLite connection pool, see [`connect_sqlite`]
+    /// * `name` - Name of this data store (used as table prefix)
+    /// * `device_id` - Unique identifier for this device
+    pub async fn from_sqlite(pool: SqlitePool, name: &str, device_id: DeviceId) -> Result<Self> {
+        let settings_table = SettingsTable::from_sqlite(pool.clone(), device_id).await?;
+        let local_path = get_local_path(&settings_table).await?;
+
+        let metadata_table = SqliteMetadataTable::from_pool(pool.clone(), name).await?;
+        let collation = get_collation(&settings_table, name).await?;
+        let max_metadata_size = get_max_metadata_size(&settings_table, name).await?;
+        let data_table = SqliteDataTable::from_pool(pool).await?;
+        let data_table = open_data_handler(data_table, local_path).await?;
 
         Ok(Self {
             device_id,
             data_table,
             metadata_table,
             settings_table,
+            changes: broadcast::channel(CHANGE_CHANNEL_CAPACITY).0,
+            collation,
+            max_metadata_size,
+            counters: Counters::default(),
         })
     }
+
+    /// Copy this store into a new store called `name` in the database of `pool`
+    ///
+    /// See [`Self::fork_into`].
+    pub async fn fork_sqlite(&self, pool: SqlitePool, name: &str) -> Result<Self> {
+        let mut fork = Self::from_sqlite(pool, name, self.device_id).await?;
+        self.fork_into(&mut fork).await?;
+        Ok(fork)
+    }
+
+    /// List the stores in a SQLite database
+    ///
+    /// Any table shaped like a metadata table counts as a store, except the settings table.
+    pub async fn list_sqlite_stores(pool: &SqlitePool) -> Result<Vec<StoreInfo>> {
+        let names: Vec<String> = sqlx::query_scalar(
+            r#"
+            SELECT m.name
+            FROM sqlite_master AS m, pragma_table_info(m.name) AS p
+            WHERE m.type = 'table'
+                AND p.name IN ('id', 'parent_id', 'metadata', 'data_hash')
+            GROUP BY m.name
+            HAVING COUNT(*) = 4 AND m.name <> 'settings'
+            ORDER BY m.name
+            "#,
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let mut stores = Vec::with_capacity(names.len());
+        for name in names {
+            let row = sqlx::query(&format!(
+                r#"
+                SELECT
+                    COUNT(*) AS entries,
+                    COUNT(*) FILTER (WHERE NOT archived) AS active_entries,
+                    (SELECT id FROM "{name}" ORDER BY id LIMIT 1) AS first_id
+                FROM "{name}"
+                "#
+            ))
+            .fetch_one(pool)
+            .await?;
+            stores.push(StoreInfo::new(
+                name,
+                row.get("entries"),
+                row.get("active_entries"),
+                row.get("first_id"),
+            ));
+        }
+        Ok(stores)
+    }
+}
+
+/// Sort entries into the canonical order shared by every copy of a store
+///
+/// Entries are ordered by id, which is creation time, except that an entry always comes
+/// after its parent when both are given. That holds even if the device that wrote the child
+/// had a clock behind the parent's, so every device displays the same entries identically.
+pub fn canonical_order(entries: Vec<MetadataEntry>) -> Vec<MetadataEntry> {
+    let ids: HashSet<Uuid> = entries.iter().map(|entry| entry.id).collect();
+    let mut children: HashMap<Uuid, Vec<MetadataEntry>> = HashMap::new();
+    let mut ready = BTreeMap::new();
+    for entry in entries {
+        match entry.parent_id.filter(|parent| ids.contains(parent)) {
+            Some(parent) => children.entry(parent).or_default().push(entry),
+            None => {
+                ready.insert(entry.id, entry);
+            }
+        }
+    }
+
+    // Always take the lowest id whose parent has already been placed
+    let mut ordered = Vec::with_capacity(ids.len());
+    while let Some((id, entry)) = ready.pop_first() {
+        ordered.push(entry);
+        for child in children.remove(&id).unwrap_or_default() {
+            ready.insert(child.id, child);
+        }
+    }
+    ordered
+}
+
+/// Entries copied from another copy of a store, queued in an order the metadata table accepts
+///
+/// Entries are read in id order, but a child written on a device whose clock was behind has
+/// a lower id than its parent, possibly in an earlier page. The metadata table won't store an
+/// entry before its parent, so such a child is held back until its parent is queued.
+#[derive(Default)]
+struct CopyQueue {
+    ready: Vec<MetadataEntry>,
+    /// Ids of the entries in `ready`
+    queued: HashSet<Uuid>,
+    /// Held back entries by the id of their missing parent
+    waiting: HashMap<Uuid, Vec<MetadataEntry>>,
+}
+
+impl CopyQueue {
+    /// Queue an entry, or hold it back if its parent isn't in `table` or queued yet
+    async fn push<M: MetadataTable>(&mut self, table: &M, entry: MetadataEntry) -> Result<()> {
+        if let Some(parent_id) = entry.parent_id {
+            if !self.queued.contains(&parent_id) && table.get_entry(parent_id).await?.is_none() {
+                self.waiting.entry(parent_id).or_default().push(entry);
+                return Ok(());
+            }
+        }
+
+        // Queueing an entry releases everything that was waiting for it
+        let mut next = vec![entry];
+        while let Some(entry) = next.pop() {
+            next.extend(self.waiting.remove(&entry.id).unwrap_or_default());
+            self.queued.insert(entry.id);
+            self.ready.push(entry);
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.ready.len()
+    }
+
+    /// Take the queued entries, every parent comes before its children
+    fn take(&mut self) -> Vec<MetadataEntry> {
+        self.queued.clear();
+        std::mem::take(&mut self.ready)
+    }
+
+    /// Take the last queued entries, failing if any entry never got its parent
+    fn finish(mut self) -> Result<Vec<MetadataEntry>> {
+        if let Some((parent_id, children)) = self.waiting.iter().next() {
+            bail!(
+                "Entry {} can't be copied without its parent {}",
+                children[0].id,
+                parent_id
+            );
+        }
+        Ok(self.take())
+    }
+}
+
+/// Pages through the metadata entries of a DataStore, oldest first
+///
+/// Created with [`DataStore::iter_entries`] or [`DataStore::iter_entries_since`].
+pub struct EntryIter<'a, D: DataTable, M: MetadataTable> {
+    store: &'a DataStore<D, M>,
+    /// Id of the last entry fetched, the next page starts after it
+    after: Option<Uuid>,
+    page_size: i64,
+    include_archived: bool,
+    page: std::vec::IntoIter<MetadataEntry>,
+    done: bool,
+}
+
+#[allow(dead_code)]
+impl<'a, D: DataTable, M: MetadataTable> EntryIter<'a, D, M> {
+    fn new(
+        store: &'a DataStore<D, M>,
+        after: Option<Uuid>,
+        page_size: i64,
+        include_archived: bool,
+    ) -> Self {
+        Self {
+            store,
+            after,
+            page_size: page_size.max(1),
+            include_archived,
+            page: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+
+    /// Get the next entry, fetching a new page when the current one runs out
+    ///
+    /// Returns `None` once every entry has been returned.
+    pub async fn next(&mut self) -> Result<Option<MetadataEntry>> {
+        if let Some(entry) = self.page.next() {
+            return Ok(Some(entry));
+        }
+        if self.done {
+            return Ok(None);
+        }
+
+        let page = self
+            .store
+            .metadata_table
+            .get_entries_after(self.after, self.page_size, self.include_archived)
+            .await?;
+
+        // A short page means there is nothing left to fetch
+        self.done = (page.len() as i64) < self.page_size;
+        self.after = page.last().map(|entry| entry.id).or(self.after);
+        self.page = page.into_iter();
+
+        Ok(self.page.next())
+    }
+}
+
+/// Open a SQLite connection pool suitable for a DataStore
+///
+/// The database file is created if it doesn't exist, and every connection has
+/// the REGEXP function loaded for metadata queries.
+/// In-memory databases are limited to a single connection that is never closed,
+/// since each connection would otherwise see its own empty database.
+pub async fn connect_sqlite(url: &str) -> Result<SqlitePool> {
+    let options = SqliteConnectOptions::from_str(url)?
+        .create_if_missing(true)
+        .foreign_keys(true)
+        .with_regexp();
+
+    let mut pool_options = SqlitePoolOptions::new();
+    if url.contains(":memory:") || url.contains("mode=memory") {
+        pool_options = pool_options
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None);
+    }
+
+    Ok(pool_options.connect_with(options).await?)
+}
+
+/// Store the local_path setting for a new DataStore
+async fn set_local_path<M: MetadataTable>(
+    settings_table: &mut SettingsTable<M>,
+    local_path: PathBuf,
+) -> Result<()> {
+    let setting = Setting {
+        key: SETTING_LOCAL_PATH.to_string(),
+        value: Value::String(local_path.to_string_lossy().into_owned()),
+        description: Some("Local path for storing data".to_string()),
+    };
+    settings_table
+        .set_setting(setting)
+        .await
+        .context("Failed to set local_path in settings")
+}
+
+/// Name of the setting holding the [`StoreSettings`] of a store
+fn store_settings_key(name: &str) -> String {
+    format!("store_{}", name)
+}
+
+/// Name of the setting holding the collation of a store
+fn collation_key(name: &str) -> String {
+    format!("collation_{}", name)
+}
+
+/// Read the collation setting of a store, if one is set
+async fn get_collation<M: MetadataTable>(
+    settings_table: &SettingsTable<M>,
+    name: &str,
+) -> Result<Option<String>> {
+    let setting = settings_table
+        .get_setting(&collation_key(name))
+        .await
+        .context("Failed to retrieve collation from settings")?;
+    match setting.map(|setting| setting.value) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(locale)) => Ok(Some(locale)),
+        Some(_) => Err(anyhow!("collation setting is not a string")),
+    }
+}
+
+/// Name of the setting holding the metadata size limit of a store
+fn max_metadata_size_key(name: &str) -> String {
+    format!("{}_{}", SETTING_MAX_METADATA_SIZE, name)
+}
+
+/// Read the metadata size limit of a store, falling back to the default for all stores
+async fn get_max_metadata_size<M: MetadataTable>(
+    settings_table: &SettingsTable<M>,
+    name: &str,
+) -> Result<Option<usize>> {
+    for key in [
+        max_metadata_size_key(name),
+        SETTING_MAX_METADATA_SIZE.to_string(),
+    ] {
+        let setting = settings_table
+            .get_setting(&key)
+            .await
+            .context("Failed to retrieve max_metadata_size from settings")?;
+        match setting.map(|setting| setting.value) {
+            None | Some(Value::Null) => continue,
+            Some(value) => {
+                let limit = value
+                    .as_u64()
+                    .ok_or_else(|| anyhow!("{} setting is not a positive integer", key))?;
+                return Ok(Some(limit as usize));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Manage the data of a database in its own directory under `local_path`
+///
+/// Files are named by hash, so databases sharing a directory would remove each other's
+/// files during gc. Data stored directly in `local_path` by older versions is still read.
+async fn open_data_handler<D: DataTable>(
+    data_table: D,
+    local_path: PathBuf,
+) -> Result<DataTableHandler<D>> {
+    let database_id = data_table.database_id().await?;
+    Ok(
+        DataTableHandler::new(data_table, local_path.join(database_id.to_string()))
+            .with_legacy_path(local_path),
+    )
+}
+
+/// Read the local_path setting of an initialized DataStore
+async fn get_local_path<M: MetadataTable>(settings_table: &SettingsTable<M>) -> Result<PathBuf> {
+    let local_path_setting = settings_table
+        .get_setting(SETTING_LOCAL_PATH)
+        .await
+        .context("Failed to retrieve local_path from settings")?
+        .ok_or_else(|| anyhow!("DataStore not initialized: 'local_path' not set"))?;
+
+    // Convert the setting value to PathBuf
+    match local_path_setting.value {
+        Value::String(s) => Ok(PathBuf::from(s)),
+        _ => Err(anyhow!("local_path setting is not a string")),
+    }
 }
 
 #[allow(dead_code)]
 impl<D: DataTable, M: MetadataTable> DataStore<D, M> {
     /// Store a new piece of data
     ///
+    /// With a parent this stores a new version of that entry, only if it hasn't been replaced
+    /// since it was read. If another writer replaced or archived the parent first, this fails
+    /// with [`EntryReplaced`] and nothing is written, so the caller can re-read the latest
+    /// version and retry. Updates never fork the history of an entry.
+    ///
     /// # Arguments
     /// * `data` - The raw data to store
     /// * `metadata` - JSON metadata about the data (type, store name, etc)
-    /// * `parent_id` - Optional parent entry this is updating
+    /// * `parent_id` - Optional parent entry this is updating, which must still be active
     ///
     /// # Returns
-    /// The UUID of the newly created entry
+    /// The UUID of the newly created entry, or [`EntryReplaced`] if the parent was already
+    /// replaced or archived
     pub async fn store_data(
         &mut self,
         data: DataLocation,
         metadata: Value,
         parent_id: Option<Uuid>,
     ) -> Result<Uuid> {
+        let mut entry = self.new_entry(String::new(), metadata, parent_id);
+        self.check_entry_size(&entry)?;
         // Insert data, acquiring it from the DataLocation
-        let entry = self.data_table.copy_file(data).await?;
-        let hash = entry.hash.clone();
+        entry.data_hash = self.data_table.copy_file(data).await?.hash;
+        self.insert_metadata(entry).await
+    }
+
+    /// Store a new piece of data read from a stream
+    ///
+    /// The data is never fully loaded into memory, see [`Self::set_ingest_buffer_size`].
+    /// Updates are checked the same way as in [`Self::store_data`].
+    ///
+    /// # Arguments
+    /// * `reader` - Source of the raw data to store
+    /// * `metadata` - JSON metadata about the data (type, store name, etc)
+    /// * `parent_id` - Optional parent entry this is updating, which must still be active
+    ///
+    /// # Returns
+    /// The UUID of the newly created entry, or [`EntryReplaced`] if the parent was already
+    /// replaced or archived
+    pub async fn store_reader<R: std::io::Read>(
+        &mut self,
+        reader: R,
+        metadata: Value,
+        parent_id: Option<Uuid>,
+    ) -> Result<Uuid> {
+        let mut entry = self.new_entry(String::new(), metadata, parent_id);
+        self.check_entry_size(&entry)?;
+        entry.data_hash = self.data_table.put_reader(reader).await?.hash;
+        self.insert_metadata(entry).await
+    }
+
+    /// Write the data of an entry into `writer`
+    ///
+    /// Only works for data available on this device.
+    ///
+    /// # Arguments
+    /// * `id` - UUID of the entry to read
+    /// * `writer` - Destination for the raw data
+    ///
+    /// # Returns
+    /// The number of bytes written
+    pub async fn read_data<W: std::io::Write>(&self, id: Uuid, writer: &mut W) -> Result<u64> {
+        let entry = self
+            .metadata_table
+            .get_entry(id)
+            .await?
+            .context("Not found")?;
+        let written = self.data_table.get_writer(&entry.data_hash, writer).await?;
+        Counters::add(&self.counters.bytes_read, written);
+        Ok(written)
+    }
+
+    /// Create the metadata entry for newly stored data and mark the data as needed
+    ///
+    /// Fails with [`EntryReplaced`] if the parent was already replaced or archived.
+    async fn insert_metadata(&mut self, entry: MetadataEntry) -> Result<Uuid> {
+        let id = entry.id;
+        self.commit_if_current(entry, false).await?;
 
-        // Create a MetadataEntry with the generated hash and provided metadata
-        let entry = MetadataEntry {
+        // Return the UUID of the newly created entry
+        Ok(id)
+    }
+
+    /// Build a new entry written by this device
+    fn new_entry(&self, hash: String, metadata: Value, parent_id: Option<Uuid>) -> MetadataEntry {
+        MetadataEntry {
             id: Uuid::now_v7(),
             device_id: self.device_id,
             archived: false,
             local: true, // Assuming the data is stored locally on creation
             parent_id,
             metadata,
-            data_hash: entry.hash,
+            data_hash: hash,
+        }
+    }
+
+    /// Fail if the metadata of a new entry is over the store's size limit
+    fn check_entry_size(&self, entry: &MetadataEntry) -> Result<()> {
+        let Some(limit) = self.max_metadata_size else {
+            return Ok(());
         };
-        let id = entry.id;
+        let size = serde_json::to_vec(&entry.metadata)?.len();
+        if size > limit {
+            return Err(EntryTooLarge {
+                id: entry.id,
+                size,
+                limit,
+            }
+            .into());
+        }
+        Ok(())
+    }
 
-        // Insert the MetadataEntry into the metadata table
-        self.metadata_table.create_entry(entry).await?;
+    /// Insert new entries in one transaction and mark their data as needed
+    ///
+    /// Fails with [`EntryReplaced`] if any parent was already replaced or archived.
+    #[instrument(level = "debug", skip_all, fields(store = self.metadata_table.table_name(), count = entries.len()))]
+    async fn commit_entries(&mut self, entries: Vec<MetadataEntry>) -> Result<()> {
+        for entry in &entries {
+            self.check_entry_size(entry)?;
+        }
+        let events: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.id,
+                    entry.parent_id,
+                    entry.metadata.clone(),
+                    entry.data_hash.clone(),
+                )
+            })
+            .collect();
 
-        // Now increment the ref_count
-        self.data_table.set_local_needed(&hash).await?;
+        // Count the references first, so the data can't be collected once the entries exist
+        let hashes: Vec<_> = events.iter().map(|(.., hash)| hash.clone()).collect();
+        self.data_table.set_many_local_needed(&hashes).await?;
+        if let Err(e) = self.metadata_table.create_entries_if_current(entries).await {
+            self.release_data(&hashes).await;
+            return Err(e);
+        }
 
-        // Return the UUID of the newly created entry
-        Ok(id)
+        for (id, parent_id, metadata, _) in events {
+            Counters::add(&self.counters.entries_committed, 1);
+            debug!(%id, ?parent_id, "Committed entry");
+            self.notify(id, parent_id, metadata, ChangeKind::Created);
+        }
+        Ok(())
     }
 
-    /// Get a copy of all the active metadata entries.
+    /// Insert a new entry if its parent is still active and mark its data as needed
     ///
-    /// Not the data, that is queried only individually.
-    pub async fn get_active_entries(&self) -> Result<Vec<MetadataEntry>> {
-        self.metadata_table.get_active_entries().await
+    /// If `replaces_archive` the parent is an archive entry, which is archived from the
+    /// start, so the entry is only inserted if nothing else replaced the archive yet.
+    #[instrument(level = "debug", skip_all, fields(store = self.metadata_table.table_name(), id = %entry.id, parent_id = ?entry.parent_id))]
+    async fn commit_if_current(
+        &mut self,
+        entry: MetadataEntry,
+        replaces_archive: bool,
+    ) -> Result<()> {
+        self.check_entry_size(&entry)?;
+        let (id, parent_id, metadata, hash) = (
+            entry.id,
+            entry.parent_id,
+            entry.metadata.clone(),
+            entry.data_hash.clone(),
+        );
+        self.data_table.set_local_needed(&hash).await?;
+        let created = if replaces_archive {
+            self.metadata_table.create_entry_if_leaf(entry).await
+        } else {
+            self.metadata_table.create_entry_if_current(entry).await
+        };
+        if let Err(e) = created {
+            self.release_data(&[hash]).await;
+            return Err(e);
+        }
+        Counters::add(&self.counters.entries_committed, 1);
+        debug!("Committed entry");
+        self.notify(id, parent_id, metadata, ChangeKind::Created);
+        Ok(())
     }
 
-    /// Get a copy of all the archived metadata entries.
-    pub async fn get_archived_entries(&self) -> Result<Vec<MetadataEntry>> {
-        self.metadata_table.get_archived_entries().await
+    /// Give back the references taken for entries that failed to insert
+    ///
+    /// Errors are only logged, a count left too high just keeps the data from [`Self::gc`].
+    async fn release_data(&mut self, hashes: &[String]) {
+        for hash in hashes {
+            if let Err(e) = self.data_table.set_local_not_needed(hash).await {
+                warn!(%hash, "Failed to release data: {e}");
+            }
+        }
     }
 
-    /// Get the history of a piece of data by following its parent chain
+    /// Store many pieces of data, creating all their entries in a single transaction
     ///
-    /// Returns a vector of MetadataEntry objects in descending order (newest to oldest).
-    /// Each entry contains:
-    /// - id: The unique identifier for this version
-    /// - device_id: The device that created this version
-    /// - archived: Whether this version is archived
-    /// - parent_id: Reference to the previous version
-    /// - metadata: User-defined metadata for this version
-    /// - data_hash: Reference to the underlying data
+    /// Much faster than repeated calls to [`Self::store_data`] when importing. If creating
+    /// the entries fails no entry is created, and the copied data is left for [`Self::gc`].
     ///
     /// # Arguments
-    /// * `id` - UUID of the entry to get history for
+    /// * `items` - The data to store and its metadata
     ///
     /// # Returns
-    /// Vector of MetadataEntry in descending order (newest to oldest)
-    pub async fn get_history(&self, id: Uuid) -> Result<Vec<MetadataEntry>> {
-        // Use the metadata table's recursive query capability to get the full history
-        self.metadata_table.get_entry_history(id).await
+    /// The UUIDs of the new entries, in the same order as `items`
+    pub async fn store_many<I>(&mut self, items: I) -> Result<Vec<Uuid>>
+    where
+        I: IntoIterator<Item = (DataLocation, Value)>,
+    {
+        let mut entries = Vec::new();
+        let mut locations = Vec::new();
+        for (data, metadata) in items {
+            let entry = self.new_entry(String::new(), metadata, None);
+            self.check_entry_size(&entry)?;
+            entries.push(entry);
+            locations.push(data);
+        }
+        for (entry, data) in entries.iter_mut().zip(locations) {
+            entry.data_hash = self.data_table.copy_file(data).await?.hash;
+        }
+        let ids = entries.iter().map(|entry| entry.id).collect();
+        self.commit_entries(entries).await?;
+        Ok(ids)
     }
 
-    /// Mark a piece of data as archived/deleted by creating a new entry that archives the old one
+    /// Update the metadata of every active entry matching a query, in a single transaction
+    ///
+    /// Each match is replaced by a new entry with the same data, whose metadata is the old
+    /// metadata after `update` has modified it. Archived entries never match. If another
+    /// writer replaces a match first, this fails with [`EntryReplaced`] and nothing is updated.
     ///
     /// # Arguments
-    /// * `id` - UUID of the entry to archive
-    pub async fn archive(&mut self, id: Uuid) -> Result<()> {
-        // First check if the entry exists and get its metadata
-        let existing_entry = self
-            .metadata_table
-            .get_entry(id)
-            .await?
-            .context("Not found")?;
-
-        // Create a new metadata entry that marks this as archived
+    /// * `query` - Selects the entries to update, its limit and ordering are respected
+    /// * `update` - Modifies the metadata of each entry in place
+    ///
+    /// # Returns
+    /// The UUIDs of the new entries, in query order
+    pub async fn update_where<F>(
+        &mut self,
+        query: &MetadataQuery,
+        mut update: F,
+    ) -> Result<Vec<Uuid>>
+    where
+        F: FnMut(&mut Value),
+    {
+        let query = query.clone().include_archived(false);
+        let mut entries = self.metadata_table.query(&query).await?;
+        for entry in &mut entries {
+            update(&mut entry.metadata);
+            *entry = MetadataEntry {
+                local: entry.local,
+                ..self.new_entry(
+                    std::mem::take(&mut entry.data_hash),
+                    std::mem::take(&mut entry.metadata),
+                    Some(entry.id),
+                )
+            };
+        }
+        let ids = entries.iter().map(|entry| entry.id).collect();
+        self.commit_entries(entries).await?;
+        Ok(ids)
+    }
+
+    /// Replace an entry with a modified copy of its metadata
+    ///
+    /// Reads the entry, lets `modify` change its metadata, and writes the result as a new
+    /// entry with the same data. Returning an error from `modify` leaves the entry unchanged.
+    /// Fails with [`EntryReplaced`] if the entry has already been replaced or archived,
+    /// including by a concurrent writer.
+    ///
+    /// # Arguments
+    /// * `id` - UUID of the entry to modify
+    /// * `modify` - Modifies the metadata in place
+    ///
+    /// # Returns
+    /// The UUID of the new entry
+    pub async fn modify<F>(&mut self, id: Uuid, modify: F) -> Result<Uuid>
+    where
+        F: FnOnce(&mut Value) -> Result<()>,
+    {
+        let entry = self
+            .metadata_table
+            .get_entry(id)
+            .await?
+            .context("Not found")?;
+        if entry.archived {
+            return Err(EntryReplaced { id }.into());
+        }
+
+        let mut metadata = entry.metadata;
+        modify(&mut metadata)?;
+        let new_entry = MetadataEntry {
+            local: entry.local,
+            ..self.new_entry(entry.data_hash, metadata, Some(id))
+        };
+        let new_id = new_entry.id;
+        self.commit_if_current(new_entry, false).await?;
+        Ok(new_id)
+    }
+
+    /// Replace an entry with a copy where the value at `path` has been modified
+    ///
+    /// `modify` receives the current value, or None if there isn't one, and returns the new
+    /// value. See [`Self::modify`].
+    ///
+    /// # Arguments
+    /// * `id` - UUID of the entry to modify
+    /// * `path` - Location of the value in the metadata, can't contain wildcards
+    /// * `modify` - Computes the new value from the current one
+    pub async fn modify_value<F>(
+        &mut self,
+        id: Uuid,
+        path: &MetadataPath,
+        modify: F,
+    ) -> Result<Uuid>
+    where
+        F: FnOnce(Option<&Value>) -> Value,
+    {
+        self.modify(id, |metadata| {
+            let value = modify(path.get(metadata).first().copied());
+            path.set(metadata, value)
+        })
+        .await
+    }
+
+    /// Undo the change made by an entry
+    ///
+    /// A new entry is committed that restores the entry's parent, with the parent's data
+    /// and metadata. Reverting an archive restores the archived entry, and reverting an
+    /// entry without a parent archives it. Reverting a revert redoes the original change,
+    /// which for a restored archive archives the entry again.
+    ///
+    /// Only the latest change to an entry can be reverted, otherwise this fails with
+    /// [`EntryReplaced`].
+    ///
+    /// # Arguments
+    /// * `id` - UUID of the entry whose change to undo
+    ///
+    /// # Returns
+    /// The UUID of the new entry, or of the archive entry if `id` had no parent
+    pub async fn revert(&mut self, id: Uuid) -> Result<Uuid> {
+        let entry = self
+            .metadata_table
+            .get_entry(id)
+            .await?
+            .context("Not found")?;
+        let is_archive = entry.archived && entry.data_hash.is_empty();
+        if entry.archived && !is_archive {
+            return Err(EntryReplaced { id }.into());
+        }
+
+        let Some(parent_id) = entry.parent_id else {
+            return self.archive(id).await;
+        };
+        let parent = self
+            .metadata_table
+            .get_entry(parent_id)
+            .await?
+            .context("Parent entry not found")?;
+
+        // The entry restored an archived entry, so undoing it archives the entry again
+        if parent.archived && parent.data_hash.is_empty() {
+            return self.archive(id).await;
+        }
+
+        let restored = MetadataEntry {
+            local: parent.local,
+            ..self.new_entry(parent.data_hash, parent.metadata, Some(id))
+        };
+        let restored_id = restored.id;
+        self.commit_if_current(restored, is_archive).await?;
+        Ok(restored_id)
+    }
+
+    /// Undo the latest change made by this device
+    ///
+    /// See [`Self::revert`]. Calling this again undoes the undo.
+    ///
+    /// # Returns
+    /// The UUID of the new entry, or None if this device hasn't changed anything
+    pub async fn undo_last(&mut self) -> Result<Option<Uuid>> {
+        let query = MetadataQuery::new()
+            .device(self.device_id)
+            .include_archived(true)
+            .order_by_id(Direction::Descending)
+            .limit(1);
+        match self.metadata_table.query(&query).await?.first() {
+            Some(entry) => Ok(Some(self.revert(entry.id).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Subscribe to the entries committed to this store
+    ///
+    /// Events are only sent for changes made through this DataStore, not by other
+    /// processes sharing the same database.
+    pub fn subscribe(&self) -> broadcast::Receiver<ChangeEvent> {
+        self.changes.subscribe()
+    }
+
+    /// Watch a single entry for updates
+    ///
+    /// The watch follows the entry's history, so after an update it tracks the new entry.
+    ///
+    /// # Arguments
+    /// * `id` - UUID of the entry to watch, normally the latest in its history
+    pub fn watch(&self, id: Uuid) -> EntryWatch {
+        EntryWatch::new(self.subscribe(), id)
+    }
+
+    /// Send a ChangeEvent to any subscribers
+    fn notify(&self, id: Uuid, parent_id: Option<Uuid>, metadata: Value, kind: ChangeKind) {
+        // Sending only fails when nobody is subscribed
+        let _ = self.changes.send(ChangeEvent {
+            store: self.metadata_table.table_name().to_string(),
+            id,
+            parent_id,
+            metadata,
+            kind,
+        });
+    }
+
+    /// Get a copy of all the active metadata entries.
+    ///
+    /// Not the data, that is queried only individually.
+    pub async fn get_active_entries(&self) -> Result<Vec<MetadataEntry>> {
+        self.metadata_table.get_active_entries().await
+    }
+
+    /// Iterate over the metadata entries, oldest first, one page at a time
+    ///
+    /// Unlike [`Self::get_active_entries`] this never loads more than `page_size` entries.
+    ///
+    /// # Arguments
+    /// * `page_size` - Number of entries fetched from the database at once
+    /// * `include_archived` - Whether to include archived entries
+    pub fn iter_entries(&self, page_size: i64, include_archived: bool) -> EntryIter<'_, D, M> {
+        EntryIter::new(self, None, page_size, include_archived)
+    }
+
+    /// Iterate over the metadata entries created after the entry `id`, oldest first
+    ///
+    /// # Arguments
+    /// * `id` - UUID of the last entry already seen
+    /// * `page_size` - Number of entries fetched from the database at once
+    /// * `include_archived` - Whether to include archived entries
+    pub fn iter_entries_since(
+        &self,
+        id: Uuid,
+        page_size: i64,
+        include_archived: bool,
+    ) -> EntryIter<'_, D, M> {
+        EntryIter::new(self, Some(id), page_size, include_archived)
+    }
+
+    /// Find the entries that were updated more than once concurrently
+    ///
+    /// This happens when two copies of a store update the same entry and are merged, see
+    /// [`Self::merge_from`]. Resolve a conflict by replacing or archiving the extra updates.
+    ///
+    /// # Returns
+    /// The conflicts ordered by the id of the updated entry
+    pub async fn find_conflicts(&self) -> Result<Vec<Conflict>> {
+        let mut conflicts: Vec<Conflict> = Vec::new();
+        for entry in self.metadata_table.get_forked_entries().await? {
+            let parent_id = entry.parent_id.context("Forked entry has no parent")?;
+            match conflicts.last_mut() {
+                Some(conflict) if conflict.parent_id == parent_id => conflict.entries.push(entry),
+                _ => conflicts.push(Conflict {
+                    parent_id,
+                    entries: vec![entry],
+                }),
+            }
+        }
+        Ok(conflicts)
+    }
+
+    /// Put entries into the canonical order shared by every copy of this store
+    ///
+    /// Use this to display entries from concurrent updates the same way on every device.
+    /// See [`canonical_order`] for the ordering.
+    ///
+    /// # Arguments
+    /// * `ids` - UUIDs of the entries to order
+    pub async fn order_entries(&self, ids: &[Uuid]) -> Result<Vec<Uuid>> {
+        let mut entries = Vec::with_capacity(ids.len());
+        for id in ids {
+            let entry = self
+                .metadata_table
+                .get_entry(*id)
+                .await?
+                .with_context(|| format!("Entry {} not found", id))?;
+            entries.push(entry);
+        }
+        Ok(canonical_order(entries)
+            .into_iter()
+            .map(|entry| entry.id)
+            .collect())
+    }
+
+    /// Get a copy of all the archived metadata entries.
+    pub async fn get_archived_entries(&self) -> Result<Vec<MetadataEntry>> {
+        self.metadata_table.get_archived_entries().await
+    }
+
+    /// Get the history of a piece of data by following its parent chain
+    ///
+    /// Returns a vector of MetadataEntry objects in descending order (newest to oldest).
+    /// Each entry contains:
+    /// - id: The unique identifier for this version
+    /// - device_id: The device that created this version
+    /// - archived: Whether this version is archived
+    /// - parent_id: Reference to the previous version
+    /// - metadata: User-defined metadata for this version
+    /// - data_hash: Reference to the underlying data
+    ///
+    /// # Arguments
+    /// * `id` - UUID of the entry to get history for
+    ///
+    /// # Returns
+    /// Vector of MetadataEntry in descending order (newest to oldest)
+    pub async fn get_history(&self, id: Uuid) -> Result<Vec<MetadataEntry>> {
+        // Use the metadata table's recursive query capability to get the full history
+        self.metadata_table.get_entry_history(id).await
+    }
+
+    /// Mark a piece of data as archived/deleted by creating a new entry that archives the old one
+    ///
+    /// # Arguments
+    /// * `id` - UUID of the entry to archive, which must still be active
+    ///
+    /// # Returns
+    /// The UUID of the archive entry, or [`EntryReplaced`] if the entry was already replaced
+    /// or archived
+    #[instrument(level = "debug", skip(self), fields(store = self.metadata_table.table_name()))]
+    pub async fn archive(&mut self, id: Uuid) -> Result<Uuid> {
+        // First check if the entry exists and get its metadata
+        let existing_entry = self
+            .metadata_table
+            .get_entry(id)
+            .await?
+            .context("Not found")?;
+
+        // Create a new metadata entry that marks this as archived
         // TODO: I'm not certain this is the right new entry, leaving for now
         let archive_entry = MetadataEntry {
             id: Uuid::now_v7(),
@@ -212,16 +1210,24 @@ impl<D: DataTable, M: MetadataTable> DataStore<D, M> {
             local: false,
         };
 
-        // Create the new entry - this will automatically mark the parent as archived
-        self.metadata_table.create_entry(archive_entry).await?;
+        // Create the new entry - this also marks the parent as archived, unless another
+        // writer replaced or archived it first
+        let archive_id = archive_entry.id;
+        let metadata = archive_entry.metadata.clone();
+        self.metadata_table
+            .create_entry_if_current(archive_entry)
+            .await?;
+        Counters::add(&self.counters.entries_archived, 1);
+        debug!(%archive_id, "Archived entry");
+        self.notify(archive_id, Some(id), metadata, ChangeKind::Archived);
 
-        Ok(())
+        Ok(archive_id)
     }
 
     /// Query active entries by metadata conditions
     ///
     /// # Arguments
-    /// * `conditions` - JSON metadata conditions to match against
+    /// * `conditions` - JSON metadata conditions to match against, keyed by metadata path
     /// * `include_archived` - Whether to include archived entries
     ///
     /// # Returns
@@ -231,6 +1237,7 @@ impl<D: DataTable, M: MetadataTable> DataStore<D, M> {
         conditions: &Value,
         include_archived: bool,
     ) -> Result<Vec<(Uuid, Value)>> {
+        Counters::add(&self.counters.queries, 1);
         // Use the metadata table's query capability
         let entries = self
             .metadata_table
@@ -244,6 +1251,182 @@ impl<D: DataTable, M: MetadataTable> DataStore<D, M> {
             .collect())
     }
 
+    /// Get the id of the newest entry, for pinning queries with [`MetadataQuery::pinned_at`]
+    ///
+    /// Returns None if the store is empty.
+    pub async fn latest_id(&self) -> Result<Option<Uuid>> {
+        let query = MetadataQuery::new()
+            .include_archived(true)
+            .order_by_id(Direction::Descending)
+            .limit(1);
+        let latest = self.metadata_table.query(&query).await?;
+        Ok(latest.first().map(|entry| entry.id))
+    }
+
+    /// Get the metadata entries matching a query
+    ///
+    /// # Arguments
+    /// * `query` - Filters, ordering, and pagination to apply, see [`MetadataQuery`]
+    ///
+    /// Strings are ordered using the store's collation unless the query sets its own.
+    pub async fn query(&self, query: &MetadataQuery) -> Result<Vec<MetadataEntry>> {
+        Counters::add(&self.counters.queries, 1);
+        match (&self.collation, &query.collation) {
+            (Some(locale), None) => {
+                let query = query.clone().collation(locale);
+                self.metadata_table.query(&query).await
+            }
+            _ => self.metadata_table.query(query).await,
+        }
+    }
+
+    /// Get a record of the changes made to this store, oldest first
+    ///
+    /// Every entry is kept in the metadata table, so the log is read straight from it.
+    /// Filter it with the device and creation time options of the query, e.g.
+    /// `MetadataQuery::new().device(id).created_since(time)`. Archived entries are always
+    /// included and the query's ordering is replaced.
+    ///
+    /// Settings changes and data locations aren't part of the log.
+    pub async fn audit_log(&self, query: MetadataQuery) -> Result<Vec<AuditRecord>> {
+        let query = query
+            .include_archived(true)
+            .order_by_id(Direction::Ascending);
+        let store = self.metadata_table.table_name();
+        Ok(self
+            .metadata_table
+            .query(&query)
+            .await?
+            .iter()
+            .map(|entry| AuditRecord::new(store, entry))
+            .collect())
+    }
+
+    /// Borrow this store as a view that can only read from it
+    ///
+    /// Use it to hand the store to code that shouldn't be able to change it.
+    pub fn read_only(&self) -> ReadOnlyDataStore<'_, D, M> {
+        ReadOnlyDataStore::new(self)
+    }
+
+    /// Get the counts of the work this store has done since it was opened
+    pub fn metrics(&self) -> StoreMetrics {
+        self.counters.snapshot()
+    }
+
+    /// Get the device id new entries are written with
+    pub fn device_id(&self) -> DeviceId {
+        self.device_id
+    }
+
+    /// Get the descriptive settings of this store, the defaults if they were never set
+    pub async fn store_settings(&self) -> Result<StoreSettings> {
+        let key = store_settings_key(self.metadata_table.table_name());
+        Ok(self
+            .settings_table
+            .get_typed(&key)
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Change the descriptive settings of this store
+    ///
+    /// `update` receives the current settings. The result is validated before it's saved,
+    /// and fields this version doesn't know about are kept as they were.
+    ///
+    /// # Returns
+    /// The saved settings
+    pub async fn update_store_settings<F>(&mut self, update: F) -> Result<StoreSettings>
+    where
+        F: FnOnce(&mut StoreSettings),
+    {
+        let mut settings = self.store_settings().await?;
+        update(&mut settings);
+        settings.validate().context("Invalid store settings")?;
+
+        let key = store_settings_key(self.metadata_table.table_name());
+        self.settings_table.set_typed(&key, &settings).await?;
+        Ok(settings)
+    }
+
+    /// Get the locale used to order strings in queries, None for byte order
+    pub fn collation(&self) -> Option<&str> {
+        self.collation.as_deref()
+    }
+
+    /// Set the locale used to order strings in queries, e.g. "en-US" or "sv"
+    ///
+    /// This is saved in the store's settings, so every copy of the store pages in the same order.
+    /// Pass None to go back to ordering strings by their bytes.
+    pub async fn set_collation(&mut self, locale: Option<&str>) -> Result<()> {
+        if let Some(locale) = locale {
+            validate_locale(locale)?;
+        }
+        let setting = Setting {
+            key: collation_key(self.metadata_table.table_name()),
+            value: locale.map_or(Value::Null, |locale| Value::String(locale.to_string())),
+            description: Some("Locale used to order strings in queries".to_string()),
+        };
+        self.settings_table
+            .set_setting(setting)
+            .await
+            .context("Failed to set collation in settings")?;
+        self.collation = locale.map(str::to_string);
+        Ok(())
+    }
+
+    /// Get the largest metadata allowed in new entries in bytes, None if there's no limit
+    pub fn max_metadata_size(&self) -> Option<usize> {
+        self.max_metadata_size
+    }
+
+    /// Limit the size of the metadata of new entries in this store, measured as JSON
+    ///
+    /// Committing a larger entry fails with [`EntryTooLarge`]. This keeps entries small
+    /// enough to copy between devices; existing and merged entries aren't checked.
+    /// Pass None to use the default set with [`Self::set_default_max_metadata_size`].
+    pub async fn set_max_metadata_size(&mut self, limit: Option<usize>) -> Result<()> {
+        let key = max_metadata_size_key(self.metadata_table.table_name());
+        self.save_max_metadata_size(key, limit).await
+    }
+
+    /// Set the metadata size limit of every store in the database without their own limit
+    ///
+    /// Other open DataStores pick this up when they're next opened.
+    pub async fn set_default_max_metadata_size(&mut self, limit: Option<usize>) -> Result<()> {
+        self.save_max_metadata_size(SETTING_MAX_METADATA_SIZE.to_string(), limit)
+            .await
+    }
+
+    /// Save a metadata size limit setting and reload the limit of this store
+    async fn save_max_metadata_size(&mut self, key: String, limit: Option<usize>) -> Result<()> {
+        let setting = Setting {
+            key,
+            value: limit.map_or(Value::Null, Value::from),
+            description: Some("Largest metadata allowed in new entries in bytes".to_string()),
+        };
+        self.settings_table
+            .set_setting(setting)
+            .await
+            .context("Failed to set max_metadata_size in settings")?;
+        self.max_metadata_size =
+            get_max_metadata_size(&self.settings_table, self.metadata_table.table_name()).await?;
+        Ok(())
+    }
+
+    /// Export the active entries of this store, signed by `key`
+    ///
+    /// The result can be serialized and checked offline with [`snapshot::verify_snapshot`].
+    pub async fn export_signed_snapshot(&self, key: &SigningKey) -> Result<SignedSnapshot> {
+        let snapshot = Snapshot {
+            store: self.metadata_table.table_name().to_string(),
+            device_id: self.device_id,
+            created_at: Utc::now(),
+            entries: self.metadata_table.get_active_entries().await?,
+        };
+        SignedSnapshot::sign(&snapshot, key)
+    }
+
     /// Get all locations where a piece of data is stored
     /// (inline, local paths, S3 paths, other devices)
     ///
@@ -281,36 +1464,372 @@ impl<D: DataTable, M: MetadataTable> DataStore<D, M> {
             .await
     }
 
-    /// Search the metadata entries for those with matching conditions
-    pub async fn get_entries_by_metadata_conditions(
-        &self,
-        conditions: Value,
-    ) -> Result<Vec<MetadataEntry>> {
-        self.metadata_table
-            .get_entries_by_metadata_conditions(&conditions, false)
-            .await
+    /// Get the least recently accessed data on this device, oldest first
+    ///
+    /// The data table is shared, so this covers data from every store on this device.
+    /// Useful for finding stale data to drop from local storage.
+    ///
+    /// # Arguments
+    /// * `limit` - Maximum number of results to return
+    pub async fn get_least_recently_used(&self, limit: i64) -> Result<Vec<DataAccess>> {
+        self.data_table.get_least_recently_used(limit).await
     }
 
-    /// Get a setting value by key
-    pub async fn get_setting(&self, name: &str) -> Result<Option<Setting>> {
-        self.settings_table.get_setting(name).await
-    }
+    /// Copy every entry in `other` that this store doesn't have yet
+    ///
+    /// Used to combine two copies of the same store that diverged, e.g. two SQLite files
+    /// edited offline. Entries keep their ids, so merging is idempotent and can go in
+    /// either direction. Data available on the other store is copied too.
+    ///
+    /// Entries are copied oldest first, so a newer entry archives its parent as it does
+    /// in `other`. If both copies updated the same entry, both updates stay active.
+    /// Settings aren't merged, they hold device specific values like the local path.
+    ///
+    /// # Arguments
+    /// * `other` - The store to copy entries from
+    #[instrument(level = "debug", skip_all, fields(store = self.metadata_table.table_name(), from = other.metadata_table.table_name()))]
+    pub async fn merge_from<D2: DataTable, M2: MetadataTable>(
+        &mut self,
+        other: &DataStore<D2, M2>,
+    ) -> Result<MergeStats> {
+        let mut stats = MergeStats::default();
+        let mut pending = CopyQueue::default();
+        let mut entries = other.iter_entries(MERGE_PAGE_SIZE, true);
+        while let Some(entry) = entries.next().await? {
+            if self.metadata_table.get_entry(entry.id).await?.is_some() {
+                continue;
+            }
 
-    /// Set a setting value by key
-    pub async fn set_setting(&mut self, setting: Setting) -> Result<()> {
-        self.settings_table.set_setting(setting).await
+            // Archive entries don't have any data
+            if !entry.data_hash.is_empty() {
+                if let Some(location) = other
+                    .data_table
+                    .get_readable_location(&entry.data_hash)
+                    .await?
+                {
+                    let copied = self.data_table.copy_file(location).await?;
+                    if copied.hash != entry.data_hash {
+                        bail!("Data for entry {} doesn't match its hash", entry.id);
+                    }
+                    stats.data += 1;
+                }
+            }
+            pending.push(&self.metadata_table, entry).await?;
+            stats.entries += 1;
+            if pending.len() as i64 >= MERGE_PAGE_SIZE {
+                self.copy_entries(pending.take()).await?;
+            }
+        }
+        self.copy_entries(pending.finish()?).await?;
+        debug!(entries = stats.entries, data = stats.data, "Merged store");
+        Ok(stats)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::utils::generate_key;
-    use sqlx::PgPool;
-    use tempfile::{tempdir, TempDir};
-    use uuid::Uuid;
+    /// Make the empty store `fork` a copy of this one
+    ///
+    /// Every entry is copied with its data, along with the collation and
+    /// [`StoreSettings`]. The fork can then be changed independently, e.g. as a draft, and
+    /// its changes brought back with [`Self::merge_from`]. Entries keep their ids, so the
+    /// merge only copies what changed in the fork.
+    ///
+    /// # Arguments
+    /// * `fork` - The store to copy into, which must not have any entries
+    pub async fn fork_into<D2: DataTable, M2: MetadataTable>(
+        &self,
+        fork: &mut DataStore<D2, M2>,
+    ) -> Result<MergeStats> {
+        if fork.latest_id().await?.is_some() {
+            bail!(
+                "Can't fork into store {}, it already has entries",
+                fork.metadata_table.table_name()
+            );
+        }
+        let stats = fork.merge_from(self).await?;
 
-    /// TestDataStore struct to hold the temporary directory and the datastore
+        fork.set_collation(self.collation()).await?;
+        let settings = self.store_settings().await?;
+        fork.update_store_settings(|fork_settings| *fork_settings = settings)
+            .await?;
+        Ok(stats)
+    }
+
+    /// Insert entries from another copy of this store in one transaction, keeping their ids
+    ///
+    /// The entries' data should already have been copied if it's available. Entries keep
+    /// the archived flag they had in the other copy, so the order they're given in doesn't
+    /// change the result.
+    async fn copy_entries(&mut self, entries: Vec<MetadataEntry>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let events: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                let kind = if entry.archived && entry.data_hash.is_empty() {
+                    ChangeKind::Archived
+                } else {
+                    ChangeKind::Created
+                };
+                (
+                    entry.id,
+                    entry.parent_id,
+                    entry.metadata.clone(),
+                    entry.data_hash.clone(),
+                    kind,
+                )
+            })
+            .collect();
+
+        // Archive entries don't have any data. Data that wasn't available to copy still
+        // needs a row, and is counted before the entries exist like in commit_entries.
+        let hashes: Vec<_> = events
+            .iter()
+            .map(|(.., hash, _)| hash.clone())
+            .filter(|hash| !hash.is_empty())
+            .collect();
+        for hash in hashes.iter().collect::<HashSet<_>>() {
+            self.data_table.ensure_entry(hash).await?;
+        }
+        self.data_table.set_many_local_needed(&hashes).await?;
+        if let Err(e) = self.metadata_table.create_entries(entries).await {
+            self.release_data(&hashes).await;
+            return Err(e);
+        }
+        debug!(count = events.len(), "Copied entries");
+
+        for (id, parent_id, metadata, _, kind) in events {
+            Counters::add(&self.counters.entries_merged, 1);
+            self.notify(id, parent_id, metadata, kind);
+        }
+        Ok(())
+    }
+
+    /// Write every entry of this store and the data available for them to `writer`
+    ///
+    /// The backup is JSON lines, see [`backup`](crate::datastore::backup) for the format.
+    /// Data that isn't available on this device is left out, restoring the backup still
+    /// creates its entries. Settings aren't included, they hold device specific values.
+    ///
+    /// # Arguments
+    /// * `writer` - Destination for the backup
+    ///
+    /// # Returns
+    /// The number of entries and pieces of data written
+    pub async fn export_backup<W: std::io::Write>(&self, writer: &mut W) -> Result<MergeStats> {
+        self.write_backup(None, writer).await
+    }
+
+    /// Write the entries created after `since` and their data to `writer`
+    ///
+    /// A bundle is a partial backup, used to bring another copy of this store up to date
+    /// when there's no connection between them. Pass the newest entry the other copy has,
+    /// and apply the bundle there with [`Self::import_backup`].
+    ///
+    /// Entries are selected by id, so entries merged into this store after `since` was
+    /// created but with older ids are left out. Applying a bundle is idempotent, so when in
+    /// doubt pick an older `since`.
+    ///
+    /// # Arguments
+    /// * `since` - UUID of the last entry the other copy already has
+    /// * `writer` - Destination for the bundle
+    ///
+    /// # Returns
+    /// The number of entries and pieces of data written
+    pub async fn export_bundle<W: std::io::Write>(
+        &self,
+        since: Uuid,
+        writer: &mut W,
+    ) -> Result<MergeStats> {
+        self.write_backup(Some(since), writer).await
+    }
+
+    /// Write a backup of every entry, or only those created after `since`
+    #[instrument(level = "debug", skip_all, fields(store = self.metadata_table.table_name(), since = ?since))]
+    async fn write_backup<W: std::io::Write>(
+        &self,
+        since: Option<Uuid>,
+        writer: &mut W,
+    ) -> Result<MergeStats> {
+        fn write<W: std::io::Write>(writer: &mut W, record: &BackupRecord) -> Result<()> {
+            serde_json::to_writer(&mut *writer, record)?;
+            writer.write_all(b"\n")?;
+            Ok(())
+        }
+        write(
+            writer,
+            &BackupRecord::Header {
+                version: BACKUP_VERSION,
+                store: self.metadata_table.table_name().to_string(),
+                created_at: Utc::now(),
+                since,
+            },
+        )?;
+
+        let mut stats = MergeStats::default();
+        let mut written = HashSet::new();
+        let mut entries = match since {
+            Some(id) => self.iter_entries_since(id, MERGE_PAGE_SIZE, true),
+            None => self.iter_entries(MERGE_PAGE_SIZE, true),
+        };
+        while let Some(entry) = entries.next().await? {
+            if !entry.data_hash.is_empty()
+                && written.insert(entry.data_hash.clone())
+                && self
+                    .data_table
+                    .get_readable_location(&entry.data_hash)
+                    .await?
+                    .is_some()
+            {
+                // Write the BackupRecord::Data line by hand so the data is streamed into it
+                // instead of being loaded into memory
+                let hash = serde_json::to_string(&entry.data_hash)?;
+                write!(writer, r#"{{"data":{{"hash":{hash},"bytes":""#)?;
+                self.data_table
+                    .get_writer(&entry.data_hash, &mut HexWriter(&mut *writer))
+                    .await?;
+                writer.write_all(b"\"}}\n")?;
+                stats.data += 1;
+            }
+            write(writer, &BackupRecord::Entry(entry))?;
+            stats.entries += 1;
+        }
+        Ok(stats)
+    }
+
+    /// Restore a backup written by [`Self::export_backup`] or apply a bundle
+    ///
+    /// Entries this store already has are skipped, so restoring is idempotent and a backup
+    /// can be restored into a store that has moved on since. The backup can come from a
+    /// store with another name or backend. Entries that come before their parent in the
+    /// backup, because of clock skew, are held back until the parent is restored.
+    ///
+    /// # Arguments
+    /// * `reader` - Source of the backup
+    ///
+    /// # Returns
+    /// The number of entries and pieces of data restored
+    #[instrument(level = "debug", skip_all, fields(store = self.metadata_table.table_name()))]
+    pub async fn import_backup<R: std::io::BufRead>(&mut self, reader: R) -> Result<MergeStats> {
+        let mut lines = reader.lines();
+        let header = lines.next().context("Backup is empty")??;
+        match serde_json::from_str(&header).context("Invalid backup header")? {
+            BackupRecord::Header { version, .. } if version == BACKUP_VERSION => {}
+            BackupRecord::Header { version, .. } => {
+                bail!("Unsupported backup version {}", version)
+            }
+            _ => bail!("Backup doesn't start with a header"),
+        }
+
+        let mut stats = MergeStats::default();
+        let mut pending = CopyQueue::default();
+        for line in lines {
+            match serde_json::from_str(&line?).context("Invalid backup record")? {
+                BackupRecord::Header { .. } => bail!("Backup has more than one header"),
+                BackupRecord::Data { hash, bytes } => {
+                    let stored = self
+                        .data_table
+                        .copy_file(DataLocation::Inline(hex::decode(bytes)?))
+                        .await?;
+                    if stored.hash != hash {
+                        bail!("Data in backup doesn't match its hash {}", hash);
+                    }
+                    stats.data += 1;
+                }
+                BackupRecord::Entry(entry) => {
+                    if self.metadata_table.get_entry(entry.id).await?.is_none() {
+                        pending.push(&self.metadata_table, entry).await?;
+                        stats.entries += 1;
+                    }
+                    if pending.len() as i64 >= MERGE_PAGE_SIZE {
+                        self.copy_entries(pending.take()).await?;
+                    }
+                }
+            }
+        }
+        self.copy_entries(pending.finish()?).await?;
+        Ok(stats)
+    }
+
+    /// Check the data stored on this device for damage, e.g. after restoring a backup
+    ///
+    /// Every piece of data with a local copy is rehashed to check it still matches. Data that
+    /// isn't stored on this device can't be checked and is skipped. Each piece of data is only
+    /// checked once, and reported against the first entry that references it.
+    ///
+    /// Parent links don't need checking, the metadata table won't store an entry whose parent
+    /// is missing.
+    ///
+    /// # Returns
+    /// Every problem found, oldest entry first. Empty if the store is intact.
+    #[instrument(level = "debug", skip_all, fields(store = self.metadata_table.table_name()))]
+    pub async fn verify_integrity(&self) -> Result<Vec<IntegrityViolation>> {
+        let mut violations = Vec::new();
+        let mut checked = HashSet::new();
+        let mut entries = self.iter_entries(MERGE_PAGE_SIZE, true);
+        while let Some(entry) = entries.next().await? {
+            // Archive entries don't have any data
+            if entry.data_hash.is_empty() || !checked.insert(entry.data_hash.clone()) {
+                continue;
+            }
+            let (id, hash) = (entry.id, entry.data_hash);
+            match self.data_table.verify_data(&hash).await? {
+                DataCheck::Corrupt => violations.push(IntegrityViolation::CorruptData { id, hash }),
+                DataCheck::Missing => violations.push(IntegrityViolation::MissingData { id, hash }),
+                DataCheck::Valid | DataCheck::NotStored => {}
+            }
+        }
+        debug!(violations = violations.len(), "Verified store");
+        Ok(violations)
+    }
+
+    /// Remove stored data that no entry references any more
+    ///
+    /// Unreferenced data is kept for `min_age` after it was last accessed, so data that is
+    /// still being stored isn't removed. See [`DataTableHandler::collect_garbage`].
+    ///
+    /// # Arguments
+    /// * `min_age` - How long unreferenced data is kept after it was last accessed
+    /// * `dry_run` - Only report what would be removed
+    #[instrument(level = "debug", skip(self))]
+    pub async fn gc(&mut self, min_age: chrono::Duration, dry_run: bool) -> Result<GcStats> {
+        self.data_table.collect_garbage(min_age, dry_run).await
+    }
+
+    /// Set how many bytes of data are buffered in memory at once while storing data
+    pub fn set_ingest_buffer_size(&mut self, buffer_size: usize) {
+        self.data_table.set_buffer_size(buffer_size);
+    }
+
+    /// Search the metadata entries for those with matching conditions
+    pub async fn get_entries_by_metadata_conditions(
+        &self,
+        conditions: Value,
+    ) -> Result<Vec<MetadataEntry>> {
+        self.metadata_table
+            .get_entries_by_metadata_conditions(&conditions, false)
+            .await
+    }
+
+    /// Get a setting value by key
+    pub async fn get_setting(&self, name: &str) -> Result<Option<Setting>> {
+        self.settings_table.get_setting(name).await
+    }
+
+    /// Set a setting value by key
+    pub async fn set_setting(&mut self, setting: Setting) -> Result<()> {
+        self.settings_table.set_setting(setting).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::generate_key;
+    use sqlx::PgPool;
+    use tempfile::{tempdir, TempDir};
+    use uuid::Uuid;
+
+    /// TestDataStore struct to hold the temporary directory and the datastore
     /// This allows us to keep the tempdir around so it's not deleted after setup
     #[allow(dead_code)]
     struct TestDataStore {
@@ -446,301 +1965,2360 @@ mod tests {
         let archived = store
             .get_archived_entries()
             .await
-            .expect("Failed to query archived");
-        assert!(!archived.is_empty());
+            .expect("Failed to query archived");
+        assert!(!archived.is_empty());
+
+        // Archiving it again, or archiving an entry that was replaced, doesn't fork it
+        let err = store.archive(id).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EntryReplaced>(),
+            Some(&EntryReplaced { id })
+        );
+        let old = store
+            .store_data(DataLocation::Inline(b"old".to_vec()), Value::Null, None)
+            .await
+            .expect("Failed to store data");
+        store
+            .store_data(
+                DataLocation::Inline(b"new".to_vec()),
+                Value::Null,
+                Some(old),
+            )
+            .await
+            .expect("Failed to update entry");
+        let err = store.archive(old).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EntryReplaced>(),
+            Some(&EntryReplaced { id: old })
+        );
+        assert!(store
+            .find_conflicts()
+            .await
+            .expect("Failed to find conflicts")
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_query_by_metadata(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool).await?;
+
+        // Store entries with different metadata
+        let test1_id = store
+            .store_data(
+                DataLocation::Inline("test1".as_bytes().to_vec()),
+                serde_json::json!({"type": "text", "tag": "a"}),
+                None,
+            )
+            .await
+            .expect("Failed to store data 1");
+
+        store
+            .store_data(
+                DataLocation::Inline("test2".as_bytes().to_vec()),
+                serde_json::json!({"type": "text", "tag": "b"}),
+                None,
+            )
+            .await
+            .expect("Failed to store data 2");
+
+        // Query for specific tag
+        let results: Vec<(Uuid, Value)> = store
+            .query_by_metadata(&serde_json::json!({"tag": "a"}), false)
+            .await
+            .expect("Failed to query");
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].1["tag"], "a");
+        assert_eq!(results[0].0, test1_id);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_data_locations_management(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool).await?;
+
+        // Store initial data as inline
+        let data = "Test data".as_bytes().to_vec();
+        let id = store
+            .store_data(
+                DataLocation::Inline(data.clone()),
+                serde_json::json!({"type": "text"}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+
+        // Get locations - should have been converted to local path
+        let locations = store
+            .get_data_locations(id)
+            .await
+            .expect("Failed to get locations");
+        assert_eq!(locations.len(), 1);
+        match &locations[0] {
+            DataLocation::LocalPath(path) => {
+                assert!(path.exists());
+                let contents = std::fs::read(path).expect("Failed to read file");
+                assert_eq!(contents, data);
+            }
+            _ => panic!("Expected local path data"),
+        }
+
+        // Add an S3 location
+        let s3_path = "s3://bucket/test.txt".to_string();
+        store
+            .add_data_location(id, DataLocation::S3(s3_path.clone()))
+            .await
+            .expect("Failed to add S3 location");
+
+        // Verify both locations exist
+        let locations = store
+            .get_data_locations(id)
+            .await
+            .expect("Failed to get locations");
+        assert_eq!(locations.len(), 2);
+
+        // Verify local path still exists
+        let has_local = locations
+            .iter()
+            .any(|loc| matches!(loc, DataLocation::LocalPath(_)));
+        assert!(has_local, "Local path not found");
+
+        // Verify S3 location exists
+        let has_s3 = locations.iter().any(|loc| match loc {
+            DataLocation::S3(path) => path == &s3_path,
+            _ => false,
+        });
+        assert!(has_s3, "S3 location not found");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_data_storage_with_different_types(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool).await?;
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+
+        // Test inline data
+        let inline_data = "Inline content".as_bytes().to_vec();
+        let inline_id = store
+            .store_data(
+                DataLocation::Inline(inline_data.clone()),
+                serde_json::json!({"type": "inline"}),
+                None,
+            )
+            .await
+            .expect("Failed to store inline data");
+
+        // Test local file
+        let file_path = temp_dir.path().join("test.txt");
+        std::fs::write(&file_path, "Local file content").expect("Failed to write test file");
+        let file_id = store
+            .store_data(
+                DataLocation::LocalPath(file_path),
+                serde_json::json!({"type": "file"}),
+                None,
+            )
+            .await
+            .expect("Failed to store file");
+
+        // Verify both entries exist and have correct metadata
+        let entries = store
+            .get_active_entries()
+            .await
+            .expect("Failed to get entries");
+        assert_eq!(entries.len(), 2);
+
+        // Verify inline data entry
+        let inline_entry = entries
+            .iter()
+            .find(|e| e.id == inline_id)
+            .expect("Inline entry not found");
+        assert_eq!(inline_entry.metadata["type"], "inline");
+
+        // Verify file entry
+        let file_entry = entries
+            .iter()
+            .find(|e| e.id == file_id)
+            .expect("File entry not found");
+        assert_eq!(file_entry.metadata["type"], "file");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_data_versioning_and_history(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool).await?;
+
+        // Create initial version
+        let v1_data = "Version 1".as_bytes().to_vec();
+        let v1_id = store
+            .store_data(
+                DataLocation::Inline(v1_data),
+                serde_json::json!({
+                    "version": 1,
+                    "type": "document"
+                }),
+                None,
+            )
+            .await
+            .expect("Failed to store v1");
+
+        // Create version 2
+        let v2_data = "Version 2".as_bytes().to_vec();
+        let v2_id = store
+            .store_data(
+                DataLocation::Inline(v2_data),
+                serde_json::json!({
+                    "version": 2,
+                    "type": "document"
+                }),
+                Some(v1_id),
+            )
+            .await
+            .expect("Failed to store v2");
+
+        // Create version 3
+        let v3_data = "Version 3".as_bytes().to_vec();
+        let v3_id = store
+            .store_data(
+                DataLocation::Inline(v3_data),
+                serde_json::json!({
+                    "version": 3,
+                    "type": "document"
+                }),
+                Some(v2_id),
+            )
+            .await
+            .expect("Failed to store v3");
+
+        // Get history starting from v3
+        let history = store
+            .get_history(v3_id)
+            .await
+            .expect("Failed to get history");
+
+        // Verify history order and content
+        assert_eq!(history.len(), 3, "Expected 3 versions in history");
+        assert_eq!(history[0].id, v3_id, "Latest version should be first");
+        assert_eq!(history[1].id, v2_id, "Second version should be second");
+        assert_eq!(history[2].id, v1_id, "First version should be last");
+
+        // Verify version numbers in metadata
+        assert_eq!(
+            history[0].metadata["version"], 3,
+            "Latest version should have version 3"
+        );
+        assert_eq!(
+            history[1].metadata["version"], 2,
+            "Second version should have version 2"
+        );
+        assert_eq!(
+            history[2].metadata["version"], 1,
+            "First version should have version 1"
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_metadata_query_complex(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool).await?;
+
+        // Store multiple entries with different metadata
+        for i in 1..=3 {
+            store
+                .store_data(
+                    DataLocation::Inline(format!("Data {}", i).as_bytes().to_vec()),
+                    serde_json::json!({
+                        "type": "document",
+                        "category": if i % 2 == 0 { "even" } else { "odd" },
+                        "priority": i
+                    }),
+                    None,
+                )
+                .await
+                .expect("Failed to store data");
+        }
+
+        // Query by category
+        let odd_results = store
+            .query_by_metadata(&serde_json::json!({"category": "odd"}), false)
+            .await
+            .expect("Failed to query odd entries");
+        assert_eq!(odd_results.len(), 2, "Expected 2 odd entries");
+
+        // Query by priority
+        let high_priority = store
+            .query_by_metadata(&serde_json::json!({"priority": 3}), false)
+            .await
+            .expect("Failed to query high priority entries");
+        assert_eq!(high_priority.len(), 1, "Expected 1 high priority entry");
+
+        // Query with multiple conditions
+        let specific_entries = store
+            .query_by_metadata(
+                &serde_json::json!({
+                    "type": "document",
+                    "category": "odd"
+                }),
+                false,
+            )
+            .await
+            .expect("Failed to query specific entries");
+        assert_eq!(
+            specific_entries.len(),
+            2,
+            "Expected 2 entries matching both conditions"
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_data_access_tracking(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool).await?;
+
+        let first_id = store
+            .store_data(
+                DataLocation::Inline("first".as_bytes().to_vec()),
+                serde_json::json!({"name": "first"}),
+                None,
+            )
+            .await
+            .expect("Failed to store first");
+        let second_id = store
+            .store_data(
+                DataLocation::Inline("second".as_bytes().to_vec()),
+                serde_json::json!({"name": "second"}),
+                None,
+            )
+            .await
+            .expect("Failed to store second");
+
+        let first_hash = store
+            .metadata_table
+            .get_entry(first_id)
+            .await
+            .expect("Failed to get first")
+            .unwrap()
+            .data_hash;
+        let second_hash = store
+            .metadata_table
+            .get_entry(second_id)
+            .await
+            .expect("Failed to get second")
+            .unwrap()
+            .data_hash;
+
+        // The first entry was written first, so it is the least recently used
+        let lru = store
+            .get_least_recently_used(2)
+            .await
+            .expect("Failed to get LRU");
+        assert_eq!(lru[0].hash, first_hash);
+        assert_eq!(lru[1].hash, second_hash);
+
+        // Reading the first entry's locations moves it to the back
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        store
+            .get_data_locations(first_id)
+            .await
+            .expect("Failed to get locations");
+        let lru = store
+            .get_least_recently_used(2)
+            .await
+            .expect("Failed to get LRU");
+        assert_eq!(lru[0].hash, second_hash);
+        assert_eq!(lru[1].hash, first_hash);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_datastore() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let database_url = format!("sqlite://{}", temp_dir.path().join("store.db").display());
+        let data_path = temp_dir.path().join("data");
+        std::fs::create_dir_all(&data_path).expect("Failed to create data dir");
+        let device_id = generate_test_device_id();
+
+        let pool = connect_sqlite(&database_url)
+            .await
+            .expect("Failed to open database");
+
+        // Opening before initialization fails
+        assert!(DataStore::from_sqlite(pool.clone(), "test", device_id)
+            .await
+            .is_err());
+
+        let mut store = DataStore::init_sqlite(pool, "test", device_id, data_path)
+            .await
+            .expect("Failed to initialize store");
+        let id = store
+            .store_data(
+                DataLocation::Inline(b"Hello world!".to_vec()),
+                serde_json::json!({"name": "hello.txt"}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+        let updated_id = store
+            .store_data(
+                DataLocation::Inline(b"Hello again!".to_vec()),
+                serde_json::json!({"name": "hello.txt", "version": 2}),
+                Some(id),
+            )
+            .await
+            .expect("Failed to store update");
+
+        // Reopen the database file and find the data again
+        let pool = connect_sqlite(&database_url)
+            .await
+            .expect("Failed to reopen database");
+        let store = DataStore::from_sqlite(pool, "test", device_id)
+            .await
+            .expect("Failed to open store");
+
+        let results = store
+            .query_by_metadata(&serde_json::json!({"name": {"$regex": "^hello"}}), false)
+            .await
+            .expect("Failed to query");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, updated_id);
+
+        let history = store.get_history(updated_id).await.expect("No history");
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].id, id);
+
+        let locations = store
+            .get_data_locations(updated_id)
+            .await
+            .expect("Failed to get locations");
+        assert!(!locations.is_empty());
+    }
+
+    #[sqlx::test]
+    async fn test_iter_entries(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool).await?;
+
+        let mut ids = Vec::new();
+        for i in 0..5 {
+            let id = store
+                .store_data(
+                    DataLocation::Inline(format!("data {}", i).into_bytes()),
+                    serde_json::json!({ "index": i }),
+                    None,
+                )
+                .await
+                .expect("Failed to store data");
+            ids.push(id);
+        }
+        store.archive(ids[1]).await.expect("Failed to archive");
+
+        // Pages smaller than the table are stitched together, oldest first
+        let mut iter = store.iter_entries(2, false);
+        let mut seen = Vec::new();
+        while let Some(entry) = iter.next().await.expect("Failed to page") {
+            seen.push(entry.id);
+        }
+        assert_eq!(seen, [ids[0], ids[2], ids[3], ids[4]]);
+        assert!(iter.next().await.expect("Failed to page").is_none());
+
+        // Archived entries include the archive marker created after the data
+        let mut iter = store.iter_entries(10, true);
+        let mut count = 0;
+        while iter.next().await.expect("Failed to page").is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 6);
+
+        // Resume after an entry that was already seen
+        let mut iter = store.iter_entries_since(ids[2], 1, false);
+        assert_eq!(
+            iter.next().await.expect("Failed to page").unwrap().id,
+            ids[3]
+        );
+        assert_eq!(
+            iter.next().await.expect("Failed to page").unwrap().id,
+            ids[4]
+        );
+        assert!(iter.next().await.expect("Failed to page").is_none());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_store_reader_round_trip(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool).await?;
+        store.set_ingest_buffer_size(10);
+
+        // Larger than the buffer, so both directions take many reads
+        let data: Vec<u8> = (0..=255u8).cycle().take(1000).collect();
+        let id = store
+            .store_reader(
+                std::io::Cursor::new(data.clone()),
+                serde_json::json!({"name": "blob.bin"}),
+                None,
+            )
+            .await
+            .expect("Failed to store reader");
+
+        let mut output = Vec::new();
+        let written = store
+            .read_data(id, &mut output)
+            .await
+            .expect("Failed to read data");
+        assert_eq!(written, 1000);
+        assert_eq!(output, data);
+
+        // Identical data stored inline reads back the same way
+        let inline_id = store
+            .store_data(
+                DataLocation::Inline(data.clone()),
+                serde_json::json!({}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+        let mut output = Vec::new();
+        store
+            .read_data(inline_id, &mut output)
+            .await
+            .expect("Failed to read data");
+        assert_eq!(output, data);
+
+        assert!(store
+            .read_data(Uuid::now_v7(), &mut Vec::new())
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_export_signed_snapshot(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool).await?;
+
+        let first = store
+            .store_data(
+                DataLocation::Inline(b"one".to_vec()),
+                serde_json::json!({"n": 1}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+        store
+            .store_data(
+                DataLocation::Inline(b"two".to_vec()),
+                serde_json::json!({"n": 2}),
+                Some(first),
+            )
+            .await
+            .expect("Failed to store data");
+
+        let key = generate_key();
+        let signed = store
+            .export_signed_snapshot(&key)
+            .await
+            .expect("Failed to export snapshot");
+        let contents = signed.snapshot().expect("Failed to parse snapshot");
+        assert_eq!(contents.store, "test");
+        assert_eq!(signed.signer, key.verifying_key().to_bytes());
+        // Only the newest entry in the chain is active
+        assert_eq!(contents.entries.len(), 1);
+        assert_eq!(contents.entries[0].metadata["n"], 2);
+
+        // Survives a round trip through JSON, including through an untyped Value that
+        // reorders the keys of objects
+        let json = serde_json::to_string(&signed).expect("Failed to serialize");
+        let parsed: SignedSnapshot = serde_json::from_str(&json).expect("Failed to parse");
+        assert_eq!(parsed, signed);
+        let verified = snapshot::verify_snapshot(&parsed).expect("Snapshot should verify");
+        assert_eq!(verified, contents);
+        let value: Value = serde_json::from_str(&json).expect("Failed to parse");
+        let parsed: SignedSnapshot = serde_json::from_value(value).expect("Failed to parse");
+        snapshot::verify_snapshot(&parsed).expect("Snapshot should verify");
+
+        // Any change to the contents breaks the signature
+        let mut tampered = signed.clone();
+        tampered.payload = tampered.payload.replace(r#""n":2"#, r#""n":3"#);
+        assert_ne!(tampered.payload, signed.payload);
+        assert!(snapshot::verify_snapshot(&tampered).is_err());
+
+        // As does claiming a different signer
+        let mut tampered = signed.clone();
+        tampered.signer = generate_test_device_id();
+        assert!(snapshot::verify_snapshot(&tampered).is_err());
+
+        let mut tampered = signed;
+        tampered.signature = "00".repeat(64);
+        assert!(snapshot::verify_snapshot(&tampered).is_err());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_subscribe(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool).await?;
+
+        let mut changes = store.subscribe();
+        let id = store
+            .store_data(
+                DataLocation::Inline(b"data".to_vec()),
+                serde_json::json!({}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+        let updated = store
+            .store_reader(&b"more data"[..], serde_json::json!({}), Some(id))
+            .await
+            .expect("Failed to store reader");
+        store.archive(updated).await.expect("Failed to archive");
+
+        let event = changes.recv().await.expect("Missing event");
+        assert_eq!(event.store, "test");
+        assert_eq!(event.id, id);
+        assert_eq!(event.parent_id, None);
+        assert_eq!(event.kind, ChangeKind::Created);
+
+        let event = changes.recv().await.expect("Missing event");
+        assert_eq!(event.id, updated);
+        assert_eq!(event.parent_id, Some(id));
+        assert_eq!(event.kind, ChangeKind::Created);
+
+        let event = changes.recv().await.expect("Missing event");
+        assert_eq!(event.parent_id, Some(updated));
+        assert_eq!(event.kind, ChangeKind::Archived);
+        assert!(changes.try_recv().is_err());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_watch(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool).await?;
+
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        struct Task {
+            done: bool,
+        }
+
+        let id = store
+            .store_data(
+                DataLocation::Inline(b"task".to_vec()),
+                serde_json::json!({"done": false}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+        let other = store
+            .store_data(
+                DataLocation::Inline(b"other".to_vec()),
+                serde_json::json!({"done": false}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+
+        let mut watch = store.watch(id);
+
+        // Changes to other entries are skipped
+        store
+            .store_data(
+                DataLocation::Inline(b"other".to_vec()),
+                serde_json::json!({"done": true}),
+                Some(other),
+            )
+            .await
+            .expect("Failed to store data");
+        let updated = store
+            .store_data(
+                DataLocation::Inline(b"task".to_vec()),
+                serde_json::json!({"done": true}),
+                Some(id),
+            )
+            .await
+            .expect("Failed to store data");
+
+        let task: Task = watch.changed_value().await.expect("Missing change");
+        assert_eq!(task, Task { done: true });
+        assert_eq!(watch.current(), updated);
+
+        // The watch follows the history to the new entry
+        store.archive(updated).await.expect("Failed to archive");
+        let event = watch.changed().await.expect("Missing change");
+        assert_eq!(event.kind, ChangeKind::Archived);
+        assert_eq!(event.parent_id, Some(updated));
+
+        drop(store);
+        assert!(watch.changed().await.is_err());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_collation_setting(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool.clone()).await?;
+
+        let mut ids = Vec::new();
+        for name in ["b", "B", "a"] {
+            ids.push(
+                store
+                    .store_data(
+                        DataLocation::Inline(name.as_bytes().to_vec()),
+                        serde_json::json!({ "name": name }),
+                        None,
+                    )
+                    .await
+                    .expect("Failed to store data"),
+            );
+        }
+        let query = MetadataQuery::new().order_by(
+            "name".parse().expect("Invalid path"),
+            query::Direction::Ascending,
+        );
+        let sorted = |entries: Vec<MetadataEntry>| -> Vec<Uuid> {
+            entries.into_iter().map(|entry| entry.id).collect()
+        };
+
+        assert_eq!(store.collation(), None);
+        assert_eq!(
+            sorted(store.query(&query).await.expect("Query failed")),
+            [ids[1], ids[2], ids[0]]
+        );
+
+        // The collation is used by default and saved with the store
+        store
+            .set_collation(Some("en-US"))
+            .await
+            .expect("Failed to set collation");
+        assert_eq!(
+            sorted(store.query(&query).await.expect("Query failed")),
+            [ids[2], ids[0], ids[1]]
+        );
+        let reopened = DataStore::from_pool(pool, "test", store.device_id)
+            .await
+            .expect("Failed to open");
+        assert_eq!(reopened.collation(), Some("en-US"));
+
+        store
+            .set_collation(None)
+            .await
+            .expect("Failed to clear collation");
+        assert_eq!(
+            sorted(store.query(&query).await.expect("Query failed")),
+            [ids[1], ids[2], ids[0]]
+        );
+        assert!(store.set_collation(Some("en US")).await.is_err());
+
+        Ok(())
+    }
+
+    /// Create a SQLite store in its own file, as on a separate device
+    async fn setup_sqlite_store(
+        temp_dir: &TempDir,
+        name: &str,
+    ) -> DataStore<SqliteDataTable, SqliteMetadataTable> {
+        let database_url = format!(
+            "sqlite://{}",
+            temp_dir.path().join(format!("{name}.db")).display()
+        );
+        let data_path = temp_dir.path().join(name);
+        std::fs::create_dir_all(&data_path).expect("Failed to create data dir");
+        let pool = connect_sqlite(&database_url)
+            .await
+            .expect("Failed to open database");
+        DataStore::init_sqlite(pool, "test", generate_test_device_id(), data_path)
+            .await
+            .expect("Failed to initialize store")
+    }
+
+    #[tokio::test]
+    async fn test_merge_from() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let mut laptop = setup_sqlite_store(&temp_dir, "laptop").await;
+        let mut desktop = setup_sqlite_store(&temp_dir, "desktop").await;
+
+        let shared = laptop
+            .store_data(
+                DataLocation::Inline(b"shared".to_vec()),
+                serde_json::json!({"name": "shared"}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+        let stats = desktop.merge_from(&laptop).await.expect("Failed to merge");
+        assert_eq!(
+            stats,
+            MergeStats {
+                entries: 1,
+                data: 1
+            }
+        );
+
+        // Both diverge while offline
+        let updated = laptop
+            .store_reader(
+                &b"updated on the laptop"[..],
+                serde_json::json!({"name": "shared", "version": 2}),
+                Some(shared),
+            )
+            .await
+            .expect("Failed to store data");
+        let new = desktop
+            .store_data(
+                DataLocation::Inline(b"new on the desktop".to_vec()),
+                serde_json::json!({"name": "new"}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+
+        let mut changes = desktop.subscribe();
+        let stats = desktop.merge_from(&laptop).await.expect("Failed to merge");
+        assert_eq!(
+            stats,
+            MergeStats {
+                entries: 1,
+                data: 1
+            }
+        );
+        assert_eq!(changes.try_recv().expect("Missing event").id, updated);
+        let stats = laptop.merge_from(&desktop).await.expect("Failed to merge");
+        assert_eq!(
+            stats,
+            MergeStats {
+                entries: 1,
+                data: 1
+            }
+        );
+
+        // Both end up with the same active entries and data
+        for store in [&laptop, &desktop] {
+            let mut active: Vec<Uuid> = store
+                .get_active_entries()
+                .await
+                .expect("Failed to get entries")
+                .into_iter()
+                .map(|entry| entry.id)
+                .collect();
+            active.sort();
+            assert_eq!(active, [updated, new]);
+
+            let mut data = Vec::new();
+            store
+                .read_data(updated, &mut data)
+                .await
+                .expect("Failed to read data");
+            assert_eq!(data, b"updated on the laptop");
+        }
+
+        // Merging again copies nothing
+        let stats = desktop.merge_from(&laptop).await.expect("Failed to merge");
+        assert_eq!(stats, MergeStats::default());
+    }
+
+    #[tokio::test]
+    async fn test_merge_from_clock_skew() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let mut laptop = setup_sqlite_store(&temp_dir, "laptop").await;
+        let mut desktop = setup_sqlite_store(&temp_dir, "desktop").await;
+
+        // The child is written on a device with a slow clock, so its id is lower
+        let child_id = Uuid::now_v7();
+        let parent_id = laptop
+            .store_data(
+                DataLocation::Inline(b"data".to_vec()),
+                serde_json::json!({"name": "parent"}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+        let parent = laptop
+            .metadata_table
+            .get_entry(parent_id)
+            .await
+            .expect("Failed to get entry")
+            .expect("Not found");
+        laptop
+            .metadata_table
+            .create_entry(MetadataEntry {
+                id: child_id,
+                parent_id: Some(parent_id),
+                metadata: serde_json::json!({"name": "child"}),
+                ..parent
+            })
+            .await
+            .expect("Failed to create entry");
+        assert!(child_id < parent_id);
+
+        let stats = desktop.merge_from(&laptop).await.expect("Failed to merge");
+        assert_eq!(stats.entries, 2);
+        let active = desktop
+            .get_active_entries()
+            .await
+            .expect("Failed to get entries");
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].id, child_id);
+    }
+
+    #[sqlx::test]
+    async fn test_order_entries(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool).await?;
+
+        let ids: Vec<Uuid> = (0..4).map(|_| Uuid::now_v7()).collect();
+        // The first entry was written on a device with a slow clock, after its parent
+        let parents = [Some(ids[2]), None, None, Some(ids[1])];
+        for i in [1, 2, 0, 3] {
+            store
+                .metadata_table
+                .create_entry(MetadataEntry {
+                    id: ids[i],
+                    device_id: store.device_id,
+                    archived: false,
+                    local: false,
+                    parent_id: parents[i],
+                    metadata: serde_json::json!({}),
+                    data_hash: "".to_string(),
+                })
+                .await
+                .expect("Failed to create entry");
+        }
+
+        let expected = [ids[1], ids[2], ids[0], ids[3]];
+        assert_eq!(
+            store.order_entries(&ids).await.expect("Failed to order"),
+            expected
+        );
+        let mut reversed = ids.clone();
+        reversed.reverse();
+        assert_eq!(
+            store
+                .order_entries(&reversed)
+                .await
+                .expect("Failed to order"),
+            expected
+        );
+
+        // Without its parent an entry is ordered by id
+        assert_eq!(
+            store
+                .order_entries(&[ids[3], ids[0], ids[1]])
+                .await
+                .expect("Failed to order"),
+            [ids[0], ids[1], ids[3]]
+        );
+
+        assert!(store.order_entries(&[Uuid::now_v7()]).await.is_err());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_list_stores(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir,
+        } = setup_datastore(pool.clone()).await?;
+        let before = Utc::now();
+        let id = store
+            .store_data(
+                DataLocation::Inline(b"data".to_vec()),
+                serde_json::json!({}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+        store.archive(id).await.expect("Failed to archive");
+        DataStore::init(
+            pool.clone(),
+            "empty",
+            store.device_id,
+            temp_dir.path().to_path_buf(),
+        )
+        .await
+        .expect("Failed to initialize store");
+
+        let stores = DataStore::list_stores(&pool)
+            .await
+            .expect("Failed to list stores");
+        assert_eq!(stores.len(), 2);
+        assert_eq!(stores[0], StoreInfo::new("empty".to_string(), 0, 0, None));
+        assert_eq!(stores[1].name, "test");
+        assert_eq!(stores[1].entries, 2);
+        assert_eq!(stores[1].active_entries, 0);
+        let created_at = stores[1].created_at.expect("Missing creation time");
+        assert!(created_at >= before - chrono::Duration::seconds(1));
+        assert!(created_at <= Utc::now());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_sqlite_stores() {
+        let pool = connect_sqlite("sqlite::memory:")
+            .await
+            .expect("Failed to open database");
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let mut store = DataStore::init_sqlite(
+            pool.clone(),
+            "notes",
+            generate_test_device_id(),
+            temp_dir.path().to_path_buf(),
+        )
+        .await
+        .expect("Failed to initialize store");
+        store
+            .store_data(
+                DataLocation::Inline(b"data".to_vec()),
+                serde_json::json!({}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+
+        let stores = DataStore::list_sqlite_stores(&pool)
+            .await
+            .expect("Failed to list stores");
+        assert_eq!(stores.len(), 1);
+        assert_eq!(stores[0].name, "notes");
+        assert_eq!(stores[0].entries, 1);
+        assert_eq!(stores[0].active_entries, 1);
+        assert!(stores[0].created_at.is_some());
+    }
+
+    #[sqlx::test]
+    async fn test_audit_log(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut laptop,
+            temp_dir: _,
+        } = setup_datastore(pool.clone()).await?;
+        let mut phone = DataStore::from_pool(pool, "test", generate_test_device_id())
+            .await
+            .expect("Failed to open store");
+
+        let created = laptop
+            .store_data(
+                DataLocation::Inline(b"v1".to_vec()),
+                serde_json::json!({}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+        let updated = phone
+            .store_data(
+                DataLocation::Inline(b"v2".to_vec()),
+                serde_json::json!({}),
+                Some(created),
+            )
+            .await
+            .expect("Failed to store data");
+        laptop.archive(updated).await.expect("Failed to archive");
+
+        let log = laptop
+            .audit_log(MetadataQuery::new())
+            .await
+            .expect("Failed to read log");
+        let operations: Vec<_> = log
+            .iter()
+            .map(|record| (record.operation, record.device_id, record.parent_id))
+            .collect();
+        assert_eq!(
+            operations,
+            [
+                (audit::AuditOperation::Create, laptop.device_id, None),
+                (
+                    audit::AuditOperation::Update,
+                    phone.device_id,
+                    Some(created)
+                ),
+                (
+                    audit::AuditOperation::Archive,
+                    laptop.device_id,
+                    Some(updated)
+                ),
+            ]
+        );
+        assert!(log.iter().all(|record| record.store == "test"));
+        assert!(log[0].timestamp.expect("Missing timestamp") <= Utc::now());
+        let entry = laptop
+            .metadata_table
+            .get_entry(created)
+            .await
+            .expect("Failed to get entry")
+            .expect("Missing entry");
+        assert_eq!(entry.created_at(), log[0].timestamp);
+
+        // Filter by device and time
+        let log = laptop
+            .audit_log(MetadataQuery::new().device(phone.device_id))
+            .await
+            .expect("Failed to read log");
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].id, updated);
+        let log = laptop
+            .audit_log(MetadataQuery::new().created_since(Utc::now() + chrono::Duration::hours(1)))
+            .await
+            .expect("Failed to read log");
+        assert!(log.is_empty());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_store_many_and_update_where(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool).await?;
+
+        let mut changes = store.subscribe();
+        let ids = store
+            .store_many((0..5).map(|i| {
+                (
+                    DataLocation::Inline(format!("record {i}").into_bytes()),
+                    serde_json::json!({"n": i, "done": false}),
+                )
+            }))
+            .await
+            .expect("Failed to store data");
+        assert_eq!(ids.len(), 5);
+        for id in &ids {
+            assert_eq!(changes.recv().await.expect("Missing event").id, *id);
+        }
+        let active = store
+            .query(&MetadataQuery::new())
+            .await
+            .expect("Failed to query");
+        assert_eq!(active.len(), 5);
+        assert_eq!(active[3].id, ids[3]);
+        assert_eq!(active[3].metadata["n"], 3);
+
+        // Mark the even records done
+        let even = MetadataQuery::new().filter(
+            "n".parse().expect("Invalid path"),
+            query::Predicate::Regex("^[024]$".to_string()),
+        );
+        let updated = store
+            .update_where(&even, |metadata| metadata["done"] = Value::Bool(true))
+            .await
+            .expect("Failed to update");
+        assert_eq!(updated.len(), 3);
+
+        let done = store
+            .query(&MetadataQuery::new().filter(
+                "done".parse().expect("Invalid path"),
+                query::Predicate::Eq(Value::Bool(true)),
+            ))
+            .await
+            .expect("Failed to query");
+        assert_eq!(
+            done.iter().map(|entry| entry.id).collect::<Vec<_>>(),
+            updated
+        );
+        assert_eq!(done[0].parent_id, Some(ids[0]));
+        assert_eq!(done[1].metadata, serde_json::json!({"n": 2, "done": true}));
+        let mut data = Vec::new();
+        store
+            .read_data(updated[2], &mut data)
+            .await
+            .expect("Failed to read data");
+        assert_eq!(data, b"record 4");
+        assert_eq!(store.get_active_entries().await.expect("Failed").len(), 5);
+
+        // The old versions are archived, so a second pass matches only the new ones
+        let again = store
+            .update_where(&even, |metadata| metadata["again"] = Value::Bool(true))
+            .await
+            .expect("Failed to update");
+        assert_eq!(again.len(), 3);
+        assert_eq!(store.get_archived_entries().await.expect("Failed").len(), 6);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_modify(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool).await?;
+
+        let id = store
+            .store_data(
+                DataLocation::Inline(b"todo".to_vec()),
+                serde_json::json!({"title": "Write tests", "done": false}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+
+        let done = store
+            .modify(id, |metadata| {
+                metadata["done"] = Value::Bool(true);
+                Ok(())
+            })
+            .await
+            .expect("Failed to modify");
+        let entry = store
+            .metadata_table
+            .get_entry(done)
+            .await
+            .expect("Failed to get")
+            .expect("Missing entry");
+        assert_eq!(
+            entry.metadata,
+            serde_json::json!({"title": "Write tests", "done": true})
+        );
+        assert_eq!(entry.parent_id, Some(id));
+        let mut data = Vec::new();
+        store
+            .read_data(done, &mut data)
+            .await
+            .expect("Failed to read data");
+        assert_eq!(data, b"todo");
+
+        // The replaced entry can't be modified again
+        let err = store.modify(id, |_| Ok(())).await.unwrap_err();
+        assert!(err.downcast_ref::<EntryReplaced>().is_some());
+
+        // A failing closure changes nothing
+        assert!(store
+            .modify(done, |_| Err(anyhow!("Changed my mind")))
+            .await
+            .is_err());
+        assert_eq!(store.get_active_entries().await.expect("Failed").len(), 1);
+
+        // Modify a single value, creating it if needed
+        let path: MetadataPath = "edits.count".parse().expect("Invalid path");
+        let mut latest = done;
+        for expected in 1..=2 {
+            latest = store
+                .modify_value(latest, &path, |count| {
+                    serde_json::json!(count.and_then(Value::as_i64).unwrap_or(0) + 1)
+                })
+                .await
+                .expect("Failed to modify value");
+            let entry = store
+                .metadata_table
+                .get_entry(latest)
+                .await
+                .expect("Failed to get")
+                .expect("Missing entry");
+            assert_eq!(entry.metadata["edits"]["count"], expected);
+        }
+        assert!(store
+            .modify_value(latest, &"title[*]".parse().expect("Invalid path"), |_| {
+                Value::Null
+            })
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_store_data_replaced_parent(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool.clone()).await?;
+        // Another device writing to the same store
+        let mut other = DataStore::from_pool(pool, "test", generate_test_device_id())
+            .await
+            .expect("Failed to open store");
+
+        let id = store
+            .store_data(
+                DataLocation::Inline(b"v1".to_vec()),
+                serde_json::json!({"name": "a"}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+
+        // Both devices read the same version, only the first update wins
+        let theirs = other
+            .store_data(
+                DataLocation::Inline(b"theirs".to_vec()),
+                serde_json::json!({"name": "b"}),
+                Some(id),
+            )
+            .await
+            .expect("Failed to update");
+        let err = store
+            .store_data(
+                DataLocation::Inline(b"ours".to_vec()),
+                serde_json::json!({"name": "c"}),
+                Some(id),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EntryReplaced>(),
+            Some(&EntryReplaced { id })
+        );
+
+        // The rejected update doesn't keep a reference to its data
+        let unreferenced = store
+            .gc(chrono::Duration::zero(), true)
+            .await
+            .expect("Failed to collect garbage");
+        assert_eq!(unreferenced.entries, 1);
+
+        // Retrying against the latest version succeeds
+        store
+            .store_data(
+                DataLocation::Inline(b"ours".to_vec()),
+                serde_json::json!({"name": "c"}),
+                Some(theirs),
+            )
+            .await
+            .expect("Failed to retry");
+        let active = store.get_active_entries().await.expect("Failed to get");
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].metadata, serde_json::json!({"name": "c"}));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_max_metadata_size(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool.clone()).await?;
+        assert_eq!(store.max_metadata_size(), None);
+
+        let small = serde_json::json!({"name": "a"});
+        let large = serde_json::json!({"name": "a".repeat(64)});
+        let id = store
+            .store_data(DataLocation::Inline(b"a".to_vec()), large.clone(), None)
+            .await
+            .expect("Failed to store data");
+
+        // The default applies to every store
+        store
+            .set_default_max_metadata_size(Some(32))
+            .await
+            .expect("Failed to set default");
+        assert_eq!(store.max_metadata_size(), Some(32));
+        let err = store
+            .store_data(DataLocation::Inline(b"a".to_vec()), large.clone(), None)
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<EntryTooLarge>().expect("Wrong error");
+        assert_eq!((err.size, err.limit), (large.to_string().len(), 32));
+        assert!(store
+            .modify(id, |metadata| {
+                metadata["done"] = Value::Bool(true);
+                Ok(())
+            })
+            .await
+            .unwrap_err()
+            .downcast_ref::<EntryTooLarge>()
+            .is_some());
+
+        // The data of rejected entries isn't stored
+        let data = || DataLocation::Inline(b"rejected".to_vec());
+        store
+            .store_data(data(), large.clone(), Some(id))
+            .await
+            .unwrap_err();
+        store
+            .store_reader(&b"rejected"[..], large.clone(), None)
+            .await
+            .unwrap_err();
+        store
+            .store_many(vec![(data(), small.clone()), (data(), large.clone())])
+            .await
+            .unwrap_err();
+        let unreferenced = store
+            .gc(chrono::Duration::zero(), true)
+            .await
+            .expect("Failed to collect garbage");
+        assert_eq!(unreferenced.entries, 0);
+
+        store
+            .store_data(DataLocation::Inline(b"a".to_vec()), small, None)
+            .await
+            .expect("Failed to store small entry");
+        assert_eq!(store.get_active_entries().await.expect("Failed").len(), 2);
+
+        // A store's own limit overrides the default and is saved
+        store
+            .set_max_metadata_size(Some(1024))
+            .await
+            .expect("Failed to set limit");
+        let reopened = DataStore::from_pool(pool, "test", store.device_id())
+            .await
+            .expect("Failed to reopen");
+        assert_eq!(reopened.max_metadata_size(), Some(1024));
+        store
+            .store_data(DataLocation::Inline(b"a".to_vec()), large, None)
+            .await
+            .expect("Failed to store under the store's limit");
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_metrics(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool).await?;
+        assert_eq!(store.metrics(), StoreMetrics::default());
+
+        let id = store
+            .store_data(
+                DataLocation::Inline(b"hello".to_vec()),
+                serde_json::json!({"name": "a"}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+        store
+            .store_many(vec![
+                (DataLocation::Inline(b"b".to_vec()), serde_json::json!({})),
+                (DataLocation::Inline(b"c".to_vec()), serde_json::json!({})),
+            ])
+            .await
+            .expect("Failed to store data");
+        let mut data = Vec::new();
+        store
+            .read_data(id, &mut data)
+            .await
+            .expect("Failed to read data");
+        store
+            .query(&MetadataQuery::new())
+            .await
+            .expect("Failed to query");
+        store.archive(id).await.expect("Failed to archive");
+
+        assert_eq!(
+            store.metrics(),
+            StoreMetrics {
+                entries_committed: 3,
+                entries_archived: 1,
+                entries_merged: 0,
+                queries: 1,
+                bytes_read: 5,
+            }
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_backup_round_trip(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool).await?;
+
+        let first = store
+            .store_data(
+                DataLocation::Inline(b"first".to_vec()),
+                serde_json::json!({"name": "first"}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+        store
+            .store_reader(
+                &b"second"[..],
+                serde_json::json!({"name": "second"}),
+                Some(first),
+            )
+            .await
+            .expect("Failed to store data");
+        let gone = store
+            .store_data(
+                DataLocation::Inline(b"first".to_vec()),
+                serde_json::json!({"name": "gone"}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+        store.archive(gone).await.expect("Failed to archive");
+
+        let mut backup = Vec::new();
+        let stats = store
+            .export_backup(&mut backup)
+            .await
+            .expect("Failed to export");
+        // The data shared by two entries is only written once
+        assert_eq!(
+            stats,
+            MergeStats {
+                entries: 4,
+                data: 2
+            }
+        );
+
+        // Restore into a SQLite store
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let mut restored = setup_sqlite_store(&temp_dir, "restored").await;
+        let stats = restored
+            .import_backup(&backup[..])
+            .await
+            .expect("Failed to import");
+        assert_eq!(stats.entries, 4);
+        let mut entries = restored
+            .query(&MetadataQuery::new().include_archived(true))
+            .await
+            .expect("Failed to query");
+        let mut original = store
+            .query(&MetadataQuery::new().include_archived(true))
+            .await
+            .expect("Failed to query");
+        entries.iter_mut().for_each(|entry| entry.local = false);
+        original.iter_mut().for_each(|entry| entry.local = false);
+        assert_eq!(entries, original);
+        let mut data = Vec::new();
+        restored
+            .read_data(original[1].id, &mut data)
+            .await
+            .expect("Failed to read data");
+        assert_eq!(data, b"second");
+
+        // Restoring again changes nothing
+        let stats = restored
+            .import_backup(&backup[..])
+            .await
+            .expect("Failed to import");
+        assert_eq!(stats.entries, 0);
+
+        // Other versions are rejected
+        let future = format!(
+            "{}\n",
+            serde_json::to_string(&BackupRecord::Header {
+                version: BACKUP_VERSION + 1,
+                store: "test".to_string(),
+                created_at: Utc::now(),
+                since: None,
+            })
+            .unwrap()
+        );
+        assert!(restored.import_backup(future.as_bytes()).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_gc_shared_local_path() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let local_path = temp_dir.path().join("data");
+        std::fs::create_dir_all(&local_path).expect("Failed to create data dir");
+        let mut stores = Vec::new();
+        for name in ["first", "second"] {
+            let url = format!(
+                "sqlite://{}",
+                temp_dir.path().join(format!("{name}.db")).display()
+            );
+            let pool = connect_sqlite(&url).await.expect("Failed to open database");
+            let store =
+                DataStore::init_sqlite(pool, "test", generate_test_device_id(), local_path.clone())
+                    .await
+                    .expect("Failed to initialize store");
+            stores.push(store);
+        }
+        let (mut first, mut second) = (stores.remove(0), stores.remove(0));
+
+        // Both databases store the same data, only the second one references it
+        let data = b"shared data".to_vec();
+        first
+            .data_table
+            .put_reader(&data[..])
+            .await
+            .expect("Failed to store data");
+        let theirs = second
+            .store_reader(&data[..], Value::Null, None)
+            .await
+            .expect("Failed to store data");
+
+        // Collecting it in one database leaves the other's copy alone
+        let stats = first
+            .gc(chrono::Duration::zero(), false)
+            .await
+            .expect("Failed to collect garbage");
+        assert_eq!(stats.files, 1);
+        let mut read = Vec::new();
+        second
+            .read_data(theirs, &mut read)
+            .await
+            .expect("Failed to read data");
+        assert_eq!(read, data);
+    }
+
+    #[tokio::test]
+    async fn test_backup_clock_skew() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let mut store = setup_sqlite_store(&temp_dir, "store").await;
+
+        // The child is written on a device with a slow clock, so its id is lower
+        let child_id = Uuid::now_v7();
+        let parent_id = store
+            .store_data(
+                DataLocation::Inline(b"data".to_vec()),
+                serde_json::json!({"name": "parent"}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+        let parent = store
+            .metadata_table
+            .get_entry(parent_id)
+            .await
+            .expect("Failed to get entry")
+            .expect("Not found");
+        store
+            .metadata_table
+            .create_entry(MetadataEntry {
+                id: child_id,
+                parent_id: Some(parent_id),
+                metadata: serde_json::json!({"name": "child"}),
+                ..parent
+            })
+            .await
+            .expect("Failed to create entry");
+
+        let mut backup = Vec::new();
+        store
+            .export_backup(&mut backup)
+            .await
+            .expect("Failed to export");
+        let mut restored = setup_sqlite_store(&temp_dir, "restored").await;
+        let stats = restored
+            .import_backup(&backup[..])
+            .await
+            .expect("Failed to import");
+        assert_eq!(stats.entries, 2);
+        let active = restored
+            .get_active_entries()
+            .await
+            .expect("Failed to get entries");
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].id, child_id);
+
+        // A backup that is missing the child's parent is rejected
+        let mut broken_backup = Vec::new();
+        for line in backup
+            .split(|&byte| byte == b'\n')
+            .filter(|l| !l.is_empty())
+        {
+            let record: BackupRecord = serde_json::from_slice(line).expect("Invalid record");
+            if !matches!(&record, BackupRecord::Entry(entry) if entry.id == parent_id) {
+                serde_json::to_writer(&mut broken_backup, &record).unwrap();
+                broken_backup.push(b'\n');
+            }
+        }
+        let mut broken = setup_sqlite_store(&temp_dir, "broken").await;
+        let err = broken.import_backup(&broken_backup[..]).await.unwrap_err();
+        assert!(err.to_string().contains(&parent_id.to_string()));
+        assert!(broken
+            .get_active_entries()
+            .await
+            .expect("Failed to get entries")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_backup_streams_data() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let mut store = setup_sqlite_store(&temp_dir, "store").await;
+        store.set_ingest_buffer_size(1024);
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let id = store
+            .store_reader(&data[..], serde_json::json!({"name": "large"}), None)
+            .await
+            .expect("Failed to store data");
+
+        let mut backup = Vec::new();
+        store
+            .export_backup(&mut backup)
+            .await
+            .expect("Failed to export");
+
+        // The data is read in several pieces but still written as one record
+        let records: Vec<BackupRecord> = backup
+            .split(|&byte| byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).expect("Invalid record"))
+            .collect();
+        assert_eq!(records.len(), 3);
+        match &records[1] {
+            BackupRecord::Data { bytes, .. } => assert_eq!(hex::decode(bytes).unwrap(), data),
+            other => panic!("Expected data, got {other:?}"),
+        }
+
+        let mut restored = setup_sqlite_store(&temp_dir, "restored").await;
+        restored
+            .import_backup(&backup[..])
+            .await
+            .expect("Failed to import");
+        let mut read = Vec::new();
+        restored
+            .read_data(id, &mut read)
+            .await
+            .expect("Failed to read data");
+        assert_eq!(read, data);
+    }
+
+    #[tokio::test]
+    async fn test_bundle() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let mut laptop = setup_sqlite_store(&temp_dir, "laptop").await;
+        let mut desktop = setup_sqlite_store(&temp_dir, "desktop").await;
+
+        let first = laptop
+            .store_data(
+                DataLocation::Inline(b"first".to_vec()),
+                serde_json::json!({"name": "first"}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+        let mut backup = Vec::new();
+        laptop
+            .export_backup(&mut backup)
+            .await
+            .expect("Failed to export");
+        desktop
+            .import_backup(&backup[..])
+            .await
+            .expect("Failed to import");
+
+        // Only the changes since the desktop's newest entry are bundled
+        let second = laptop
+            .store_data(
+                DataLocation::Inline(b"second".to_vec()),
+                serde_json::json!({"name": "second"}),
+                Some(first),
+            )
+            .await
+            .expect("Failed to store data");
+        let mut bundle = Vec::new();
+        let stats = laptop
+            .export_bundle(first, &mut bundle)
+            .await
+            .expect("Failed to export bundle");
+        assert_eq!(
+            stats,
+            MergeStats {
+                entries: 1,
+                data: 1
+            }
+        );
+        desktop
+            .import_backup(&bundle[..])
+            .await
+            .expect("Failed to apply bundle");
+
+        let active = desktop.get_active_entries().await.expect("Failed to get");
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].id, second);
+        let mut data = Vec::new();
+        desktop
+            .read_data(second, &mut data)
+            .await
+            .expect("Failed to read data");
+        assert_eq!(data, b"second");
+    }
+
+    #[sqlx::test]
+    async fn test_read_only(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool).await?;
+        let id = store
+            .store_data(
+                DataLocation::Inline(b"hello".to_vec()),
+                serde_json::json!({"name": "a"}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+
+        let view = store.read_only();
+        let entries = view
+            .query(&MetadataQuery::new())
+            .await
+            .expect("Failed to query");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, id);
+        let mut data = Vec::new();
+        view.read_data(id, &mut data)
+            .await
+            .expect("Failed to read data");
+        assert_eq!(data, b"hello");
+        assert_eq!(view.device_id(), store.device_id());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_pinned_query(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool).await?;
+        assert_eq!(store.latest_id().await.expect("Failed to get"), None);
+
+        let first = store
+            .store_data(
+                DataLocation::Inline(b"a".to_vec()),
+                serde_json::json!({"name": "a"}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+        let pin = store
+            .latest_id()
+            .await
+            .expect("Failed to get")
+            .expect("Missing id");
+        assert_eq!(pin, first);
+
+        // Changes after the pin aren't seen by pinned queries
+        store
+            .modify(first, |metadata| {
+                metadata["name"] = serde_json::json!("b");
+                Ok(())
+            })
+            .await
+            .expect("Failed to modify");
+        store
+            .store_data(
+                DataLocation::Inline(b"c".to_vec()),
+                serde_json::json!({"name": "c"}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+        let pinned = store
+            .query(&MetadataQuery::new().pinned_at(pin))
+            .await
+            .expect("Failed to query");
+        assert_eq!(pinned.len(), 1);
+        assert_eq!(pinned[0].metadata, serde_json::json!({"name": "a"}));
+        assert_eq!(
+            store
+                .query(&MetadataQuery::new())
+                .await
+                .expect("Failed to query")
+                .len(),
+            2
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_find_conflicts(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool.clone()).await?;
+
+        let id = store
+            .store_data(
+                DataLocation::Inline(b"v1".to_vec()),
+                serde_json::json!({"name": "a"}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+        // Copies of the store on another device, and on another machine with the same key
+        let mut other = DataStore::from_pool(pool.clone(), "other", generate_test_device_id())
+            .await
+            .expect("Failed to open store");
+        other.merge_from(&store).await.expect("Failed to merge");
+        let mut copy = store.fork(pool, "copy").await.expect("Failed to fork");
+
+        let update = |name: &'static str| {
+            (
+                DataLocation::Inline(name.as_bytes().to_vec()),
+                serde_json::json!({ "name": name }),
+            )
+        };
+        let (data, metadata) = update("b");
+        store
+            .store_data(data, metadata, Some(id))
+            .await
+            .expect("Failed to update");
+        assert!(store.find_conflicts().await.expect("Failed").is_empty());
+
+        // A stale update is rejected instead of forking the history
+        let (data, metadata) = update("stale");
+        let err = store
+            .store_data(data, metadata, Some(id))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EntryReplaced>(),
+            Some(&EntryReplaced { id })
+        );
+
+        // Another device updated the same version in its copy
+        let (data, metadata) = update("c");
+        other
+            .store_data(data, metadata, Some(id))
+            .await
+            .expect("Failed to update");
+        store.merge_from(&other).await.expect("Failed to merge");
+        let conflicts = store.find_conflicts().await.expect("Failed");
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].parent_id, id);
+        assert_eq!(conflicts[0].entries.len(), 2);
+        assert!(conflicts[0].equivocators().is_empty());
+
+        // The same key updating it in a third copy is flagged
+        let (data, metadata) = update("d");
+        copy.store_data(data, metadata, Some(id))
+            .await
+            .expect("Failed to update");
+        store.merge_from(&copy).await.expect("Failed to merge");
+        let conflicts = store.find_conflicts().await.expect("Failed");
+        assert_eq!(conflicts[0].entries.len(), 3);
+        assert_eq!(conflicts[0].equivocators(), [store.device_id()]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merge_from_batches() {
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let mut laptop = setup_sqlite_store(&temp_dir, "laptop").await;
+        let mut desktop = setup_sqlite_store(&temp_dir, "desktop").await;
+
+        // More than one batch, with updates spanning batches
+        let count = MERGE_PAGE_SIZE as usize * 2 + 10;
+        let ids = laptop
+            .store_many((0..count).map(|i| {
+                (
+                    DataLocation::Inline(format!("entry {i}").into_bytes()),
+                    serde_json::json!({ "index": i }),
+                )
+            }))
+            .await
+            .expect("Failed to store data");
+        laptop
+            .update_where(&MetadataQuery::new().limit(5), |metadata| {
+                metadata["updated"] = Value::Bool(true);
+            })
+            .await
+            .expect("Failed to update");
+
+        let stats = desktop.merge_from(&laptop).await.expect("Failed to merge");
+        assert_eq!(stats.entries, count + 5);
+        assert_eq!(desktop.metrics().entries_merged, (count + 5) as u64);
+        let active = desktop
+            .query(&MetadataQuery::new())
+            .await
+            .expect("Failed to query");
+        assert_eq!(active.len(), count);
+        assert!(!active.iter().any(|entry| entry.id == ids[0]));
+        let mut data = Vec::new();
+        desktop
+            .read_data(ids[count - 1], &mut data)
+            .await
+            .expect("Failed to read data");
+        assert_eq!(data, format!("entry {}", count - 1).into_bytes());
+    }
+
+    #[sqlx::test]
+    async fn test_store_settings(pool: PgPool) -> TestResult<()> {
+        let TestDataStore {
+            datastore: mut store,
+            temp_dir: _,
+        } = setup_datastore(pool.clone()).await?;
+        assert_eq!(
+            store.store_settings().await.expect("Failed to get"),
+            StoreSettings::default()
+        );
+
+        // A field written by a newer version
+        store
+            .set_setting(Setting {
+                key: "store_test".to_string(),
+                value: serde_json::json!({"title": "Old", "icon": "star"}),
+                description: None,
+            })
+            .await
+            .expect("Failed to set");
+
+        let settings = store
+            .update_store_settings(|settings| {
+                settings.title = Some("Documents".to_string());
+                settings
+                    .custom
+                    .insert("color".to_string(), serde_json::json!("blue"));
+            })
+            .await
+            .expect("Failed to update");
+        assert_eq!(settings.title.as_deref(), Some("Documents"));
+
+        let reopened = DataStore::from_pool(pool, "test", store.device_id())
+            .await
+            .expect("Failed to reopen");
+        let settings = reopened.store_settings().await.expect("Failed to get");
+        assert_eq!(settings.title.as_deref(), Some("Documents"));
+        assert_eq!(settings.custom["icon"], serde_json::json!("star"));
+        assert_eq!(settings.custom["color"], serde_json::json!("blue"));
 
+        // Invalid settings aren't saved
+        assert!(store
+            .update_store_settings(|settings| settings.title = Some(String::new()))
+            .await
+            .is_err());
+        assert_eq!(
+            store.store_settings().await.expect("Failed to get").title,
+            Some("Documents".to_string())
+        );
         Ok(())
     }
 
     #[sqlx::test]
-    async fn test_query_by_metadata(pool: PgPool) -> TestResult<()> {
+    async fn test_fork(pool: PgPool) -> TestResult<()> {
         let TestDataStore {
             datastore: mut store,
             temp_dir: _,
-        } = setup_datastore(pool).await?;
-
-        // Store entries with different metadata
-        let test1_id = store
+        } = setup_datastore(pool.clone()).await?;
+        let id = store
             .store_data(
-                DataLocation::Inline("test1".as_bytes().to_vec()),
-                serde_json::json!({"type": "text", "tag": "a"}),
+                DataLocation::Inline(b"published".to_vec()),
+                serde_json::json!({"title": "Post", "draft": false}),
                 None,
             )
             .await
-            .expect("Failed to store data 1");
-
+            .expect("Failed to store data");
         store
-            .store_data(
-                DataLocation::Inline("test2".as_bytes().to_vec()),
-                serde_json::json!({"type": "text", "tag": "b"}),
-                None,
-            )
+            .update_store_settings(|settings| settings.title = Some("Blog".to_string()))
             .await
-            .expect("Failed to store data 2");
+            .expect("Failed to update settings");
 
-        // Query for specific tag
-        let results: Vec<(Uuid, Value)> = store
-            .query_by_metadata(&serde_json::json!({"tag": "a"}), false)
+        let mut draft = store
+            .fork(pool.clone(), "draft")
             .await
-            .expect("Failed to query");
+            .expect("Failed to fork");
+        assert_eq!(
+            draft
+                .store_settings()
+                .await
+                .expect("Failed")
+                .title
+                .as_deref(),
+            Some("Blog")
+        );
+        let edited = draft
+            .modify(id, |metadata| {
+                metadata["title"] = serde_json::json!("Better post");
+                Ok(())
+            })
+            .await
+            .expect("Failed to modify");
 
-        assert_eq!(results.len(), 1);
-        assert_eq!(results[0].1["tag"], "a");
-        assert_eq!(results[0].0, test1_id);
+        // The original is unchanged until the draft is merged back
+        assert_eq!(store.get_active_entries().await.expect("Failed")[0].id, id);
+        let stats = store.merge_from(&draft).await.expect("Failed to merge");
+        assert_eq!(stats.entries, 1);
+        let active = store.get_active_entries().await.expect("Failed");
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].id, edited);
 
+        // Forks must start empty
+        assert!(store.fork(pool, "draft").await.is_err());
         Ok(())
     }
 
     #[sqlx::test]
-    async fn test_data_locations_management(pool: PgPool) -> TestResult<()> {
+    async fn test_revert(pool: PgPool) -> TestResult<()> {
         let TestDataStore {
             datastore: mut store,
             temp_dir: _,
         } = setup_datastore(pool).await?;
+        assert_eq!(store.undo_last().await.expect("Failed to undo"), None);
 
-        // Store initial data as inline
-        let data = "Test data".as_bytes().to_vec();
         let id = store
             .store_data(
-                DataLocation::Inline(data.clone()),
-                serde_json::json!({"type": "text"}),
+                DataLocation::Inline(b"v1".to_vec()),
+                serde_json::json!({"version": 1}),
                 None,
             )
             .await
             .expect("Failed to store data");
-
-        // Get locations - should have been converted to local path
-        let locations = store
-            .get_data_locations(id)
+        let v2 = store
+            .store_data(
+                DataLocation::Inline(b"v2".to_vec()),
+                serde_json::json!({"version": 2}),
+                Some(id),
+            )
             .await
-            .expect("Failed to get locations");
-        assert_eq!(locations.len(), 1);
-        match &locations[0] {
-            DataLocation::LocalPath(path) => {
-                assert!(path.exists());
-                let contents = std::fs::read(path).expect("Failed to read file");
-                assert_eq!(contents, data);
-            }
-            _ => panic!("Expected local path data"),
-        }
+            .expect("Failed to store data");
 
-        // Add an S3 location
-        let s3_path = "s3://bucket/test.txt".to_string();
+        // Undo the update, restoring the first version's data
+        let restored = store.revert(v2).await.expect("Failed to revert");
+        let entries = store.get_active_entries().await.expect("Failed");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, restored);
+        assert_eq!(entries[0].metadata, serde_json::json!({"version": 1}));
+        let mut data = Vec::new();
         store
-            .add_data_location(id, DataLocation::S3(s3_path.clone()))
+            .read_data(restored, &mut data)
             .await
-            .expect("Failed to add S3 location");
+            .expect("Failed to read data");
+        assert_eq!(data, b"v1");
 
-        // Verify both locations exist
-        let locations = store
-            .get_data_locations(id)
+        // Only the latest change can be reverted
+        let err = store.revert(v2).await.unwrap_err();
+        assert!(err.downcast_ref::<EntryReplaced>().is_some());
+
+        // Undoing the revert redoes the update
+        let redone = store
+            .undo_last()
             .await
-            .expect("Failed to get locations");
-        assert_eq!(locations.len(), 2);
+            .expect("Failed to undo")
+            .expect("Nothing to undo");
+        let entries = store.get_active_entries().await.expect("Failed");
+        assert_eq!(entries[0].id, redone);
+        assert_eq!(entries[0].metadata, serde_json::json!({"version": 2}));
 
-        // Verify local path still exists
-        let has_local = locations
-            .iter()
-            .any(|loc| matches!(loc, DataLocation::LocalPath(_)));
-        assert!(has_local, "Local path not found");
+        // Undoing an archive restores the entry
+        let archive_id = store.archive(redone).await.expect("Failed to archive");
+        assert!(store.get_active_entries().await.expect("Failed").is_empty());
+        let unarchived = store
+            .undo_last()
+            .await
+            .expect("Failed to undo")
+            .expect("Nothing to undo");
+        let entries = store.get_active_entries().await.expect("Failed");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].metadata, serde_json::json!({"version": 2}));
 
-        // Verify S3 location exists
-        let has_s3 = locations.iter().any(|loc| match loc {
-            DataLocation::S3(path) => path == &s3_path,
-            _ => false,
-        });
-        assert!(has_s3, "S3 location not found");
+        // The archive can only be restored once
+        let err = store.revert(archive_id).await.unwrap_err();
+        assert!(err.downcast_ref::<EntryReplaced>().is_some());
+
+        // Undoing the restore archives the entry again
+        let rearchived = store
+            .undo_last()
+            .await
+            .expect("Failed to undo")
+            .expect("Nothing to undo");
+        assert!(store.get_active_entries().await.expect("Failed").is_empty());
+        let history = store.get_history(rearchived).await.expect("Failed");
+        assert_eq!(history[1].id, unarchived);
+        assert!(history[0].archived && history[0].data_hash.is_empty());
+
+        // And undoing that restores it
+        store.undo_last().await.expect("Failed to undo");
+        let entries = store.get_active_entries().await.expect("Failed");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].metadata, serde_json::json!({"version": 2}));
 
+        // Reverting a new entry archives it
+        let new = store
+            .store_data(
+                DataLocation::Inline(b"new".to_vec()),
+                serde_json::json!({}),
+                None,
+            )
+            .await
+            .expect("Failed to store data");
+        store.revert(new).await.expect("Failed to revert");
+        assert_eq!(store.get_active_entries().await.expect("Failed").len(), 1);
         Ok(())
     }
 
     #[sqlx::test]
-    async fn test_data_storage_with_different_types(pool: PgPool) -> TestResult<()> {
+    async fn test_verify_integrity(pool: PgPool) -> TestResult<()> {
         let TestDataStore {
             datastore: mut store,
             temp_dir: _,
         } = setup_datastore(pool).await?;
-        let temp_dir = tempdir().expect("Failed to create temp dir");
 
-        // Test inline data
-        let inline_data = "Inline content".as_bytes().to_vec();
-        let inline_id = store
+        let id = store
             .store_data(
-                DataLocation::Inline(inline_data.clone()),
-                serde_json::json!({"type": "inline"}),
+                DataLocation::Inline(b"v1".to_vec()),
+                serde_json::json!({}),
                 None,
             )
             .await
-            .expect("Failed to store inline data");
-
-        // Test local file
-        let file_path = temp_dir.path().join("test.txt");
-        std::fs::write(&file_path, "Local file content").expect("Failed to write test file");
-        let file_id = store
+            .expect("Failed to store data");
+        store
             .store_data(
-                DataLocation::LocalPath(file_path),
-                serde_json::json!({"type": "file"}),
-                None,
+                DataLocation::Inline(b"v2".to_vec()),
+                serde_json::json!({}),
+                Some(id),
             )
             .await
-            .expect("Failed to store file");
+            .expect("Failed to update");
+        assert!(store.verify_integrity().await.expect("Failed").is_empty());
 
-        // Verify both entries exist and have correct metadata
-        let entries = store
-            .get_active_entries()
+        let hash = store
+            .metadata_table
+            .get_entry(id)
             .await
-            .expect("Failed to get entries");
-        assert_eq!(entries.len(), 2);
-
-        // Verify inline data entry
-        let inline_entry = entries
-            .iter()
-            .find(|e| e.id == inline_id)
-            .expect("Inline entry not found");
-        assert_eq!(inline_entry.metadata["type"], "inline");
-
-        // Verify file entry
-        let file_entry = entries
-            .iter()
-            .find(|e| e.id == file_id)
-            .expect("File entry not found");
-        assert_eq!(file_entry.metadata["type"], "file");
+            .expect("Failed to get entry")
+            .expect("Missing entry")
+            .data_hash;
+        let path = store.data_table.get_local_path(&hash).await?;
+        std::fs::write(&path, b"corrupt")?;
+        assert_eq!(
+            store.verify_integrity().await.expect("Failed"),
+            [IntegrityViolation::CorruptData {
+                id,
+                hash: hash.clone()
+            }]
+        );
 
+        std::fs::remove_file(&path)?;
+        assert_eq!(
+            store.verify_integrity().await.expect("Failed"),
+            [IntegrityViolation::MissingData { id, hash }]
+        );
         Ok(())
     }
 
     #[sqlx::test]
-    async fn test_data_versioning_and_history(pool: PgPool) -> TestResult<()> {
+    async fn test_merge_from_postgres(pool: PgPool) -> TestResult<()> {
         let TestDataStore {
-            datastore: mut store,
+            datastore: mut server,
             temp_dir: _,
         } = setup_datastore(pool).await?;
+        let temp_dir = tempdir()?;
+        let mut laptop = setup_sqlite_store(&temp_dir, "laptop").await;
 
-        // Create initial version
-        let v1_data = "Version 1".as_bytes().to_vec();
-        let v1_id = store
+        let kept = server
             .store_data(
-                DataLocation::Inline(v1_data),
-                serde_json::json!({
-                    "version": 1,
-                    "type": "document"
-                }),
+                DataLocation::Inline(b"kept".to_vec()),
+                serde_json::json!({"name": "kept"}),
                 None,
             )
             .await
-            .expect("Failed to store v1");
-
-        // Create version 2
-        let v2_data = "Version 2".as_bytes().to_vec();
-        let v2_id = store
-            .store_data(
-                DataLocation::Inline(v2_data),
-                serde_json::json!({
-                    "version": 2,
-                    "type": "document"
-                }),
-                Some(v1_id),
-            )
-            .await
-            .expect("Failed to store v2");
-
-        // Create version 3
-        let v3_data = "Version 3".as_bytes().to_vec();
-        let v3_id = store
+            .expect("Failed to store data");
+        let removed = server
             .store_data(
-                DataLocation::Inline(v3_data),
-                serde_json::json!({
-                    "version": 3,
-                    "type": "document"
-                }),
-                Some(v2_id),
+                DataLocation::Inline(b"removed".to_vec()),
+                serde_json::json!({"name": "removed"}),
+                None,
             )
             .await
-            .expect("Failed to store v3");
-
-        // Get history starting from v3
-        let history = store
-            .get_history(v3_id)
-            .await
-            .expect("Failed to get history");
-
-        // Verify history order and content
-        assert_eq!(history.len(), 3, "Expected 3 versions in history");
-        assert_eq!(history[0].id, v3_id, "Latest version should be first");
-        assert_eq!(history[1].id, v2_id, "Second version should be second");
-        assert_eq!(history[2].id, v1_id, "First version should be last");
+            .expect("Failed to store data");
+        server.archive(removed).await.expect("Failed to archive");
 
-        // Verify version numbers in metadata
-        assert_eq!(
-            history[0].metadata["version"], 3,
-            "Latest version should have version 3"
-        );
-        assert_eq!(
-            history[1].metadata["version"], 2,
-            "Second version should have version 2"
-        );
+        // The archive entry has no data to copy or reference
+        let stats = laptop.merge_from(&server).await.expect("Failed to merge");
         assert_eq!(
-            history[2].metadata["version"], 1,
-            "First version should have version 1"
+            stats,
+            MergeStats {
+                entries: 3,
+                data: 2
+            }
         );
-
+        let active = laptop.get_active_entries().await.expect("Failed");
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].id, kept);
+        assert!(laptop.verify_integrity().await.expect("Failed").is_empty());
         Ok(())
     }
 
     #[sqlx::test]
-    async fn test_metadata_query_complex(pool: PgPool) -> TestResult<()> {
+    async fn test_backup_missing_data(pool: PgPool) -> TestResult<()> {
         let TestDataStore {
             datastore: mut store,
             temp_dir: _,
         } = setup_datastore(pool).await?;
 
-        // Store multiple entries with different metadata
-        for i in 1..=3 {
-            store
-                .store_data(
-                    DataLocation::Inline(format!("Data {}", i).as_bytes().to_vec()),
-                    serde_json::json!({
-                        "type": "document",
-                        "category": if i % 2 == 0 { "even" } else { "odd" },
-                        "priority": i
-                    }),
-                    None,
-                )
-                .await
-                .expect("Failed to store data");
-        }
-
-        // Query by category
-        let odd_results = store
-            .query_by_metadata(&serde_json::json!({"category": "odd"}), false)
-            .await
-            .expect("Failed to query odd entries");
-        assert_eq!(odd_results.len(), 2, "Expected 2 odd entries");
-
-        // Query by priority
-        let high_priority = store
-            .query_by_metadata(&serde_json::json!({"priority": 3}), false)
+        let id = store
+            .store_data(
+                DataLocation::Inline(b"lost".to_vec()),
+                serde_json::json!({"name": "lost"}),
+                None,
+            )
             .await
-            .expect("Failed to query high priority entries");
-        assert_eq!(high_priority.len(), 1, "Expected 1 high priority entry");
+            .expect("Failed to store data");
+        let hash = store.get_history(id).await.expect("Failed")[0]
+            .data_hash
+            .clone();
+        store.data_table.delete_local_file(&hash).await?;
 
-        // Query with multiple conditions
-        let specific_entries = store
-            .query_by_metadata(
-                &serde_json::json!({
-                    "type": "document",
-                    "category": "odd"
-                }),
-                false,
-            )
+        let mut backup = Vec::new();
+        let stats = store
+            .export_backup(&mut backup)
             .await
-            .expect("Failed to query specific entries");
+            .expect("Failed to export");
         assert_eq!(
-            specific_entries.len(),
-            2,
-            "Expected 2 entries matching both conditions"
+            stats,
+            MergeStats {
+                entries: 1,
+                data: 0
+            }
         );
 
+        // The entry is restored and references the data it's missing
+        let temp_dir = tempdir().expect("Failed to create temp dir");
+        let mut restored = setup_sqlite_store(&temp_dir, "restored").await;
+        let stats = restored
+            .import_backup(&backup[..])
+            .await
+            .expect("Failed to import");
+        assert_eq!(stats.entries, 1);
+        assert!(restored
+            .get_data_locations(id)
+            .await
+            .expect("Failed to get locations")
+            .is_empty());
+        let unreferenced = restored
+            .gc(chrono::Duration::zero(), true)
+            .await
+            .expect("Failed to collect garbage");
+        assert_eq!(unreferenced.entries, 0);
+
+        let stats = restored
+            .import_backup(&backup[..])
+            .await
+            .expect("Failed to import");
+        assert_eq!(stats.entries, 0);
         Ok(())
     }
 }
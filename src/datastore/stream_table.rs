@@ -1,6 +1,6 @@
 use crate::datastore::schema::{DeviceId, PrivateKey, StreamEntry, StreamType};
 use anyhow::Result;
-use sqlx::{Error, PgPool};
+use sqlx::{Error, PgPool, SqlitePool};
 
 /// Interface for interacting with the stream table
 #[allow(dead_code, async_fn_in_trait)]
@@ -217,6 +217,166 @@ impl StreamTable for PostgresStreamTable {
     }
 }
 
+/// SQLite implementation of the stream table
+pub struct SqliteStreamTable {
+    pub pool: SqlitePool,
+}
+
+#[allow(dead_code)]
+impl SqliteStreamTable {
+    /// Create a new SqliteStreamTable instance
+    pub async fn new(connection_string: &str) -> Result<Self> {
+        let pool = SqlitePool::connect(connection_string).await?;
+
+        let mut table = Self { pool };
+        table.create_table().await?;
+        Ok(table)
+    }
+
+    /// Create a new SqliteStreamTable from an existing pool connection
+    pub async fn from_pool(pool: SqlitePool) -> Result<Self> {
+        let mut table = Self { pool };
+        table.create_table().await?;
+        Ok(table)
+    }
+}
+
+impl StreamTable for SqliteStreamTable {
+    async fn create_table(&mut self) -> Result<()> {
+        // `index` is a keyword in SQLite, so it is always quoted
+        let query = r#"
+            CREATE TABLE IF NOT EXISTS streams (
+                "index" INTEGER PRIMARY KEY AUTOINCREMENT,
+                id BLOB NOT NULL UNIQUE,
+                secret_key BLOB,
+                stream_type TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now'))
+            );"#;
+
+        sqlx::query(query).execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn create_entry(
+        &mut self,
+        device_id: DeviceId,
+        stream_type: StreamType,
+        secret_key: Option<PrivateKey>,
+    ) -> Result<StreamEntry> {
+        let stream_type_str = match stream_type {
+            StreamType::Stream => "Stream",
+            StreamType::User => "User",
+            StreamType::Instance => "Instance",
+            StreamType::Store => "Store",
+        };
+
+        let query = r#"
+            INSERT INTO streams (id, secret_key, stream_type)
+            VALUES (?1, ?2, ?3)
+            RETURNING "index", id, secret_key, stream_type"#;
+
+        let row = sqlx::query(query)
+            .bind(device_id.to_vec())
+            .bind(secret_key.map(|key| key.to_vec()))
+            .bind(stream_type_str)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(StreamEntry::from(row))
+    }
+
+    async fn get_entry_by_device_id(&self, device_id: &DeviceId) -> Result<Option<StreamEntry>> {
+        let query = r#"
+            SELECT "index", id, secret_key, stream_type
+            FROM streams
+            WHERE id = ?1"#;
+
+        let row = sqlx::query(query)
+            .bind(device_id.to_vec())
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(StreamEntry::from))
+    }
+
+    async fn get_entry_by_index(&self, index: i64) -> Result<Option<StreamEntry>> {
+        let query = r#"
+            SELECT "index", id, secret_key, stream_type
+            FROM streams
+            WHERE "index" = ?1"#;
+
+        let row = sqlx::query(query)
+            .bind(index)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(StreamEntry::from))
+    }
+
+    async fn get_entries_by_type(&self, stream_type: StreamType) -> Result<Vec<StreamEntry>> {
+        let stream_type_str = match stream_type {
+            StreamType::Stream => "Stream",
+            StreamType::User => "User",
+            StreamType::Instance => "Instance",
+            StreamType::Store => "Store",
+        };
+
+        let query = r#"
+            SELECT "index", id, secret_key, stream_type
+            FROM streams
+            WHERE stream_type = ?1
+            ORDER BY "index""#;
+
+        let rows = sqlx::query(query)
+            .bind(stream_type_str)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(StreamEntry::from).collect())
+    }
+
+    async fn update_entry(&self, entry: StreamEntry) -> Result<()> {
+        let stream_type_str = match entry.stream_type {
+            StreamType::Stream => "Stream",
+            StreamType::User => "User",
+            StreamType::Instance => "Instance",
+            StreamType::Store => "Store",
+        };
+
+        let query = r#"
+            UPDATE streams
+            SET id = ?2, secret_key = ?3, stream_type = ?4
+            WHERE "index" = ?1"#;
+
+        let result = sqlx::query(query)
+            .bind(entry.index)
+            .bind(entry.id.to_vec())
+            .bind(entry.secret_key.map(|key| key.to_vec()))
+            .bind(stream_type_str)
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::RowNotFound.into());
+        }
+
+        Ok(())
+    }
+
+    async fn delete_entry(&mut self, index: i64) -> Result<()> {
+        let query = r#"DELETE FROM streams WHERE "index" = ?1"#;
+
+        let result = sqlx::query(query).bind(index).execute(&self.pool).await?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::RowNotFound.into());
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -418,4 +578,46 @@ mod tests {
         assert!(table1.create_table().await.is_ok());
         assert!(table2.create_table().await.is_ok());
     }
+
+    #[sqlx::test]
+    async fn test_sqlite_stream_table(pool: SqlitePool) {
+        let mut table = SqliteStreamTable::from_pool(pool).await.unwrap();
+        let device_id = generate_test_device_id();
+        let secret_key = Some(generate_test_secret_key());
+
+        let mut entry = table
+            .create_entry(device_id, StreamType::Stream, secret_key)
+            .await
+            .unwrap();
+        assert_eq!(entry.id, device_id);
+        assert_eq!(entry.secret_key, secret_key);
+        assert!(entry.index > 0);
+
+        // Duplicate device ids are rejected
+        assert!(table
+            .create_entry(device_id, StreamType::User, None)
+            .await
+            .is_err());
+
+        let retrieved = table.get_entry_by_device_id(&device_id).await.unwrap();
+        assert_eq!(retrieved, Some(entry.clone()));
+
+        entry.secret_key = None;
+        entry.stream_type = StreamType::Store;
+        table.update_entry(entry.clone()).await.unwrap();
+        let retrieved = table.get_entry_by_index(entry.index).await.unwrap();
+        assert_eq!(retrieved, Some(entry.clone()));
+
+        let stores = table.get_entries_by_type(StreamType::Store).await.unwrap();
+        assert_eq!(stores, vec![entry.clone()]);
+
+        table.delete_entry(entry.index).await.unwrap();
+        assert!(table
+            .get_entry_by_index(entry.index)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(table.delete_entry(entry.index).await.is_err());
+        assert!(table.update_entry(entry).await.is_err());
+    }
 }
@@ -4,16 +4,22 @@ mod utils;
 
 use anyhow::Result;
 use clap::Parser;
+use datastore::data::DataTable;
 use datastore::data_handler::DataLocation;
-use datastore::store::DataStore;
+use datastore::metadata::MetadataTable;
+use datastore::store::{connect_sqlite, DataStore, StoreInfo};
 use serde_json::Value;
 use sqlx::postgres::PgPoolOptions;
 use std::env;
+use std::io::Write;
 use std::path::PathBuf;
 use std::str::FromStr;
 use tracing::info;
 use utils::generate_key;
 
+/// Number of entries fetched at a time when listing
+const LIST_PAGE_SIZE: i64 = 100;
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct EideticaArgs {
@@ -33,6 +39,30 @@ struct EideticaArgs {
 
     #[arg(short, long)]
     list: bool,
+
+    /// List the stores in the database
+    #[arg(long)]
+    stores: bool,
+
+    /// Merge in the entries of the same store from another DATABASE_URL
+    #[arg(long)]
+    merge_from: Option<String>,
+
+    /// Write a backup of the store to FILE
+    #[arg(long, value_name = "FILE")]
+    backup: Option<PathBuf>,
+
+    /// Only back up the entries created after the entry ID, making a bundle
+    #[arg(long, value_name = "ID", requires = "backup")]
+    since: Option<uuid::Uuid>,
+
+    /// Restore a backup or apply a bundle written with --backup from FILE
+    #[arg(long, value_name = "FILE")]
+    restore: Option<PathBuf>,
+
+    /// Check the data stored on this device for corruption
+    #[arg(long)]
+    verify: bool,
 }
 
 /// Setup logging with tracing
@@ -59,33 +89,65 @@ async fn main() -> Result<()> {
     let args = EideticaArgs::parse();
 
     // Retrieve DATABASE_URL from environment variables
+    // A `sqlite:` URL selects the SQLite backend, anything else is treated as PostgreSQL
     let database_url =
         env::var("DATABASE_URL").expect("DATABASE_URL must be set in the environment");
 
-    // Create a connection pool
-    let pool: sqlx::PgPool = PgPoolOptions::new()
-        .connect(&database_url)
-        .await
-        .expect("Error creating PostgreSQL connection pool");
-
     // Device ids aren't real yet, so just create a new one
     let signing_key = generate_key();
     let device_id = signing_key.verifying_key().to_bytes();
 
-    // Attempt to create store, initializing if needed
-    let mut store = match DataStore::from_pool(pool.clone(), "cmdfiles", device_id).await {
-        Ok(store) => store,
-        Err(_) => {
-            // If it fails lets just try to initialize
-            let local_path = PathBuf::from(
-                env::var("EIDETICA_DATA_DIR").unwrap_or_else(|_| "/tmp/eidetica".to_string()),
-            );
-            std::fs::create_dir_all(&local_path)?;
-
-            DataStore::init(pool, "cmdfiles", device_id, local_path).await?
+    let local_path = PathBuf::from(
+        env::var("EIDETICA_DATA_DIR").unwrap_or_else(|_| "/tmp/eidetica".to_string()),
+    );
+
+    if database_url.starts_with("sqlite:") {
+        let pool = connect_sqlite(&database_url)
+            .await
+            .expect("Error creating SQLite connection pool");
+
+        if args.stores {
+            return print_stores(DataStore::list_sqlite_stores(&pool).await?);
         }
-    };
 
+        // Attempt to create store, initializing if needed
+        let mut store = match DataStore::from_sqlite(pool.clone(), "cmdfiles", device_id).await {
+            Ok(store) => store,
+            Err(_) => {
+                std::fs::create_dir_all(&local_path)?;
+                DataStore::init_sqlite(pool, "cmdfiles", device_id, local_path).await?
+            }
+        };
+        run(args, &mut store).await
+    } else {
+        // Create a connection pool
+        let pool: sqlx::PgPool = PgPoolOptions::new()
+            .connect(&database_url)
+            .await
+            .expect("Error creating PostgreSQL connection pool");
+
+        if args.stores {
+            return print_stores(DataStore::list_stores(&pool).await?);
+        }
+
+        // Attempt to create store, initializing if needed
+        let mut store = match DataStore::from_pool(pool.clone(), "cmdfiles", device_id).await {
+            Ok(store) => store,
+            Err(_) => {
+                // If it fails lets just try to initialize
+                std::fs::create_dir_all(&local_path)?;
+                DataStore::init(pool, "cmdfiles", device_id, local_path).await?
+            }
+        };
+        run(args, &mut store).await
+    }
+}
+
+/// Run the requested command against an opened store
+async fn run<D: DataTable, M: MetadataTable>(
+    args: EideticaArgs,
+    store: &mut DataStore<D, M>,
+) -> Result<()> {
     let metadata: Option<Value> = match args.meta {
         Some(meta) =>
         // Parse the metadata string into a serde_json::Value
@@ -100,7 +162,7 @@ async fn main() -> Result<()> {
 
     // Send out to the plugin
     if let Some(plugin_args) = args.plugin {
-        return plugins::run(plugin_args, &mut store).await;
+        return plugins::run(plugin_args, store).await;
     }
 
     if let Some(insertion) = args.insert {
@@ -111,17 +173,80 @@ async fn main() -> Result<()> {
             .store_data(location, metadata.unwrap_or_default(), None)
             .await?;
         println!("Successfully inserted, UUID: {}", id);
+    } else if let Some(url) = args.merge_from {
+        let stats = if url.starts_with("sqlite:") {
+            let pool = connect_sqlite(&url).await?;
+            let other = DataStore::from_sqlite(pool, "cmdfiles", store.device_id()).await?;
+            store.merge_from(&other).await?
+        } else {
+            let pool = PgPoolOptions::new().connect(&url).await?;
+            let other = DataStore::from_pool(pool, "cmdfiles", store.device_id()).await?;
+            store.merge_from(&other).await?
+        };
+        println!(
+            "Merged {} entries and {} pieces of data",
+            stats.entries, stats.data
+        );
+    } else if let Some(path) = args.backup {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        let stats = match args.since {
+            Some(since) => store.export_bundle(since, &mut file).await?,
+            None => store.export_backup(&mut file).await?,
+        };
+        file.flush()?;
+        println!(
+            "Backed up {} entries and {} pieces of data",
+            stats.entries, stats.data
+        );
+    } else if let Some(path) = args.restore {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let stats = store.import_backup(file).await?;
+        println!(
+            "Restored {} entries and {} pieces of data",
+            stats.entries, stats.data
+        );
+    } else if args.verify {
+        let violations = store.verify_integrity().await?;
+        for violation in &violations {
+            println!("{:?}", violation);
+        }
+        println!("Found {} problems", violations.len());
     } else if args.list {
         // List the raw data from all active entries
-        let entries = match metadata {
-            Some(conditions) => store.get_entries_by_metadata_conditions(conditions).await?,
-            None => store.get_active_entries().await?,
-        };
-        for x in entries {
-            println!("Entry: {:?}", x);
+        match metadata {
+            Some(conditions) => {
+                for x in store.get_entries_by_metadata_conditions(conditions).await? {
+                    println!("Entry: {:?}", x);
+                }
+            }
+            None => {
+                // Page through the entries so large stores aren't loaded at once
+                let mut entries = store.iter_entries(LIST_PAGE_SIZE, false);
+                while let Some(x) = entries.next().await? {
+                    println!("Entry: {:?}", x);
+                }
+            }
         }
     }
 
+    info!("Store metrics: {:?}", store.metrics());
+    Ok(())
+}
+
+/// Print a summary of each store in the database
+fn print_stores(stores: Vec<StoreInfo>) -> Result<()> {
+    for store in stores {
+        match store.created_at {
+            Some(created_at) => println!(
+                "{}: {} active of {} entries, created {}",
+                store.name,
+                store.active_entries,
+                store.entries,
+                created_at.to_rfc3339()
+            ),
+            None => println!("{}: empty", store.name),
+        }
+    }
     Ok(())
 }
 
@@ -11,6 +11,7 @@ use walkdir::WalkDir;
 use crate::datastore::data::DataTable;
 use crate::datastore::data_handler::DataLocation;
 use crate::datastore::metadata::MetadataTable;
+use crate::datastore::readonly::ReadOnlyDataStore;
 use crate::datastore::settings::Setting;
 use crate::datastore::store::DataStore;
 
@@ -79,7 +80,7 @@ where
     match args.command {
         Some(FileCommand::Scan(args)) => scan(args, store).await?,
         Some(FileCommand::Init(args)) => init(args, store).await?,
-        Some(FileCommand::List(args)) => list(args, store).await?,
+        Some(FileCommand::List(args)) => list(args, store.read_only()).await?,
         Some(FileCommand::Watch(args)) => watch(args, store).await?,
         None => unimplemented!(),
     }
@@ -191,7 +192,7 @@ where
 }
 
 /// List all stored entries
-async fn list<D, M>(_args: ListArgs, store: &mut DataStore<D, M>) -> Result<()>
+async fn list<D, M>(_args: ListArgs, store: ReadOnlyDataStore<'_, D, M>) -> Result<()>
 where
     D: DataTable,
     M: MetadataTable,